@@ -18,6 +18,11 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
             .help("disable output"))
         .arg(Arg::with_name("output")
             .takes_value(true))
+        .arg(Arg::with_name("module")
+            .short("m")
+            .long("module")
+            .takes_value(true)
+            .help("name of the generated module (default: ap214)"))
         .get_matches();
     let input = matches.value_of("input")
         .expect("Could not get input file");
@@ -37,7 +42,8 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
     eprintln!("parsed in {:?}", since_the_epoch);
 
     let start = SystemTime::now();
-    let gen = express::gen::gen(&mut parsed.1)?;
+    let module = matches.value_of("module").unwrap_or("ap214");
+    let gen = express::gen::gen(&mut parsed.1, module)?;
     let end = SystemTime::now();
     let since_the_epoch = end.duration_since(start).expect("Time went backwards");
     eprintln!("generated in {:?}", since_the_epoch);
@@ -40,6 +40,22 @@ impl <'a> TypeMap<'a> {
             _ => false,
         }
     }
+    /// Returns the entity types that a bare `#id` in the given SELECT members
+    /// may refer to.  If any member is itself an all-entity SELECT, then its
+    /// members aren't tracked, so this returns an empty list (meaning any
+    /// entity type is allowed).
+    fn ref_targets(&self, members: &[&str]) -> Vec<String> {
+        let mut out = Vec::new();
+        for m in members {
+            match self.0.get(m) {
+                Some(Type::Entity{..}) => out.push(capitalize(m)),
+                Some(Type::Select(_)) if self.is_entity(m) => return vec![],
+                _ => (),
+            }
+        }
+        out.sort();
+        out
+    }
     fn to_rtype(&self, s: &str) -> String {
         let t = self.0.get(s).expect(&format!("Could not get {:?}", s));
         match &t {
@@ -75,6 +91,43 @@ impl <'a> TypeMap<'a> {
                 panic!("Invalid inner type"),
         }
     }
+    /// Classifies a Rust attribute type (as generated by `to_rtype`) by how
+    /// its values are written, e.g. "real" or "list of ref".  Optional
+    /// attributes are classified by their inner type.
+    fn attr_kind(&self, t: &str) -> String {
+        if let Some(t) = t.strip_prefix("Option<").and_then(|t| t.strip_suffix('>')) {
+            return self.attr_kind(t);
+        } else if let Some(t) = t.strip_prefix("Vec<").and_then(|t| t.strip_suffix('>')) {
+            return format!("list of {}", self.attr_kind(t));
+        } else if let Some(t) = t.strip_prefix("ArrayVec::<") {
+            return format!("list of {}", self.attr_kind(&t[..t.rfind(',').unwrap()]));
+        }
+        match t {
+            "f64" => "real".to_owned(),
+            "i64" | "usize" => "integer".to_owned(),
+            "bool" => "boolean".to_owned(),
+            "Logical" => "logical".to_owned(),
+            "Cow<'a, str>" => "string".to_owned(),
+            _ => {
+                let camel = t.strip_suffix("<'a>")
+                    .unwrap_or_else(|| panic!("Unknown attribute type {:?}", t));
+                let (k, v) = self.0.iter()
+                    .find(|(k, _)| to_camel(k) == camel)
+                    .unwrap_or_else(|| panic!("Could not get {:?}", camel));
+                match v {
+                    Type::Entity { .. } => "ref".to_owned(),
+                    Type::Select(_) if self.is_entity(k) => "ref".to_owned(),
+                    Type::Select(_) => "select".to_owned(),
+                    Type::Enum(_) => "enum".to_owned(),
+                    Type::Redeclared(c) => self.attr_kind(&self.to_rtype(c)),
+                    Type::RedeclaredPrimitive(c) | Type::Primitive(c) =>
+                        self.attr_kind(c),
+                    Type::Aggregation { .. } =>
+                        self.attr_kind(&self.to_inner_rtype(v)),
+                }
+            },
+        }
+    }
     fn build(&mut self, s: &'a str) {
         let v = self.1.get(s).unwrap();
         let m = match v {
@@ -138,6 +191,61 @@ impl<'a> Type<'a> {
         }
         Ok(())
     }
+    fn write_attributes<W>(&self, name: &str, buf: &mut W) -> std::fmt::Result
+        where W: std::fmt::Write
+    {
+        if let Type::Entity{attrs, ..} = self {
+            if !attrs.is_empty() {
+                write!(buf, r#"        "{}" => &["#, capitalize(name))?;
+                for (i, a) in attrs.iter().enumerate() {
+                    // Derived attributes are written as '*' in Part 21 files,
+                    // so that's how we mark them here.
+                    if a.derived {
+                        write!(buf, r#""*""#)?;
+                    } else if a.dupe {
+                        write!(buf, r#""{}__{}""#, a.from.unwrap(), a.name)?;
+                    } else {
+                        write!(buf, r#""{}""#, a.name)?;
+                    }
+                    if i == attrs.len() - 1 {
+                        writeln!(buf, "],")?;
+                    } else {
+                        write!(buf, ", ")?;
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+    fn write_attribute_kinds<W>(&self, name: &str, buf: &mut W, type_map: &TypeMap)
+        -> std::fmt::Result
+        where W: std::fmt::Write
+    {
+        if let Type::Entity{attrs, ..} = self {
+            if !attrs.is_empty() {
+                let kinds: Vec<String> = attrs.iter()
+                    .map(|a| if a.derived {
+                        r#""*""#.to_owned()
+                    } else {
+                        format!(r#""{}""#, type_map.attr_kind(&a.type_))
+                    })
+                    .collect();
+                writeln!(buf, r#"        "{}" => &[{}],"#,
+                         capitalize(name), kinds.join(", "))?;
+            }
+        }
+        Ok(())
+    }
+    fn write_defined_type_kind<W>(&self, name: &str, buf: &mut W, type_map: &TypeMap)
+        -> std::fmt::Result
+        where W: std::fmt::Write
+    {
+        match self {
+            Type::Entity { .. } | Type::Select(_) => Ok(()),
+            _ => writeln!(buf, r#"        "{}" => Some("{}"),"#, capitalize(name),
+                          type_map.attr_kind(&format!("{}<'a>", to_camel(name)))),
+        }
+    }
     fn write_type<W>(&self, name: &str, buf: &mut W, type_map: &TypeMap) -> std::fmt::Result
         where W: std::fmt::Write
     {
@@ -146,30 +254,56 @@ impl<'a> Type<'a> {
             Type::Redeclared(c) => {
                 writeln!(buf,r#"
 #[derive(Debug)]
-pub struct {0}<'a>(pub {1}, std::marker::PhantomData<&'a ()>); // redeclared
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "serde", serde(transparent))]
+pub struct {0}<'a>(pub {1}, #[cfg_attr(feature = "serde", serde(skip))] std::marker::PhantomData<&'a ()>); // redeclared
 impl<'a> Parse<'a> for {0}<'a> {{
     fn parse(s: &'a str) -> IResult<'a, Self> {{
         map({2}::parse, |r| Self(r, std::marker::PhantomData))(s)
     }}
 }}
 impl<'a> HasId for {0}<'a> {{
-    fn append_ids(&self, v: &mut Vec<usize>) {{
+    fn append_ids<V: IdVisitor>(&self, v: &mut V) {{
         self.0.append_ids(v);
     }}
 }}
+impl<'a> Write for {0}<'a> {{
+    fn write(&self, out: &mut String) {{
+        self.0.write(out);
+    }}
+}}
+impl<'a> IntoStatic for {0}<'a> {{
+    type Static = {0}<'static>;
+    fn into_static(self) -> Self::Static {{
+        {0}(self.0.into_static(), std::marker::PhantomData)
+    }}
+}}
 "#,
                 camel_name, type_map.to_rtype(c), to_camel(c))?;
             },
             Type::RedeclaredPrimitive(c) => {
                 writeln!(buf, r#"#[derive(Debug)]
-pub struct {0}<'a>(pub {1}, std::marker::PhantomData<&'a ()>); // primitive
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "serde", serde(transparent))]
+pub struct {0}<'a>(pub {1}, #[cfg_attr(feature = "serde", serde(skip))] std::marker::PhantomData<&'a ()>); // primitive
 impl<'a> Parse<'a> for {0}<'a> {{
     fn parse(s: &'a str) -> IResult<'a, Self> {{
         map(<{2}>::parse, |r| Self(r, std::marker::PhantomData))(s)
     }}
 }}
 impl<'a> HasId for {0}<'a> {{
-    fn append_ids(&self, _v: &mut Vec<usize>) {{ /* Nothing to do here */ }}
+    fn append_ids<V: IdVisitor>(&self, _v: &mut V) {{ /* Nothing to do here */ }}
+}}
+impl<'a> Write for {0}<'a> {{
+    fn write(&self, out: &mut String) {{
+        self.0.write(out);
+    }}
+}}
+impl<'a> IntoStatic for {0}<'a> {{
+    type Static = {0}<'static>;
+    fn into_static(self) -> Self::Static {{
+        {0}(self.0.into_static(), std::marker::PhantomData)
+    }}
 }}
 "#,
                     camel_name, c, strip_lifetime(c))?;
@@ -177,12 +311,14 @@ impl<'a> HasId for {0}<'a> {{
 
             Type::Enum(c) => {
                 writeln!(buf, "#[derive(Debug)]
+#[cfg_attr(feature = \"serde\", derive(serde::Serialize, serde::Deserialize))]
 pub enum {}<'a> {{ // enum", camel_name)?;
                 for v in c {
                     writeln!(buf, "    {},", to_camel(v))?;
                 }
                 writeln!(buf,
-                    r#"    _Unused(std::marker::PhantomData<&'a ()>),
+                    r#"    #[cfg_attr(feature = "serde", serde(skip))]
+    _Unused(std::marker::PhantomData<&'a ()>),
 }}
 impl<'a> Parse<'a> for {0}<'a> {{
     fn parse(s: &'a str) -> IResult<'a, Self> {{
@@ -200,9 +336,33 @@ impl<'a> Parse<'a> for {0}<'a> {{
     }}
 }}
 impl<'a> HasId for {0}<'a> {{
-    fn append_ids(&self, _v: &mut Vec<usize>) {{ /* nothing to do here */ }}
+    fn append_ids<V: IdVisitor>(&self, _v: &mut V) {{ /* nothing to do here */ }}
 }}
-"#, camel_name)?;
+impl<'a> Write for {0}<'a> {{
+    fn write(&self, out: &mut String) {{
+        use {0}::*;
+        out.push_str(match self {{"#, camel_name)?;
+                for enum_tag in c {
+                    writeln!(buf, r#"            {} => ".{}.","#,
+                        to_camel(enum_tag), capitalize(enum_tag))?;
+                }
+                writeln!(buf, r#"            _Unused(_) => unreachable!(),
+        }})
+    }}
+}}
+impl<'a> IntoStatic for {0}<'a> {{
+    type Static = {0}<'static>;
+    fn into_static(self) -> Self::Static {{
+        use {0}::*;
+        match self {{"#, camel_name)?;
+                for enum_tag in c {
+                    writeln!(buf, "            {0} => {0},", to_camel(enum_tag))?;
+                }
+                writeln!(buf, r#"            _Unused(_) => unreachable!(),
+        }}
+    }}
+}}
+"#)?;
             },
 
             Type::Select(c) => {
@@ -216,6 +376,16 @@ impl<'a> HasId for {0}<'a> {{
                     writeln!(buf, "#[derive(Debug)]
 pub struct {0}_<'a>(std::marker::PhantomData<&'a ()>); // ambiguous select
 pub type {0}<'a> = Id<{0}_<'a>>;
+impl<'a> RefTarget for {0}_<'a> {{
+    // Members aren't tracked, so references are only checked for existence
+    const TARGETS: &'static [&'static str] = &[];
+}}
+impl<'a> IntoStatic for {0}_<'a> {{
+    type Static = {0}_<'static>;
+    fn into_static(self) -> Self::Static {{
+        {0}_(std::marker::PhantomData)
+    }}
+}}
 ", camel_name)?;
                     return Ok(());
                 } else if num_entities > 1 {
@@ -226,13 +396,15 @@ pub type {0}<'a> = Id<{0}_<'a>>;
                 }
 
                 writeln!(buf, "#[derive(Debug)]
+#[cfg_attr(feature = \"serde\", derive(serde::Serialize, serde::Deserialize))]
 pub enum {}<'a> {{ // select", camel_name)?;
                 for v in c {
                     writeln!(buf, "    {}({}),", to_camel(v),
                              type_map.to_rtype(v))?;
                 }
                 writeln!(buf,
-                    r#"    _Unused(std::marker::PhantomData<&'a ()>)
+                    r#"    #[cfg_attr(feature = "serde", serde(skip))]
+    _Unused(std::marker::PhantomData<&'a ()>)
 }}
 impl<'a> Parse<'a> for {}<'a> {{
     fn parse(s: &'a str) -> IResult<'a, Self> {{"#, camel_name)?;
@@ -273,13 +445,56 @@ impl<'a> Parse<'a> for {}<'a> {{
     }}
 }}
 impl<'a> HasId for {0}<'a> {{
-    fn append_ids(&self, _v: &mut Vec<usize>) {{
+    fn append_ids<V: IdVisitor>(&self, _v: &mut V) {{", camel_name)?;
+                // A bare `#id` is parsed into the first entity member, so the
+                // reference may actually point to any of them
+                if num_entities > 0 {
+                    writeln!(buf, "        const TARGETS: &[&str] = &[{}];",
+                        type_map.ref_targets(c).iter()
+                            .map(|t| format!(r#""{}""#, t))
+                            .collect::<Vec<_>>()
+                            .join(", "))?;
+                }
+                writeln!(buf, "        match self {{")?;
+                for v in c {
+                    if type_map.is_entity(v) {
+                        writeln!(buf, "            {}::{}(c) => _v.visit(c.0, TARGETS),",
+                            camel_name, to_camel(v))?;
+                    } else {
+                        writeln!(buf, "            {}::{}(c) => c.append_ids(_v),",
+                            camel_name, to_camel(v))?;
+                    }
+                }
+                writeln!(buf, "            _ => (),
+        }}
+    }}
+}}
+impl<'a> Write for {0}<'a> {{
+    fn write(&self, out: &mut String) {{
         match self {{", camel_name)?;
                 for v in c {
-                    writeln!(buf, "            {}::{}(c) => c.append_ids(_v),",
+                    if type_map.is_entity(v) {
+                        writeln!(buf, "            {}::{}(c) => c.write(out),",
+                            camel_name, to_camel(v))?;
+                    } else {
+                        writeln!(buf,
+                            r#"            {}::{}(c) => write_typed("{}", c, out),"#,
+                            camel_name, to_camel(v), capitalize(v))?;
+                    }
+                }
+                writeln!(buf, "            _ => unreachable!(),
+        }}
+    }}
+}}
+impl<'a> IntoStatic for {0}<'a> {{
+    type Static = {0}<'static>;
+    fn into_static(self) -> Self::Static {{
+        match self {{", camel_name)?;
+                for v in c {
+                    writeln!(buf, "            {0}::{1}(c) => {0}::{1}(c.into_static()),",
                         camel_name, to_camel(v))?;
                 }
-                writeln!(buf, "            _ => (),
+                writeln!(buf, "            _ => unreachable!(),
         }}
     }}
 }}")?;
@@ -288,19 +503,34 @@ impl<'a> HasId for {0}<'a> {{
             Type::Aggregation { type_, .. } => {
                 writeln!(buf,
                     r#"#[derive(Debug)]
-pub struct {0}<'a>(pub {1}, std::marker::PhantomData<&'a ()>); // aggregation
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "serde", serde(transparent))]
+pub struct {0}<'a>(pub {1}, #[cfg_attr(feature = "serde", serde(skip))] std::marker::PhantomData<&'a ()>); // aggregation
 impl<'a> Parse<'a> for {0}<'a> {{
     fn parse(s: &'a str) -> IResult<'a, Self> {{
         map(many0(<{2}>::parse), |r| Self(r, std::marker::PhantomData))(s)
     }}
 }}
 impl<'a> HasId for {0}<'a> {{
-    fn append_ids(&self, v: &mut Vec<usize>) {{
+    fn append_ids<V: IdVisitor>(&self, v: &mut V) {{
         for i in &self.0 {{
             i.append_ids(v);
         }}
     }}
 }}
+impl<'a> Write for {0}<'a> {{
+    fn write(&self, out: &mut String) {{
+        for i in &self.0 {{
+            i.write(out);
+        }}
+    }}
+}}
+impl<'a> IntoStatic for {0}<'a> {{
+    type Static = {0}<'static>;
+    fn into_static(self) -> Self::Static {{
+        {0}(self.0.into_static(), std::marker::PhantomData)
+    }}
+}}
 "#,
                     camel_name, type_map.to_inner_rtype(self),
                     type_map.to_inner_rtype(&*type_))?;
@@ -311,6 +541,7 @@ impl<'a> HasId for {0}<'a> {{
                     writeln!(buf, "#[allow(non_snake_case)]")?;
                 }
                 writeln!(buf, "#[derive(Debug)]
+#[cfg_attr(feature = \"serde\", derive(serde::Serialize, serde::Deserialize))]
 pub struct {}_<'a> {{ // entity", camel_name)?;
                 for a in attrs {
                     // Skip derived attributes in the struct
@@ -328,20 +559,26 @@ pub struct {}_<'a> {{ // entity", camel_name)?;
                         writeln!(buf, "{},", a.type_)?;
                     }
                 }
-                writeln!(buf, r#"    _marker: std::marker::PhantomData<&'a ()>,
+                writeln!(buf, r#"    #[cfg_attr(feature = "serde", serde(skip))]
+    _marker: std::marker::PhantomData<&'a ()>,
 }}
 pub type {0}<'a> = Id<{0}_<'a>>;
+impl<'a> RefTarget for {0}_<'a> {{
+    const TARGETS: &'static [&'static str] = &["{1}"];
+}}
 impl<'a> FromEntity<'a> for {0}_<'a> {{
+    const TYPE_NAME: &'static str = "{1}";
     fn try_from_entity(e: &'a Entity<'a>) -> Option<&'a Self> {{
         match e {{
             Entity::{0}(v) => Some(v),
+            Entity::ComplexEntity(es) => es.iter().find_map(Self::try_from_entity),
             _ => None,
         }}
     }}
 }}
 impl<'a> ParseFromChunks<'a> for {0}_<'a> {{
     fn parse_chunks(strs: &[&'a str]) -> IResult<'a, Self> {{"#,
-                    camel_name)?;
+                    camel_name, capitalize(name))?;
 
                 // If we'll be reading attributes, then we need an index
                 if !attrs.is_empty() {
@@ -387,7 +624,7 @@ impl<'a> ParseFromChunks<'a> for {0}_<'a> {{
     }}
 }}
 impl<'a> HasId for {}_<'a> {{
-    fn append_ids(&self, _v: &mut Vec<usize>) {{", camel_name)?;
+    fn append_ids<V: IdVisitor>(&self, _v: &mut V) {{", camel_name)?;
                 for a in attrs.iter().filter(|a| !a.derived) {
                     if a.dupe {
                         writeln!(buf, "        self.{}__{}.append_ids(_v);",
@@ -397,6 +634,35 @@ impl<'a> HasId for {}_<'a> {{
                     }
                 }
                 writeln!(buf, "    }}
+}}
+impl<'a> WriteAttrs for {}_<'a> {{
+    fn write_attrs(&self, _out: &mut Vec<String>) {{", camel_name)?;
+                for a in attrs {
+                    if a.derived {
+                        writeln!(buf, "        write_attr(&Derived, _out);")?;
+                    } else if a.dupe {
+                        writeln!(buf, "        write_attr(&self.{}__{}, _out);",
+                                 a.from.unwrap(), a.name)?;
+                    } else {
+                        writeln!(buf, "        write_attr(&self.{}, _out);", a.name)?;
+                    }
+                }
+                writeln!(buf, "    }}
+}}
+impl<'a> IntoStatic for {0}_<'a> {{
+    type Static = {0}_<'static>;
+    fn into_static(self) -> Self::Static {{
+        {0}_ {{", camel_name)?;
+                for a in attrs.iter().filter(|a| !a.derived) {
+                    if a.dupe {
+                        writeln!(buf, "            {0}__{1}: self.{0}__{1}.into_static(),",
+                                 a.from.unwrap(), a.name)?;
+                    } else {
+                        writeln!(buf, "            {0}: self.{0}.into_static(),", a.name)?;
+                    }
+                }
+                writeln!(buf, "            _marker: std::marker::PhantomData}}
+    }}
 }}")?;
             },
             Type::Primitive(_) => (),
@@ -425,8 +691,12 @@ enum Ref<'a> {
 
 ////////////////////////////////////////////////////////////////////////////////
 
-pub fn gen(s: &mut Syntax) -> Result<String, std::fmt::Error> {
+/// Generates the Rust module for a single schema.  `module` is the name of
+/// the output module in the `step` crate (e.g. `ap214`), which is recorded
+/// in its documentation.
+pub fn gen(s: &mut Syntax, module: &str) -> Result<String, std::fmt::Error> {
     assert!(s.0.len() == 1, "Multiple schemas are unsupported");
+    let schema = capitalize(s.0[0].id.0);
 
     // First pass: collect entity names, then convert ambiguous IDs in SELECT
     // data types into Entity or Type refs
@@ -445,7 +715,7 @@ pub fn gen(s: &mut Syntax) -> Result<String, std::fmt::Error> {
     type_map.0.insert("bool", Type::Primitive("bool"));
     type_map.0.insert("i64", Type::Primitive("i64"));
     type_map.0.insert("f64", Type::Primitive("f64"));
-    type_map.0.insert("&'a str", Type::Primitive("&'a str"));
+    type_map.0.insert("Cow<'a, str>", Type::Primitive("Cow<'a, str>"));
 
     for k in ref_map.keys() {
         type_map.build(k);
@@ -456,11 +726,15 @@ pub fn gen(s: &mut Syntax) -> Result<String, std::fmt::Error> {
     keys.sort_unstable();
     let mut buf = String::new();
     writeln!(&mut buf, "// Autogenerated file, do not hand-edit!
+//! Types for the `{}` schema, generated as `step::{}`
+use std::borrow::Cow;
 use crate::{{
-    id::{{Id, HasId}},
+    id::{{Id, HasId, IdVisitor, RefTarget}},
+    owned::IntoStatic,
     parse::{{IResult, Logical, Derived, Parse, ParseFromChunks, nom_alt_err,
             parse_enum_tag, param_from_chunks, parse_complex_mapping}},
     step_file::FromEntity,
+    write::{{Write, WriteAttrs, write_attr, write_typed}},
 }};
 use nom::{{
     branch::{{alt}},
@@ -470,17 +744,24 @@ use nom::{{
     multi::{{many0}},
     sequence::{{delimited, pair}},
 }};
-use arrayvec::ArrayVec;")?;
+use arrayvec::ArrayVec;
+
+/// Name of the schema, as it's written in the `FILE_SCHEMA` header
+pub const SCHEMA_NAME: &str = \"{}\";", schema, module, schema)?;
 
     for k in &keys {
         type_map.0[k].write_type(k, &mut buf, &type_map)?;
     }
     writeln!(&mut buf, "#[derive(Debug)]
+#[cfg_attr(feature = \"serde\", derive(serde::Serialize, serde::Deserialize))]
 pub enum Entity<'a> {{")?;
     for k in &keys {
         type_map.0[k].write_enum_variant(k, &mut buf)?;
     }
     writeln!(&mut buf, r#"    ComplexEntity(Vec<Entity<'a>>),
+    /// A partial entity with no attributes of its own, which is kept by name
+    /// within a `ComplexEntity` (e.g. `LENGTH_UNIT()` in a complex unit)
+    _EmptyPartial(Cow<'a, str>),
     _FailedToParse,
     _EmptySlot,
 }}
@@ -508,25 +789,101 @@ pub fn superclasses_of(s: &str) -> &[&str] {{
     writeln!(&mut buf, "        _ => &[],
     }}
 }}
-impl<'a> Entity<'a> {{
-    pub fn upstream(&self) -> Vec<usize> {{
-        let mut out = Vec::new();
+
+pub fn attributes_of(s: &str) -> &[&str] {{
+    match s {{")?;
+    for k in &keys {
+        type_map.0[k].write_attributes(k, &mut buf)?;
+    }
+    writeln!(&mut buf, "        _ => &[],
+    }}
+}}
+
+pub fn attribute_kinds_of(s: &str) -> &[&str] {{
+    match s {{")?;
+    for k in &keys {
+        type_map.0[k].write_attribute_kinds(k, &mut buf, &type_map)?;
+    }
+    writeln!(&mut buf, "        _ => &[],
+    }}
+}}
+
+pub fn defined_type_kind(s: &str) -> Option<&'static str> {{
+    match s {{")?;
+    for k in &keys {
+        type_map.0[k].write_defined_type_kind(k, &mut buf, &type_map)?;
+    }
+    writeln!(&mut buf, "        _ => None,
+    }}
+}}
+
+pub fn is_entity_type(s: &str) -> bool {{
+    match s {{")?;
+    for k in keys.iter().filter(|k| type_map.0[*k].is_entity()) {
+        writeln!(&mut buf, r#"        "{}" => true,"#, capitalize(k))?;
+    }
+    writeln!(&mut buf, "        _ => false,
+    }}
+}}
+impl<'a> HasId for Entity<'a> {{
+    fn append_ids<V: IdVisitor>(&self, v: &mut V) {{
         match self {{
 ")?;
     for k in keys.iter().filter(|k| type_map.0[*k].is_entity()) {
         writeln!(&mut buf,
-            "            Entity::{}(c) => c.append_ids(&mut out),",
+            "            Entity::{}(c) => c.append_ids(v),",
             to_camel(k))?;
     }
-    writeln!(&mut buf, "            Entity::ComplexEntity(v) => {{
-                for e in v {{
-                    out.extend(e.upstream().into_iter());
+    writeln!(&mut buf, "            Entity::ComplexEntity(es) => {{
+                for e in es {{
+                    e.append_ids(v);
                 }}
             }},
             _ => (),
         }};
+    }}
+}}
+impl<'a> Entity<'a> {{
+    pub fn upstream(&self) -> Vec<usize> {{
+        let mut out = Vec::new();
+        self.append_ids(&mut out);
         out
     }}
+    pub fn type_name(&self) -> Option<&'static str> {{
+        match self {{")?;
+    for k in keys.iter().filter(|k| type_map.0[*k].is_entity()) {
+        writeln!(&mut buf, r#"            Entity::{}(_) => Some("{}"),"#,
+            to_camel(k), capitalize(k))?;
+    }
+    writeln!(&mut buf, "            _ => None,
+        }}
+    }}
+    pub(crate) fn write_attrs(&self, out: &mut Vec<String>) {{
+        match self {{")?;
+    for k in keys.iter().filter(|k| type_map.0[*k].is_entity()) {
+        writeln!(&mut buf,
+            "            Entity::{}(c) => c.write_attrs(out),",
+            to_camel(k))?;
+    }
+    writeln!(&mut buf, "            _ => (),
+        }}
+    }}
+}}
+impl<'a> IntoStatic for Entity<'a> {{
+    type Static = Entity<'static>;
+    fn into_static(self) -> Self::Static {{
+        match self {{")?;
+    for k in keys.iter().filter(|k| type_map.0[*k].is_entity()) {
+        writeln!(&mut buf,
+            "            Entity::{0}(c) => Entity::{0}(c.into_static()),",
+            to_camel(k))?;
+    }
+    writeln!(&mut buf, "            Entity::ComplexEntity(es) => Entity::ComplexEntity(es.into_static()),
+            Entity::_EmptyPartial(n) => Entity::_EmptyPartial(n.into_static()),
+            Entity::_FailedToParse => Entity::_FailedToParse,
+            Entity::_EmptySlot => Entity::_EmptySlot,
+        }}
+    }}
 }}")?;
 
     Ok(buf)
@@ -947,7 +1304,7 @@ impl <'a> SimpleTypes<'a> {
             SimpleTypes::Logical => "Logical",
             SimpleTypes::Number => "f64",
             SimpleTypes::Real(_) => "f64",
-            SimpleTypes::String(_) => "&'a str",
+            SimpleTypes::String(_) => "Cow<'a, str>",
         }
     }
     fn to_type(&self) -> Type {
@@ -88,9 +88,9 @@ fn main() {
         use step::step_file::StepFile;
         use triangulate::triangulate::triangulate;
 
-        let data = std::fs::read(input).expect("Could not open file");
+        let data = StepFile::read_file(input).expect("Could not open file");
         let flat = StepFile::strip_flatten(&data);
-        let step = StepFile::parse(&flat);
+        let (step, _report) = StepFile::parse(&flat);
         let (mesh, _stats) = triangulate(&step);
         mesh
     });
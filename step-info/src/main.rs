@@ -0,0 +1,230 @@
+use std::collections::HashMap;
+use std::io::{ErrorKind, Write};
+use clap::{App, AppSettings, Arg, ArgMatches, SubCommand};
+
+use step::{
+    ap214::{Entity, Product_, RepresentationContext},
+    id::Id,
+    report::ParseReport,
+    step_file::StepFile,
+    units::Units,
+};
+
+fn main() -> Result<(), Box<dyn std::error::Error>> {
+    env_logger::init();
+
+    let id_arg = || Arg::with_name("id")
+        .help("entity id, e.g. #12")
+        .takes_value(true)
+        .required(true);
+    let matches = App::new("step-info")
+        .author("Matt Keeter <matt@formlabs.com>")
+        .about("Inspects a STEP file")
+        .setting(AppSettings::SubcommandRequiredElseHelp)
+        .arg(Arg::with_name("input")
+            .takes_value(true)
+            .required(true))
+        .subcommand(SubCommand::with_name("summary")
+            .about("Prints the schema, units, products, and entity counts"))
+        .subcommand(SubCommand::with_name("show")
+            .about("Prints an entity and the entities that it refers to")
+            .arg(id_arg())
+            .arg(Arg::with_name("depth")
+                .short("d")
+                .long("depth")
+                .help("levels of referenced entities to print")
+                .takes_value(true)
+                .default_value("1")))
+        .subcommand(SubCommand::with_name("grep")
+            .about("Lists every instance of an entity type (including subtypes)")
+            .arg(Arg::with_name("type")
+                .help("entity type, e.g. ADVANCED_FACE")
+                .takes_value(true)
+                .required(true))
+            .arg(Arg::with_name("count")
+                .short("c")
+                .long("count")
+                .help("only print the number of instances")))
+        .subcommand(SubCommand::with_name("refs")
+            .about("Lists the entities which refer to an entity")
+            .arg(id_arg()))
+        .get_matches();
+    let input = matches.value_of("input")
+        .expect("Could not get input file");
+
+    let data = StepFile::read_file(input)?;
+    let flat = StepFile::strip_flatten(&data);
+    let (s, mut report) = StepFile::parse(&flat);
+    report.locate(&data);
+
+    let stdout = std::io::stdout();
+    let out = &mut stdout.lock();
+    let r = match matches.subcommand() {
+        ("summary", _) => summary(&s, &report, out),
+        ("show", Some(m)) => {
+            let depth = m.value_of("depth").unwrap().parse()
+                .map_err(|_| "Invalid depth")?;
+            show(&s, parse_id(m)?, depth, out)
+        },
+        ("grep", Some(m)) =>
+            grep(&s, m.value_of("type").unwrap(), m.is_present("count"), out),
+        ("refs", Some(m)) => refs(&s, parse_id(m)?, out),
+        _ => unreachable!(),
+    };
+    match r {
+        // Output is often piped into `head` or `less`, which may exit early
+        Err(e) if e.kind() == ErrorKind::BrokenPipe => Ok(()),
+        r => Ok(r?),
+    }
+}
+
+/// Parses an entity id, with or without the leading `#`
+fn parse_id(m: &ArgMatches) -> Result<usize, String> {
+    let id = m.value_of("id").unwrap();
+    id.trim_start_matches('#').parse()
+        .map_err(|_| format!("Invalid entity id '{}'", id))
+}
+
+/// Returns a readable type name, including the parts of a complex entity
+fn type_name(e: &Entity) -> String {
+    match e {
+        Entity::ComplexEntity(v) => format!("({})", v.iter()
+            .map(type_name)
+            .collect::<Vec<_>>()
+            .join(" ")),
+        Entity::_EmptyPartial(n) => n.to_string(),
+        Entity::_FailedToParse => "<failed to parse>".to_owned(),
+        e => e.type_name().unwrap_or("<unknown>").to_owned(),
+    }
+}
+
+/// Formats an entity, falling back to a note if it's missing or failed
+fn format(s: &StepFile, i: usize) -> String {
+    s.format_entity(i).unwrap_or_else(|| match s.0.get(i) {
+        Some(Entity::_FailedToParse) => format!("#{}: failed to parse", i),
+        _ => format!("#{}: not found", i),
+    })
+}
+
+fn summary(s: &StepFile, report: &ParseReport, out: &mut impl Write)
+    -> std::io::Result<()>
+{
+    let h = &s.1;
+    writeln!(out, "Name:     {}", h.name)?;
+    writeln!(out, "System:   {}", h.originating_system)?;
+    writeln!(out, "Schema:   {}", h.schema_identifiers.join(", "))?;
+
+    let count = s.0.iter().filter(|e| !matches!(e, Entity::_EmptySlot)).count();
+    writeln!(out, "Entities: {} ({} failed to parse)", count, report.failures.len())?;
+    for f in report.failures.iter().take(10) {
+        writeln!(out, "  {} at line {}: {:?}",
+            f.id.map(|i| format!("#{}", i)).unwrap_or_else(|| "entity".to_owned()),
+            f.line.unwrap_or(0), f.kind)?;
+    }
+    if report.failures.len() > 10 {
+        writeln!(out, "  ...")?;
+    }
+
+    // Every geometric context has its own units, but they usually match
+    writeln!(out, "\nUnits:")?;
+    let mut units: Vec<(Units, Vec<usize>)> = Vec::new();
+    for (i, _) in s.0.iter().enumerate()
+        .filter(|(_, e)| e.is_instance_of("GLOBAL_UNIT_ASSIGNED_CONTEXT"))
+    {
+        let u = Units::of_context(s, RepresentationContext::new(i));
+        match units.iter_mut().find(|(v, _)| *v == u) {
+            Some((_, ids)) => ids.push(i),
+            None => units.push((u, vec![i])),
+        }
+    }
+    for (u, ids) in &units {
+        let ids: Vec<_> = ids.iter().map(|i| format!("#{}", i)).collect();
+        writeln!(out, "  length {} m, plane angle {} rad, solid angle {} sr ({})",
+                 u.length, u.plane_angle, u.solid_angle, ids.join(", "))?;
+    }
+
+    writeln!(out, "\nProducts:")?;
+    for (p, n) in s.assembly().quantities() {
+        if let Some(e) = s.entity::<Product_>(p) {
+            writeln!(out, "  #{} {} '{}' x{}", p.0, e.id.0, e.name.0, n)?;
+        }
+    }
+
+    let mut counts: HashMap<String, usize> = HashMap::new();
+    for e in s.0.iter().filter(|e| !matches!(e, Entity::_EmptySlot)) {
+        *counts.entry(type_name(e)).or_default() += 1;
+    }
+    let mut counts: Vec<_> = counts.into_iter().collect();
+    counts.sort_by(|a, b| b.1.cmp(&a.1).then_with(|| a.0.cmp(&b.0)));
+    writeln!(out, "\nEntity types:")?;
+    for (t, n) in counts {
+        writeln!(out, "  {:>8} {}", n, t)?;
+    }
+    Ok(())
+}
+
+fn show(s: &StepFile, id: usize, depth: usize, out: &mut impl Write)
+    -> std::io::Result<()>
+{
+    fn recurse(s: &StepFile, i: usize, level: usize, depth: usize,
+               seen: &mut Vec<bool>, out: &mut impl Write) -> std::io::Result<()>
+    {
+        let indent = "  ".repeat(level);
+        if seen.get(i).copied().unwrap_or(false) {
+            writeln!(out, "{}#{} (see above)", indent, i)?;
+            return Ok(());
+        }
+        writeln!(out, "{}{}", indent, format(s, i))?;
+        if let Some(b) = seen.get_mut(i) {
+            *b = true;
+        }
+        if level < depth {
+            // Children are listed in order of first reference, skipping
+            // repeats and NUL references
+            let mut children: Vec<usize> = Vec::new();
+            for j in s.0.get(i).map(|e| e.upstream()).unwrap_or_default() {
+                if j != 0 && !children.contains(&j) {
+                    children.push(j);
+                }
+            }
+            for j in children {
+                recurse(s, j, level + 1, depth, seen, out)?;
+            }
+        }
+        Ok(())
+    }
+    recurse(s, id, 0, depth, &mut vec![false; s.0.len()], out)
+}
+
+fn grep(s: &StepFile, name: &str, count: bool, out: &mut impl Write)
+    -> std::io::Result<()>
+{
+    let name = name.to_ascii_uppercase();
+    let ids: Vec<_> = s.0.iter()
+        .enumerate()
+        .filter(|(_, e)| e.is_instance_of(&name))
+        .map(|(i, _)| i)
+        .collect();
+    if count {
+        writeln!(out, "{}", ids.len())?;
+    } else if ids.is_empty() {
+        eprintln!("No instances of {}", name);
+    } else {
+        for i in ids {
+            writeln!(out, "{}", format(s, i))?;
+        }
+    }
+    Ok(())
+}
+
+fn refs(s: &StepFile, id: usize, out: &mut impl Write) -> std::io::Result<()> {
+    let index = s.reverse_index();
+    let referrers = index.referrers(Id::<()>::new(id));
+    if referrers.is_empty() {
+        eprintln!("Nothing refers to #{}", id);
+    }
+    for i in referrers {
+        writeln!(out, "{}", format(s, *i))?;
+    }
+    Ok(())
+}
@@ -15,10 +15,18 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
 
     let start = SystemTime::now();
 
-    let data = std::fs::read(input)?;
+    let data = StepFile::read_file(input)?;
     let flat = StepFile::strip_flatten(&data);
-    let entities = StepFile::parse(&flat);
+    let (entities, mut report) = StepFile::parse(&flat);
     println!("Got {} entities", entities.0.len());
+    report.locate(&data);
+    for f in &report.failures {
+        println!("Failed to parse {} at line {}: {:?}{}",
+            f.id.map(|i| format!("#{}", i))
+                .unwrap_or_else(|| "entity".to_owned()),
+            f.line.unwrap_or(0), f.kind,
+            if f.fallback { "" } else { " (dropped)" });
+    }
 
     let end = SystemTime::now();
     let since_the_epoch = end.duration_since(start).expect("Time went backwards");
@@ -33,9 +33,9 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
         .expect("Could not get input file");
 
     let start = std::time::SystemTime::now();
-    let data = std::fs::read(input)?;
+    let data = StepFile::read_file(input)?;
     let flat = StepFile::strip_flatten(&data);
-    let entities = StepFile::parse(&flat);
+    let (entities, _report) = StepFile::parse(&flat);
     let end = std::time::SystemTime::now();
     let since_the_epoch = end.duration_since(start)
         .expect("Time went backwards");
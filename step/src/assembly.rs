@@ -0,0 +1,398 @@
+use std::collections::{HashMap, HashSet};
+use log::warn;
+
+use crate::{
+    ap214::{
+        Entity, Axis2Placement3d_, CartesianPoint_, ContextDependentShapeRepresentation_,
+        Direction, Direction_, ItemDefinedTransformation_, NextAssemblyUsageOccurrence,
+        NextAssemblyUsageOccurrence_, Product, ProductDefinition,
+        ProductDefinitionShape_, Product_, Representation,
+        RepresentationRelationshipWithTransformation_,
+        ShapeDefinitionRepresentation_, ShapeRepresentationRelationship,
+    },
+    id::Id,
+    step_file::{FromEntity, StepFile},
+};
+
+/// A 4x4 affine transform in row-major order, which maps points from a
+/// child's coordinate system into its parent's
+pub type Transform = [[f64; 4]; 4];
+
+pub const IDENTITY: Transform = [
+    [1.0, 0.0, 0.0, 0.0],
+    [0.0, 1.0, 0.0, 0.0],
+    [0.0, 0.0, 1.0, 0.0],
+    [0.0, 0.0, 0.0, 1.0],
+];
+
+/// One occurrence of a product in the assembly tree.  A product which is
+/// used several times (e.g. a screw) has one node per usage.
+#[derive(Debug)]
+pub struct AssemblyNode<'a> {
+    pub product: Product<'a>,
+    pub id: &'a str,
+    pub name: &'a str,
+    pub description: Option<&'a str>,
+    pub definition: ProductDefinition<'a>,
+
+    /// The `NEXT_ASSEMBLY_USAGE_OCCURRENCE` which places this node in its
+    /// parent, or `None` for a root
+    pub occurrence: Option<NextAssemblyUsageOccurrence<'a>>,
+    /// Placement relative to the parent node (identity for a root)
+    pub transform: Transform,
+    /// Shape representations attached to the product definition
+    pub shapes: Vec<Representation<'a>>,
+    pub children: Vec<AssemblyNode<'a>>,
+}
+
+impl<'a> AssemblyNode<'a> {
+    /// Iterates over this node and all of its descendants, depth-first
+    pub fn iter(&self) -> Nodes<'_, 'a> {
+        Nodes(vec![self])
+    }
+}
+
+/// The product structure of a file, built from `PRODUCT`,
+/// `PRODUCT_DEFINITION`, `NEXT_ASSEMBLY_USAGE_OCCURRENCE` and
+/// `CONTEXT_DEPENDENT_SHAPE_REPRESENTATION` entities
+#[derive(Debug)]
+pub struct AssemblyTree<'a> {
+    /// Product definitions which aren't used by any assembly, in file order
+    pub roots: Vec<AssemblyNode<'a>>,
+}
+
+impl<'a> AssemblyTree<'a> {
+    pub fn new(s: &'a StepFile<'a>) -> Self {
+        let shapes = shapes_by_definition(s);
+
+        // Map from parent product definition to (occurrence, child)
+        let mut usages: HashMap<usize, Vec<(usize, usize)>> = HashMap::new();
+        let mut used = HashSet::new();
+        for (i, n) in s.0.iter().enumerate()
+            .filter_map(|(i, e)| NextAssemblyUsageOccurrence_::try_from_entity(e)
+                .map(|n| (i, n)))
+        {
+            usages.entry(n.relating_product_definition.0)
+                .or_default()
+                .push((i, n.related_product_definition.0));
+            used.insert(n.related_product_definition.0);
+        }
+
+        // Map from occurrence to the relationship which places it
+        let mut placements = HashMap::new();
+        for c in s.0.iter()
+            .filter_map(ContextDependentShapeRepresentation_::try_from_entity)
+        {
+            if let Some(p) = get::<ProductDefinitionShape_>(s, c.represented_product_relation.0) {
+                placements.insert(p.definition.0, c.representation_relation);
+            }
+        }
+
+        let b = Builder { s, shapes, usages, placements };
+        let roots = (0..s.0.len())
+            .filter(|i| !used.contains(i) && product_definition(s, *i).is_some())
+            .filter_map(|i| b.node(i, None, IDENTITY, &mut vec![]))
+            .collect();
+        Self { roots }
+    }
+
+    /// Iterates over every node in the tree, depth-first
+    pub fn iter(&self) -> Nodes<'_, 'a> {
+        Nodes(self.roots.iter().rev().collect())
+    }
+
+    /// Counts how many times each product occurs in the tree, in the order
+    /// of first appearance
+    pub fn quantities(&self) -> Vec<(Product<'a>, usize)> {
+        let mut index = HashMap::new();
+        let mut out: Vec<(Product<'a>, usize)> = Vec::new();
+        for n in self.iter() {
+            let i = *index.entry(n.product).or_insert_with(|| {
+                out.push((n.product, 0));
+                out.len() - 1
+            });
+            out[i].1 += 1;
+        }
+        out
+    }
+}
+
+/// Depth-first iterator over an assembly tree
+pub struct Nodes<'t, 'a>(Vec<&'t AssemblyNode<'a>>);
+impl<'t, 'a> Iterator for Nodes<'t, 'a> {
+    type Item = &'t AssemblyNode<'a>;
+    fn next(&mut self) -> Option<Self::Item> {
+        let n = self.0.pop()?;
+        self.0.extend(n.children.iter().rev());
+        Some(n)
+    }
+}
+
+////////////////////////////////////////////////////////////////////////////////
+
+struct Builder<'a> {
+    s: &'a StepFile<'a>,
+    shapes: HashMap<usize, Vec<Representation<'a>>>,
+    usages: HashMap<usize, Vec<(usize, usize)>>,
+    placements: HashMap<usize, ShapeRepresentationRelationship<'a>>,
+}
+
+impl<'a> Builder<'a> {
+    /// Builds the node for product definition `pd`.  `path` holds the
+    /// product definitions above this node, to break cycles.
+    fn node(&self, pd: usize, occurrence: Option<usize>, transform: Transform,
+            path: &mut Vec<usize>) -> Option<AssemblyNode<'a>>
+    {
+        if path.contains(&pd) {
+            warn!("Assembly cycle at #{}", pd);
+            return None;
+        }
+        let product = product_definition(self.s, pd)?;
+        let p = match get::<Product_>(self.s, product) {
+            Some(p) => p,
+            None => {
+                warn!("Could not get product for #{}", pd);
+                return None;
+            },
+        };
+
+        path.push(pd);
+        let children = self.usages.get(&pd)
+            .into_iter()
+            .flatten()
+            .filter_map(|(n, child)| {
+                let t = self.transform(*n, pd, *child);
+                self.node(*child, Some(*n), t, path)
+            })
+            .collect();
+        path.pop();
+
+        Some(AssemblyNode {
+            product: Id::new(product),
+            id: p.id.0.as_ref(),
+            name: p.name.0.as_ref(),
+            description: p.description.as_ref().map(|d| d.0.as_ref()),
+            definition: Id::new(pd),
+            occurrence: occurrence.map(Id::new),
+            transform,
+            shapes: self.shapes.get(&pd).cloned().unwrap_or_default(),
+            children,
+        })
+    }
+
+    /// Finds the transform which places `child` in `parent` for the given
+    /// occurrence, falling back to the identity transform.
+    fn transform(&self, occurrence: usize, parent: usize, child: usize) -> Transform {
+        let rel = match self.placements.get(&occurrence) {
+            Some(r) => r.0,
+            None => return IDENTITY,
+        };
+        let r = match relationship_with_transformation(self.s, rel) {
+            Some(r) => r,
+            None => return IDENTITY,
+        };
+        let t = match item_defined_transformation(self.s, r.transformation_operator.0) {
+            Some(t) => t,
+            None => {
+                warn!("Unsupported transformation operator at #{}", rel);
+                return IDENTITY;
+            },
+        };
+
+        // The standard says that rep_1 belongs to the child, but writers
+        // don't always agree, so check against the shapes that we know about.
+        let is_shape = |pd: usize, r: Representation| self.shapes.get(&pd)
+            .map(|v| v.contains(&r))
+            .unwrap_or(false);
+        if is_shape(child, r.rep_2) || is_shape(parent, r.rep_1) {
+            rigid_inverse(&t)
+        } else {
+            t
+        }
+    }
+}
+
+/// Returns the entity at index `i`, if it's present and of type `T`
+fn get<'a, T: FromEntity<'a>>(s: &'a StepFile<'a>, i: usize) -> Option<&'a T> {
+    s.0.get(i).and_then(T::try_from_entity)
+}
+
+/// Returns the product id for a product definition (or subtype) at `i`
+pub(crate) fn product_definition(s: &StepFile, i: usize) -> Option<usize> {
+    let formation = match s.0.get(i)? {
+        Entity::ProductDefinition(p) => p.formation.0,
+        Entity::ProductDefinitionWithAssociatedDocuments(p) => p.formation.0,
+        _ => return None,
+    };
+    match s.0.get(formation)? {
+        Entity::ProductDefinitionFormation(f) => Some(f.of_product.0),
+        Entity::ProductDefinitionFormationWithSpecifiedSource(f) => Some(f.of_product.0),
+        _ => None,
+    }
+}
+
+/// Collects shape representations attached to each product definition
+fn shapes_by_definition<'a>(s: &'a StepFile<'a>)
+    -> HashMap<usize, Vec<Representation<'a>>>
+{
+    let mut out: HashMap<usize, Vec<_>> = HashMap::new();
+    for r in s.0.iter().filter_map(ShapeDefinitionRepresentation_::try_from_entity) {
+        if let Some(p) = get::<ProductDefinitionShape_>(s, r.definition.0) {
+            out.entry(p.definition.0).or_default().push(r.used_representation);
+        }
+    }
+    out
+}
+
+/// Shape representation relationships are usually complex entities, so we
+/// look for their `_WITH_TRANSFORMATION` member
+fn relationship_with_transformation<'a>(s: &'a StepFile<'a>, i: usize)
+    -> Option<&'a RepresentationRelationshipWithTransformation_<'a>>
+{
+    match s.0.get(i)? {
+        Entity::ComplexEntity(v) => v.iter()
+            .find_map(RepresentationRelationshipWithTransformation_::try_from_entity),
+        e => RepresentationRelationshipWithTransformation_::try_from_entity(e),
+    }
+}
+
+/// Returns the transform from `transform_item_1` to `transform_item_2`
+fn item_defined_transformation(s: &StepFile, i: usize) -> Option<Transform> {
+    let t = get::<ItemDefinedTransformation_>(s, i)?;
+    let a = placement(s, t.transform_item_1.0)?;
+    let b = placement(s, t.transform_item_2.0)?;
+    Some(mul(&b, &rigid_inverse(&a)))
+}
+
+/// Converts an `AXIS2_PLACEMENT_3D` into a transform from its local frame
+pub(crate) fn placement(s: &StepFile, i: usize) -> Option<Transform> {
+    let a = get::<Axis2Placement3d_>(s, i)?;
+    let p = get::<CartesianPoint_>(s, a.location.0)?;
+
+    // Build an orthonormal frame, as described for `build_axes` in Part 42
+    let z = normalize(direction(s, a.axis, [0.0, 0.0, 1.0]));
+    let r = direction(s, a.ref_direction, [1.0, 0.0, 0.0]);
+    let d = dot(r, z);
+    let x = normalize([r[0] - d * z[0], r[1] - d * z[1], r[2] - d * z[2]]);
+    let y = cross(z, x);
+
+    let mut t = IDENTITY;
+    for (i, c) in p.coordinates.iter().enumerate() {
+        t[i][3] = c.0;
+    }
+    for i in 0..3 {
+        t[i][0] = x[i];
+        t[i][1] = y[i];
+        t[i][2] = z[i];
+    }
+    Some(t)
+}
+
+fn direction(s: &StepFile, d: Option<Direction>, default: [f64; 3]) -> [f64; 3] {
+    d.and_then(|d| get::<Direction_>(s, d.0))
+        .filter(|d| d.direction_ratios.len() == 3)
+        .map(|d| [d.direction_ratios[0], d.direction_ratios[1], d.direction_ratios[2]])
+        .unwrap_or(default)
+}
+
+fn dot(a: [f64; 3], b: [f64; 3]) -> f64 {
+    a[0] * b[0] + a[1] * b[1] + a[2] * b[2]
+}
+
+fn cross(a: [f64; 3], b: [f64; 3]) -> [f64; 3] {
+    [a[1] * b[2] - a[2] * b[1],
+     a[2] * b[0] - a[0] * b[2],
+     a[0] * b[1] - a[1] * b[0]]
+}
+
+fn normalize(a: [f64; 3]) -> [f64; 3] {
+    let n = dot(a, a).sqrt();
+    [a[0] / n, a[1] / n, a[2] / n]
+}
+
+pub(crate) fn mul(a: &Transform, b: &Transform) -> Transform {
+    let mut out = [[0.0; 4]; 4];
+    for (i, row) in out.iter_mut().enumerate() {
+        for (j, v) in row.iter_mut().enumerate() {
+            *v = (0..4).map(|k| a[i][k] * b[k][j]).sum();
+        }
+    }
+    out
+}
+
+/// Inverts a transform made of a rotation and translation
+fn rigid_inverse(t: &Transform) -> Transform {
+    let mut out = IDENTITY;
+    for i in 0..3 {
+        for j in 0..3 {
+            out[i][j] = t[j][i];
+        }
+        out[i][3] = -(0..3).map(|k| t[k][i] * t[k][3]).sum::<f64>();
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn assert_close(a: &Transform, b: &Transform) {
+        for (x, y) in a.iter().flatten().zip(b.iter().flatten()) {
+            assert!((x - y).abs() < 1e-9, "{:?} != {:?}", a, b);
+        }
+    }
+
+    #[test]
+    fn test_assembly_tree() {
+        let flat = StepFile::strip_flatten(include_bytes!("../../examples/abstract_pca.step"));
+        let (s, _) = StepFile::parse(&flat);
+        let tree = s.assembly();
+
+        let names: Vec<_> = tree.iter().map(|n| n.name).collect();
+        assert_eq!(names, vec!["PCB", "Board", "Open CASCADE STEP translator 6.8 1.1.1",
+                               "Free-Models", "9084755200", "Cylinder"]);
+        assert_eq!(tree.roots.len(), 1);
+
+        let pcb = &tree.roots[0];
+        assert_eq!(pcb.occurrence, None);
+        assert_eq!(pcb.shapes, vec![Id::new(10)]);
+        assert_close(&pcb.transform, &IDENTITY);
+
+        let board = &pcb.children[0];
+        assert_eq!(board.occurrence, Some(Id::new(219)));
+        assert_eq!(board.shapes, vec![Id::new(36)]);
+
+        let mut t = IDENTITY;
+        t[2][3] = -0.41148;
+        assert_close(&board.children[0].transform, &t);
+
+        let q: Vec<_> = tree.quantities().iter().map(|(p, n)| (p.0, *n)).collect();
+        assert_eq!(q[..3], [(7, 1), (33, 1), (206, 1)]);
+    }
+
+    #[test]
+    fn test_placement() {
+        let data = b"DATA;\
+            #1=CARTESIAN_POINT('',(1.,2.,3.));\
+            #2=DIRECTION('',(0.,0.,1.));\
+            #3=DIRECTION('',(0.,1.,0.));\
+            #4=AXIS2_PLACEMENT_3D('',#1,#2,#3);\
+            #5=AXIS2_PLACEMENT_3D('',#1,$,$);\
+            ENDSEC;";
+        let (s, _) = StepFile::parse(data);
+        let t = placement(&s, 4).unwrap();
+        assert_close(&t, &[
+            [0.0, -1.0, 0.0, 1.0],
+            [1.0, 0.0, 0.0, 2.0],
+            [0.0, 0.0, 1.0, 3.0],
+            [0.0, 0.0, 0.0, 1.0],
+        ]);
+        assert_close(&mul(&t, &rigid_inverse(&t)), &IDENTITY);
+
+        let mut u = IDENTITY;
+        u[0][3] = 1.0;
+        u[1][3] = 2.0;
+        u[2][3] = 3.0;
+        assert_close(&placement(&s, 5).unwrap(), &u);
+    }
+}
@@ -0,0 +1,80 @@
+use std::borrow::Cow;
+use std::io::{Cursor, Read};
+
+use crate::Error;
+
+const GZIP_MAGIC: &[u8] = &[0x1f, 0x8b];
+const ZIP_MAGIC: &[u8] = b"PK\x03\x04";
+
+/// Decompresses gzip data or a single-file zip archive, detected by their
+/// magic bytes.  Anything else is assumed to be an uncompressed file and is
+/// returned as-is.
+pub(crate) fn decompress(data: &[u8]) -> Result<Cow<'_, [u8]>, Error> {
+    if data.starts_with(GZIP_MAGIC) {
+        // MultiGzDecoder also handles files made by concatenating streams
+        let mut out = Vec::new();
+        flate2::read::MultiGzDecoder::new(data)
+            .read_to_end(&mut out)
+            .map_err(|e| Error::Decompress(e.to_string()))?;
+        Ok(Cow::Owned(out))
+    } else if data.starts_with(ZIP_MAGIC) {
+        unzip(data).map(Cow::Owned)
+    } else {
+        Ok(Cow::Borrowed(data))
+    }
+}
+
+fn unzip(data: &[u8]) -> Result<Vec<u8>, Error> {
+    let err = |e: zip::result::ZipError| Error::Decompress(e.to_string());
+    let mut archive = zip::ZipArchive::new(Cursor::new(data)).map_err(err)?;
+
+    // Directory entries don't count towards the single file
+    let mut files = Vec::new();
+    for i in 0..archive.len() {
+        if archive.by_index(i).map_err(err)?.is_file() {
+            files.push(i);
+        }
+    }
+    if files.len() != 1 {
+        return Err(Error::ZipEntries(files.len()));
+    }
+
+    let mut f = archive.by_index(files[0]).map_err(err)?;
+    let mut out = Vec::with_capacity(f.size() as usize);
+    f.read_to_end(&mut out).map_err(|e| Error::Decompress(e.to_string()))?;
+    Ok(out)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Write;
+
+    const DATA: &[u8] = b"ISO-10303-21;\nHEADER;\nENDSEC;\nDATA;\nENDSEC;\nEND-ISO-10303-21;\n";
+
+    fn zip_of(names: &[&str]) -> Vec<u8> {
+        let mut out = Cursor::new(Vec::new());
+        let mut z = zip::ZipWriter::new(&mut out);
+        for name in names {
+            z.start_file(*name, Default::default()).unwrap();
+            z.write_all(DATA).unwrap();
+        }
+        z.finish().unwrap();
+        drop(z);
+        out.into_inner()
+    }
+
+    #[test]
+    fn test_decompress() {
+        assert!(matches!(decompress(DATA), Ok(Cow::Borrowed(DATA))));
+
+        let mut gz = flate2::write::GzEncoder::new(Vec::new(), Default::default());
+        gz.write_all(DATA).unwrap();
+        let gz = gz.finish().unwrap();
+        assert_eq!(decompress(&gz).unwrap(), DATA);
+
+        assert_eq!(decompress(&zip_of(&["part.stp"])).unwrap(), DATA);
+        assert_eq!(decompress(&zip_of(&["a.stp", "b.stp"])), Err(Error::ZipEntries(2)));
+        assert!(matches!(decompress(&gz[..gz.len() / 2]), Err(Error::Decompress(_))));
+    }
+}
@@ -0,0 +1,477 @@
+use std::collections::HashMap;
+use std::collections::hash_map::DefaultHasher;
+use std::hash::{Hash, Hasher};
+
+use crate::{
+    ap214::{Entity, ShapeRepresentationRelationship_},
+    assembly::AssemblyTree,
+    step_file::{FromEntity, StepFile},
+    subset::{closure_into, replace_refs},
+    write::write_entity_decl,
+};
+
+/// A single change between two revisions of a file
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub enum Change {
+    /// Entity in the new file with no counterpart in the old file
+    Added(usize),
+    /// Entity in the old file with no counterpart in the new file
+    Removed(usize),
+    /// Entity whose own attributes changed, or which now refers to a
+    /// different entity.  Entities which only changed because something
+    /// that they refer to changed aren't reported.
+    Modified { old: usize, new: usize },
+}
+
+/// The part of a file which a changed entity belongs to
+#[derive(Debug, Clone, Eq, PartialEq, Hash)]
+pub enum Owner {
+    /// A solid (e.g. `MANIFOLD_SOLID_BREP`), along with its ids in the old
+    /// and new files and the name of the product which uses it
+    Brep { product: Option<String>, old: Option<usize>, new: Option<usize> },
+    /// Product structure, placements and shape representations of a product
+    Product(String),
+    /// Entities which aren't used by any product or solid
+    Unowned,
+}
+
+/// Changes belonging to a single owner
+#[derive(Debug)]
+pub struct DiffGroup {
+    pub owner: Owner,
+    pub changes: Vec<Change>,
+}
+
+/// Result of [`StepFile::diff`]
+#[derive(Debug, Default)]
+pub struct Diff {
+    /// Groups of changes, in order of first appearance
+    pub groups: Vec<DiffGroup>,
+}
+
+impl Diff {
+    pub fn is_empty(&self) -> bool {
+        self.groups.is_empty()
+    }
+}
+
+////////////////////////////////////////////////////////////////////////////////
+
+/// Structural summary of a single file, independent of entity numbering
+struct Graph {
+    /// Hash of each entity's type, or `None` if there's nothing to compare
+    /// (empty slots and entities which failed to parse)
+    kind: Vec<Option<u64>>,
+    /// Hash of the entity's attributes, with references replaced by the
+    /// deep hash of the entity which they point to
+    deep: Vec<u64>,
+    /// Hash of the entity's attributes, ignoring references
+    local: Vec<u64>,
+    /// References to other valid entities, in attribute order
+    refs: Vec<Vec<usize>>,
+}
+
+fn hash<T: Hash>(t: T) -> u64 {
+    let mut h = DefaultHasher::new();
+    t.hash(&mut h);
+    h.finish()
+}
+
+impl Graph {
+    fn new(s: &StepFile) -> Self {
+        #[derive(Copy, Clone, PartialEq)]
+        enum Mark { New, Active, Done }
+
+        let n = s.0.len();
+        let valid = |e: &Entity| !matches!(e, Entity::_EmptySlot | Entity::_FailedToParse);
+        let kind: Vec<Option<u64>> = s.0.iter()
+            .map(|e| match e {
+                e if !valid(e) => None,
+                Entity::ComplexEntity(v) => Some(hash(v.iter()
+                    .map(|e| e.type_name())
+                    .collect::<Vec<_>>())),
+                e => Some(hash(e.type_name())),
+            })
+            .collect();
+        let refs: Vec<Vec<usize>> = s.0.iter()
+            .map(|e| {
+                let mut v = e.upstream();
+                v.retain(|i| kind.get(*i).map(|k| k.is_some()).unwrap_or(false));
+                v
+            })
+            .collect();
+
+        // Compute hashes in post-order, so that references are hashed before
+        // the entities which use them.  References which close a cycle are
+        // hashed as a placeholder.
+        let mut deep = vec![0; n];
+        let mut local = vec![0; n];
+        let mut mark = vec![Mark::New; n];
+        let mut stack: Vec<(usize, usize)> = Vec::new();
+        let mut line = String::new();
+        for root in 0..n {
+            if kind[root].is_none() || mark[root] != Mark::New {
+                continue;
+            }
+            mark[root] = Mark::Active;
+            stack.push((root, 0));
+            while let Some((node, child)) = stack.last_mut() {
+                if let Some(&next) = refs[*node].get(*child) {
+                    *child += 1;
+                    if mark[next] == Mark::New {
+                        mark[next] = Mark::Active;
+                        stack.push((next, 0));
+                    }
+                    continue;
+                }
+                let i = *node;
+                stack.pop();
+
+                line.clear();
+                write_entity_decl(i, &s.0[i], &mut line);
+                let body = &line[line.find('=').unwrap() + 1..];
+                local[i] = hash(replace_refs(body, |_, out| out.push('#')));
+                deep[i] = hash(replace_refs(body, |j, out| {
+                    let h = match mark.get(j) {
+                        Some(Mark::Done) => deep[j],
+                        _ => 0,
+                    };
+                    out.push_str(&format!("#{:x}", h));
+                }));
+                mark[i] = Mark::Done;
+            }
+        }
+        Self { kind, deep, local, refs }
+    }
+
+    fn len(&self) -> usize {
+        self.kind.len()
+    }
+
+    /// Finds entities in `unmatched` which aren't referred to by another
+    /// entity in `unmatched`.  These are where changes start, walking down
+    /// from the top of the graph.
+    fn seeds(&self, unmatched: &[bool]) -> Vec<usize> {
+        let mut has_parent = vec![false; self.len()];
+        for (i, r) in self.refs.iter().enumerate().filter(|(i, _)| unmatched[*i]) {
+            for j in r.iter().filter(|j| **j != i) {
+                has_parent[*j] = true;
+            }
+        }
+        (0..self.len()).filter(|i| unmatched[*i] && !has_parent[*i]).collect()
+    }
+}
+
+////////////////////////////////////////////////////////////////////////////////
+
+/// Owner of each entity within a single file, before ids are matched up
+#[derive(Clone)]
+enum LocalOwner {
+    Brep(usize, Option<String>),
+    Product(String),
+}
+
+fn is_brep(e: &Entity) -> bool {
+    e.is_instance_of("MANIFOLD_SOLID_BREP") || e.is_instance_of("SHELL_BASED_SURFACE_MODEL")
+}
+
+/// Assigns each entity to (at most) one owner.  Solids claim their geometry
+/// first, then products claim their shape representations and structure.
+///
+/// Claimed entities are never walked again, so this is a single linear pass
+/// over the file, even for large assemblies with many solids.
+fn owners(s: &StepFile) -> Vec<Option<LocalOwner>> {
+    let mut out = vec![None; s.0.len()];
+    let mut owned = vec![false; s.0.len()];
+    let claim = |ids: Vec<usize>, o: LocalOwner, out: &mut Vec<Option<LocalOwner>>| {
+        for i in ids {
+            out[i] = Some(o.clone());
+        }
+    };
+
+    // Shape representations are often linked to their breps' representation
+    // by a SHAPE_REPRESENTATION_RELATIONSHIP, rather than including them.
+    // Relationships with a transformation place children, which are handled
+    // by the assembly tree instead.
+    let mut related: HashMap<usize, Vec<usize>> = HashMap::new();
+    for r in s.0.iter()
+        .filter(|e| !e.is_instance_of("REPRESENTATION_RELATIONSHIP_WITH_TRANSFORMATION"))
+        .filter_map(ShapeRepresentationRelationship_::try_from_entity)
+    {
+        related.entry(r.rep_1.0).or_default().push(r.rep_2.0);
+    }
+
+    // Walk each product's representations, skipping anything that an earlier
+    // product has already reached, so that each solid is assigned to the
+    // first product which uses it
+    let tree = AssemblyTree::new(s);
+    let mut seen = vec![false; s.0.len()];
+    let mut products = Vec::new();
+    let mut breps = Vec::new();
+    for node in tree.iter() {
+        let mut reps: Vec<usize> = node.shapes.iter().map(|r| r.0).collect();
+        for r in node.shapes.iter() {
+            reps.extend(related.get(&r.0).into_iter().flatten());
+        }
+        let ids = closure_into(s, &mut seen, &reps);
+        breps.extend(ids.iter()
+            .filter(|i| is_brep(&s.0[**i]))
+            .map(|b| (*b, node.name)));
+        products.push((node.definition.0, node.name, ids));
+    }
+
+    breps.sort_unstable();
+    for (b, name) in breps {
+        let ids = closure_into(s, &mut owned, &[b]);
+        claim(ids, LocalOwner::Brep(b, Some(name.to_owned())), &mut out);
+    }
+    for (d, name, ids) in products {
+        // Everything below these ids was reached with them, so marking them
+        // keeps `owned` closed under references
+        let ids: Vec<usize> = ids.into_iter().filter(|i| !owned[*i]).collect();
+        for i in &ids {
+            owned[*i] = true;
+        }
+        claim(ids, LocalOwner::Product(name.to_owned()), &mut out);
+        let ids = closure_into(s, &mut owned, &[d]);
+        claim(ids, LocalOwner::Product(name.to_owned()), &mut out);
+    }
+    for node in tree.iter() {
+        if let Some(o) = node.occurrence {
+            let ids = closure_into(s, &mut owned, &[o.0]);
+            claim(ids, LocalOwner::Product(node.name.to_owned()), &mut out);
+        }
+    }
+
+    // Solids which aren't attached to a product
+    for b in 0..s.0.len() {
+        if !owned[b] && is_brep(&s.0[b]) {
+            let ids = closure_into(s, &mut owned, &[b]);
+            claim(ids, LocalOwner::Brep(b, None), &mut out);
+        }
+    }
+    out
+}
+
+////////////////////////////////////////////////////////////////////////////////
+
+pub(crate) fn diff(old: &StepFile, new: &StepFile) -> Diff {
+    let a = Graph::new(old);
+    let b = Graph::new(new);
+
+    // Match up identical sub-graphs, using their deep hashes
+    let mut match_a = vec![None; a.len()];
+    let mut match_b = vec![None; b.len()];
+    let mut buckets: HashMap<u64, Vec<usize>> = HashMap::new();
+    for j in (0..b.len()).rev().filter(|j| b.kind[*j].is_some()) {
+        buckets.entry(b.deep[j]).or_default().push(j);
+    }
+    for i in (0..a.len()).filter(|i| a.kind[*i].is_some()) {
+        if let Some(j) = buckets.get_mut(&a.deep[i]).and_then(|v| v.pop()) {
+            match_a[i] = Some(j);
+            match_b[j] = Some(i);
+        }
+    }
+    let unmatched_a: Vec<bool> = (0..a.len())
+        .map(|i| a.kind[i].is_some() && match_a[i].is_none())
+        .collect();
+    let unmatched_b: Vec<bool> = (0..b.len())
+        .map(|j| b.kind[j].is_some() && match_b[j].is_none())
+        .collect();
+
+    // Pair up the topmost changed entities, preferring pairs whose own
+    // attributes are the same, then any pair of the same type.
+    let mut pair_a = vec![None; a.len()];
+    let mut pair_b = vec![None; b.len()];
+    let mut todo = Vec::new();
+    let seeds_b = b.seeds(&unmatched_b);
+    for key in [true, false].iter() {
+        let mut buckets: HashMap<(Option<u64>, u64), Vec<usize>> = HashMap::new();
+        for j in seeds_b.iter().rev().filter(|j| pair_b[**j].is_none()) {
+            buckets.entry((b.kind[*j], if *key { b.local[*j] } else { 0 }))
+                .or_default()
+                .push(*j);
+        }
+        for i in a.seeds(&unmatched_a) {
+            if pair_a[i].is_some() {
+                continue;
+            }
+            let k = (a.kind[i], if *key { a.local[i] } else { 0 });
+            if let Some(j) = buckets.get_mut(&k).and_then(|v| v.pop()) {
+                pair_a[i] = Some(j);
+                pair_b[j] = Some(i);
+                todo.push((i, j));
+            }
+        }
+    }
+
+    // Then walk down from each pair, pairing changed references which are
+    // in the same position and of the same type
+    while let Some((i, j)) = todo.pop() {
+        for (ci, cj) in a.refs[i].iter().zip(b.refs[j].iter()) {
+            if unmatched_a[*ci] && unmatched_b[*cj] &&
+               pair_a[*ci].is_none() && pair_b[*cj].is_none() &&
+               a.kind[*ci] == b.kind[*cj]
+            {
+                pair_a[*ci] = Some(*cj);
+                pair_b[*cj] = Some(*ci);
+                todo.push((*ci, *cj));
+            }
+        }
+    }
+
+    // A pair is only modified if its own attributes changed or one of its
+    // references now points to an entity which doesn't correspond
+    let modified = |i: usize, j: usize| a.local[i] != b.local[j] ||
+        a.refs[i].len() != b.refs[j].len() ||
+        a.refs[i].iter().zip(b.refs[j].iter())
+            .any(|(ci, cj)| match_a[*ci] != Some(*cj) && pair_a[*ci] != Some(*cj));
+
+    // Group changes by owner, preferring owners from the new file
+    let owners_a = owners(old);
+    let owners_b = owners(new);
+    let owner_b = |j: usize| match &owners_b[j] {
+        Some(LocalOwner::Brep(id, product)) => Owner::Brep {
+            product: product.clone(),
+            old: match_b[*id].or(pair_b[*id]),
+            new: Some(*id),
+        },
+        Some(LocalOwner::Product(name)) => Owner::Product(name.clone()),
+        None => Owner::Unowned,
+    };
+    let owner_a = |i: usize| match &owners_a[i] {
+        Some(LocalOwner::Brep(id, product)) => match match_a[*id].or(pair_a[*id]) {
+            Some(j) => owner_b(j),
+            None => Owner::Brep { product: product.clone(), old: Some(*id), new: None },
+        },
+        Some(LocalOwner::Product(name)) => Owner::Product(name.clone()),
+        None => Owner::Unowned,
+    };
+
+    let mut out = Diff::default();
+    let mut index: HashMap<Owner, usize> = HashMap::new();
+    let mut push = |owner: Owner, c: Change| {
+        let i = *index.entry(owner.clone()).or_insert_with(|| {
+            out.groups.push(DiffGroup { owner, changes: Vec::new() });
+            out.groups.len() - 1
+        });
+        out.groups[i].changes.push(c);
+    };
+    for i in (0..a.len()).filter(|i| unmatched_a[*i] && pair_a[*i].is_none()) {
+        push(owner_a(i), Change::Removed(i));
+    }
+    for j in (0..b.len()).filter(|j| unmatched_b[*j]) {
+        match pair_b[j] {
+            None => push(owner_b(j), Change::Added(j)),
+            Some(i) if modified(i, j) =>
+                push(owner_b(j), Change::Modified { old: i, new: j }),
+            Some(_) => (),
+        }
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Writes a file with every id shuffled, applying `edit` to each line
+    fn renumber<F: Fn(&str) -> String>(s: &StepFile, edit: F) -> Vec<u8> {
+        let mut out = Vec::new();
+        s.write(&mut out).unwrap();
+        let text = String::from_utf8(out).unwrap();
+        let mut lines: Vec<String> = text.lines()
+            .map(|line| if line.starts_with('#') {
+                replace_refs(&edit(line), |i, out| out.push_str(&format!("#{}", 1000 - i)))
+            } else {
+                line.to_owned()
+            })
+            .collect();
+        lines.insert(lines.len() - 2, "#1=CARTESIAN_POINT('extra',(1.,2.,3.));".to_owned());
+        lines.join("\n").into_bytes()
+    }
+
+    #[test]
+    fn test_diff() {
+        let flat = StepFile::strip_flatten(include_bytes!("../../examples/cube_hole.step"));
+        let (a, _) = StepFile::parse(&flat);
+
+        // Pick out a point which is used by a vertex
+        let point = a.0.iter()
+            .find_map(|e| match e {
+                Entity::VertexPoint(v) => Some(v.vertex_geometry.0),
+                _ => None,
+            })
+            .unwrap();
+        let target = format!("#{}=CARTESIAN_POINT(", point);
+
+        let data = renumber(&a, |line| if line.starts_with(&target) {
+            format!("{}'',(1.,2.,3.));", target)
+        } else {
+            line.to_owned()
+        });
+        let flat = StepFile::strip_flatten(&data);
+        let (b, _) = StepFile::parse(&flat);
+
+        let d = a.diff(&b);
+        assert_eq!(d.groups.len(), 2, "{:?}", d);
+        assert_eq!(d.groups[0].owner, Owner::Unowned);
+        assert_eq!(d.groups[0].changes, vec![Change::Added(1)]);
+        assert_eq!(d.groups[1].owner, Owner::Brep {
+            product: Some("Part 1".to_owned()),
+            old: Some(141),
+            new: Some(1000 - 141),
+        });
+        assert_eq!(d.groups[1].changes, vec![
+            Change::Modified { old: point, new: 1000 - point },
+        ]);
+
+        // Renumbering alone isn't a change, and changes are symmetric
+        let data = renumber(&a, |line| line.to_owned());
+        let flat = StepFile::strip_flatten(&data);
+        let (c, _) = StepFile::parse(&flat);
+        let d = c.diff(&a);
+        assert_eq!(d.groups.len(), 1);
+        assert_eq!(d.groups[0].changes, vec![Change::Removed(1)]);
+    }
+
+    #[test]
+    fn test_owners() {
+        let data = b"DATA;\
+            #1=PRODUCT('A','Assembly','',());\
+            #2=PRODUCT_DEFINITION_FORMATION('','',#1);\
+            #3=PRODUCT_DEFINITION('design','',#2,#99);\
+            #4=PRODUCT('B','Bolt','',());\
+            #5=PRODUCT_DEFINITION_FORMATION('','',#4);\
+            #6=PRODUCT_DEFINITION('design','',#5,#99);\
+            #7=PRODUCT_DEFINITION_SHAPE('','',#6);\
+            #8=SHAPE_REPRESENTATION('',(#9),#99);\
+            #9=MANIFOLD_SOLID_BREP('',#10);\
+            #10=CLOSED_SHELL('',());\
+            #11=SHAPE_DEFINITION_REPRESENTATION(#7,#8);\
+            #12=NEXT_ASSEMBLY_USAGE_OCCURRENCE('1','','',#3,#6,$);\
+            #13=NEXT_ASSEMBLY_USAGE_OCCURRENCE('2','','',#3,#6,$);\
+            ENDSEC;";
+        let (s, _) = StepFile::parse(data);
+        assert_eq!(s.assembly().iter().count(), 3);
+
+        // The bolt is used twice, but its solid is only claimed once
+        let owners = owners(&s);
+        let product = |i: usize| match &owners[i] {
+            Some(LocalOwner::Product(name)) => name.clone(),
+            _ => panic!("#{} isn't owned by a product", i),
+        };
+        for i in &[1, 2, 3] {
+            assert_eq!(product(*i), "Assembly");
+        }
+        for i in &[4, 5, 6, 8, 12, 13] {
+            assert_eq!(product(*i), "Bolt");
+        }
+        for i in &[9, 10] {
+            match &owners[*i] {
+                Some(LocalOwner::Brep(9, Some(name))) => assert_eq!(name, "Bolt"),
+                _ => panic!("#{} isn't owned by the solid", i),
+            }
+        }
+    }
+}
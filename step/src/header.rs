@@ -0,0 +1,225 @@
+use std::borrow::Cow;
+use nom::{bytes::complete::tag, error::ErrorKind};
+
+use crate::{
+    ap214,
+    owned::IntoStatic,
+    parse::{IResult, nom_err, param_from_chunks},
+    sections::{Anchor, DataSection, ExternalRef},
+    write::Write,
+};
+
+/// Contents of the `HEADER` section of a STEP file, which is made up of the
+/// `FILE_DESCRIPTION`, `FILE_NAME`, and `FILE_SCHEMA` entities.
+///
+/// This also holds the other non-entity parts of the file: the edition 3
+/// `ANCHOR` and `REFERENCE` sections, and the parameters of each `DATA`
+/// section.
+#[derive(Debug, Default)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct StepHeader<'a> {
+    // FILE_DESCRIPTION
+    pub description: Vec<Cow<'a, str>>,
+    pub implementation_level: Cow<'a, str>,
+
+    // FILE_NAME
+    pub name: Cow<'a, str>,
+    pub time_stamp: Cow<'a, str>,
+    pub author: Vec<Cow<'a, str>>,
+    pub organization: Vec<Cow<'a, str>>,
+    pub preprocessor_version: Cow<'a, str>,
+    pub originating_system: Cow<'a, str>,
+    pub authorization: Cow<'a, str>,
+
+    // FILE_SCHEMA
+    pub schema_identifiers: Vec<Cow<'a, str>>,
+
+    // Edition 3 sections
+    pub anchors: Vec<Anchor<'a>>,
+    pub references: Vec<ExternalRef<'a>>,
+    pub data_sections: Vec<DataSection<'a>>,
+}
+
+/// Application protocols which a file can declare in its `FILE_SCHEMA`
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum Schema {
+    /// AP203, declared as `CONFIG_CONTROL_DESIGN` (or the edition 2 MIM)
+    Ap203,
+    /// AP214, declared as `AUTOMOTIVE_DESIGN`
+    Ap214,
+    /// AP242, declared as `AP242_MANAGED_MODEL_BASED_3D_ENGINEERING_MIM_LF`
+    Ap242,
+}
+
+impl Schema {
+    /// Recognizes a schema from a `FILE_SCHEMA` identifier, which may be
+    /// followed by an object identifier, e.g. `AUTOMOTIVE_DESIGN { 1 0 ... }`
+    pub fn from_identifier(s: &str) -> Option<Self> {
+        let name = s.split(|c: char| c == '{' || c.is_whitespace())
+            .next()
+            .unwrap_or("")
+            .to_ascii_uppercase();
+        match name.as_str() {
+            ap214::SCHEMA_NAME | "AUTOMOTIVE_DESIGN_CC2" => Some(Self::Ap214),
+            "CONFIG_CONTROL_DESIGN" => Some(Self::Ap203),
+            n if n.starts_with("AP203_") => Some(Self::Ap203),
+            n if n.starts_with("AP242_") => Some(Self::Ap242),
+            _ => None,
+        }
+    }
+}
+
+impl<'a> StepHeader<'a> {
+    /// Returns the first recognized schema in the `FILE_SCHEMA` header
+    pub fn schema(&self) -> Option<Schema> {
+        self.schema_identifiers.iter().find_map(|s| Schema::from_identifier(s))
+    }
+
+    /// Parses a single block from the `HEADER` section, storing its values
+    /// in the relevant fields.  Unknown header entities are ignored.
+    pub(crate) fn parse_block(&mut self, s: &'a [u8]) -> IResult<'a, ()> {
+        let s = match std::str::from_utf8(s) {
+            Ok(s) => s,
+            Err(_) => return nom_err("", ErrorKind::Escaped),
+        };
+        if s.starts_with("FILE_DESCRIPTION(") {
+            self.parse_file_description(s)
+        } else if s.starts_with("FILE_NAME(") {
+            self.parse_file_name(s)
+        } else if s.starts_with("FILE_SCHEMA(") {
+            self.parse_file_schema(s)
+        } else {
+            Ok((s, ()))
+        }
+    }
+
+    fn parse_file_description(&mut self, s: &'a str) -> IResult<'a, ()> {
+        let strs = [s];
+        let mut i = 0;
+        let (s, _) = tag("FILE_DESCRIPTION(")(s)?;
+        let (s, description) = param_from_chunks::<Vec<Cow<str>>>(false, s, &mut i, &strs)?;
+        let (s, implementation_level) = param_from_chunks::<Cow<str>>(true, s, &mut i, &strs)?;
+        self.description = description;
+        self.implementation_level = implementation_level;
+        Ok((s, ()))
+    }
+
+    fn parse_file_name(&mut self, s: &'a str) -> IResult<'a, ()> {
+        let strs = [s];
+        let mut i = 0;
+        let (s, _) = tag("FILE_NAME(")(s)?;
+        let (s, name) = param_from_chunks::<Cow<str>>(false, s, &mut i, &strs)?;
+        let (s, time_stamp) = param_from_chunks::<Cow<str>>(false, s, &mut i, &strs)?;
+        let (s, author) = param_from_chunks::<Vec<Cow<str>>>(false, s, &mut i, &strs)?;
+        let (s, organization) = param_from_chunks::<Vec<Cow<str>>>(false, s, &mut i, &strs)?;
+        let (s, preprocessor_version) = param_from_chunks::<Cow<str>>(false, s, &mut i, &strs)?;
+        let (s, originating_system) = param_from_chunks::<Cow<str>>(false, s, &mut i, &strs)?;
+        let (s, authorization) = param_from_chunks::<Cow<str>>(true, s, &mut i, &strs)?;
+        self.name = name;
+        self.time_stamp = time_stamp;
+        self.author = author;
+        self.organization = organization;
+        self.preprocessor_version = preprocessor_version;
+        self.originating_system = originating_system;
+        self.authorization = authorization;
+        Ok((s, ()))
+    }
+
+    fn parse_file_schema(&mut self, s: &'a str) -> IResult<'a, ()> {
+        let strs = [s];
+        let mut i = 0;
+        let (s, _) = tag("FILE_SCHEMA(")(s)?;
+        let (s, schema_identifiers) = param_from_chunks::<Vec<Cow<str>>>(true, s, &mut i, &strs)?;
+        self.schema_identifiers = schema_identifiers;
+        Ok((s, ()))
+    }
+
+    /// Writes the `HEADER` section, including the section delimiters
+    pub(crate) fn write(&self, out: &mut String) {
+        out.push_str("HEADER;\nFILE_DESCRIPTION(");
+        self.description.write(out);
+        out.push(',');
+        self.implementation_level.write(out);
+        out.push_str(");\nFILE_NAME(");
+        self.name.write(out);
+        out.push(',');
+        self.time_stamp.write(out);
+        out.push(',');
+        self.author.write(out);
+        out.push(',');
+        self.organization.write(out);
+        out.push(',');
+        self.preprocessor_version.write(out);
+        out.push(',');
+        self.originating_system.write(out);
+        out.push(',');
+        self.authorization.write(out);
+        out.push_str(");\nFILE_SCHEMA(");
+        self.schema_identifiers.write(out);
+        out.push_str(");\nENDSEC;\n");
+    }
+}
+impl IntoStatic for StepHeader<'_> {
+    type Static = StepHeader<'static>;
+    fn into_static(self) -> Self::Static {
+        StepHeader {
+            description: self.description.into_static(),
+            implementation_level: self.implementation_level.into_static(),
+            name: self.name.into_static(),
+            time_stamp: self.time_stamp.into_static(),
+            author: self.author.into_static(),
+            organization: self.organization.into_static(),
+            preprocessor_version: self.preprocessor_version.into_static(),
+            originating_system: self.originating_system.into_static(),
+            authorization: self.authorization.into_static(),
+            schema_identifiers: self.schema_identifiers.into_static(),
+            anchors: self.anchors.into_static(),
+            references: self.references.into_static(),
+            data_sections: self.data_sections.into_static(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::step_file::StepFile;
+
+    #[test]
+    fn test_parse_header() {
+        let data = include_bytes!("../../examples/cube_hole.step");
+        let flat = StepFile::strip_flatten(data);
+        let (s, _) = StepFile::parse(&flat);
+        let h = &s.1;
+        assert_eq!(h.description, vec!["STEP AP214"]);
+        assert_eq!(h.implementation_level, "2;1");
+        assert_eq!(h.name, "60675d679744da154d7db1ec");
+        assert_eq!(h.time_stamp, "2021-04-02T18:07:35+00:00");
+        assert_eq!(h.author, vec![""]);
+        assert_eq!(h.organization, vec![""]);
+        assert_eq!(h.preprocessor_version, "ST-DEVELOPER v18.1");
+        assert_eq!(h.originating_system, "  ");
+        assert_eq!(h.schema_identifiers, vec!["AUTOMOTIVE_DESIGN {1 0 10303 214 3 1 1}"]);
+        assert_eq!(h.schema(), Some(Schema::Ap214));
+    }
+
+    #[test]
+    fn test_schema() {
+        assert_eq!(Schema::from_identifier("CONFIG_CONTROL_DESIGN"), Some(Schema::Ap203));
+        assert_eq!(Schema::from_identifier("automotive_design"), Some(Schema::Ap214));
+        assert_eq!(Schema::from_identifier(
+            "AP242_MANAGED_MODEL_BASED_3D_ENGINEERING_MIM_LF { 1 0 10303 442 1 1 4 }"),
+            Some(Schema::Ap242));
+        assert_eq!(Schema::from_identifier("IFC2X3"), None);
+
+        // Files in other schemas are still parsed, with the AP214 types
+        let data = b"ISO-10303-21;\nHEADER;\n\
+            FILE_SCHEMA(('BOGUS','CONFIG_CONTROL_DESIGN'));\nENDSEC;\nDATA;\n\
+            #1=CARTESIAN_POINT('',(0.,0.,0.));\nENDSEC;\nEND-ISO-10303-21;\n";
+        let flat = StepFile::strip_flatten(data);
+        let (s, report) = StepFile::parse(&flat);
+        assert_eq!(s.1.schema(), Some(Schema::Ap203));
+        assert!(report.failures.is_empty());
+        assert!(s.0[1].is_instance_of("CARTESIAN_POINT"));
+    }
+}
@@ -1,3 +1,4 @@
+use std::borrow::Cow;
 use arrayvec::ArrayVec;
 
 #[derive(Debug)]
@@ -35,44 +36,76 @@ impl<T> std::hash::Hash for Id<T> {
     }
 }
 
+// References are serialized as bare entity ids
+#[cfg(feature = "serde")]
+impl<T> serde::Serialize for Id<T> {
+    fn serialize<S: serde::Serializer>(&self, s: S) -> Result<S::Ok, S::Error> {
+        s.serialize_u64(self.0 as u64)
+    }
+}
+#[cfg(feature = "serde")]
+impl<'de, T> serde::Deserialize<'de> for Id<T> {
+    fn deserialize<D: serde::Deserializer<'de>>(d: D) -> Result<Self, D::Error> {
+        usize::deserialize(d).map(Id::new)
+    }
+}
+
+/// Receives each reference found by [`HasId::append_ids`], along with the
+/// entity types that the reference was declared to point to
+pub(crate) trait IdVisitor {
+    fn visit(&mut self, id: usize, targets: &'static [&'static str]);
+}
+impl IdVisitor for Vec<usize> {
+    fn visit(&mut self, id: usize, _targets: &'static [&'static str]) {
+        self.push(id)
+    }
+}
+
+/// Implemented for every type `T` which can be used in an `Id<T>`, listing
+/// the entity types (including subtypes) that such an `Id` may refer to.  An
+/// empty list means that any entity type is allowed.
+pub(crate) trait RefTarget {
+    const TARGETS: &'static [&'static str];
+}
+
 pub(crate) trait HasId {
-    fn append_ids(&self, v: &mut Vec<usize>);
+    fn append_ids<V: IdVisitor>(&self, v: &mut V);
 }
-impl<T> HasId for Id<T> {
-    fn append_ids(&self, v: &mut Vec<usize>) {
-        v.push(self.0)
+impl<T: RefTarget> HasId for Id<T> {
+    fn append_ids<V: IdVisitor>(&self, v: &mut V) {
+        v.visit(self.0, T::TARGETS)
     }
 }
 impl<T: HasId> HasId for Vec<T> {
-    fn append_ids(&self, v: &mut Vec<usize>) {
+    fn append_ids<V: IdVisitor>(&self, v: &mut V) {
         for t in self {
             t.append_ids(v);
         }
     }
 }
 impl<T: HasId, const CAP: usize> HasId for ArrayVec<T, CAP> {
-    fn append_ids(&self, v: &mut Vec<usize>) {
+    fn append_ids<V: IdVisitor>(&self, v: &mut V) {
         for t in self {
             t.append_ids(v);
         }
     }
 }
 impl<T: HasId> HasId for Option<T> {
-    fn append_ids(&self, v: &mut Vec<usize>) {
+    fn append_ids<V: IdVisitor>(&self, v: &mut V) {
         if let Some(s) = self {
             s.append_ids(v);
         }
     }
 }
 impl HasId for i64 {
-    fn append_ids(&self, _v: &mut Vec<usize>) { /* Nothing to do here */ }
+    fn append_ids<V: IdVisitor>(&self, _v: &mut V) { /* Nothing to do here */ }
 }
 impl HasId for f64 {
-    fn append_ids(&self, _v: &mut Vec<usize>) { /* Nothing to do here */ }
+    fn append_ids<V: IdVisitor>(&self, _v: &mut V) { /* Nothing to do here */ }
 }
-impl HasId for &str {
-    fn append_ids(&self, _v: &mut Vec<usize>) { /* Nothing to do here */ }
+impl HasId for Cow<'_, str> {
+    fn append_ids<V: IdVisitor>(&self, _v: &mut V) { /* Nothing to do here */ }
 }
 impl HasId for bool {
-    fn append_ids(&self, _v: &mut Vec<usize>) { /* Nothing to do here */ }
+    fn append_ids<V: IdVisitor>(&self, _v: &mut V) { /* Nothing to do here */ }
 }
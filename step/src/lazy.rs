@@ -0,0 +1,289 @@
+use std::collections::HashMap;
+use std::sync::{Mutex, OnceLock};
+use log::warn;
+use memchr::memchr;
+use nom::error::ErrorKind;
+
+#[cfg(feature = "rayon")]
+use rayon::prelude::*;
+
+use crate::{
+    ap214::Entity,
+    header::StepHeader,
+    id::Id,
+    parse::parse_entity_fallback,
+    report::{ParseFailure, ParseReport},
+    step_file::{Entities, FromEntity, StepFile, is_subtype_of},
+};
+
+/// A STEP file which is indexed up front, but only parses each entity when
+/// it's first accessed.
+///
+/// Building the index only finds block boundaries and `#id`s, which is much
+/// cheaper than parsing every entity.  Parsed entities are cached, and the
+/// cache is thread-safe, so a `LazyStepFile` can be shared between threads.
+///
+/// Entities which fail to parse are recorded as they're found; see
+/// [`LazyStepFile::report`].
+pub struct LazyStepFile<'a> {
+    /// Values from the `HEADER` section, which are parsed eagerly
+    pub header: StepHeader<'a>,
+    /// Offset and flattened text of each entity, indexed by `#id` (empty if
+    /// unused)
+    blocks: Vec<(usize, &'a [u8])>,
+    cache: Vec<OnceLock<Entity<'a>>>,
+    failures: Mutex<Vec<ParseFailure>>,
+}
+
+impl<'a> LazyStepFile<'a> {
+    /// Indexes a STEP file, which must be preprocessed by
+    /// [`StepFile::strip_flatten`] first.  As in [`StepFile::parse`], a
+    /// truncated file is indexed up to its last complete entity.
+    pub fn parse(data: &'a [u8]) -> Self {
+        let mut blocks = Vec::new();
+        if let Err(e) = StepFile::into_blocks(data, &mut blocks) {
+            warn!("Ignoring end of file: {}", e);
+        }
+        let (other, sections) = StepFile::split_sections(&blocks);
+        let mut header = StepFile::parse_header(&other);
+
+        let mut index: Vec<(usize, &[u8])> = Vec::new();
+        let mut failures = Vec::new();
+        let openers: Vec<&[u8]> = sections.iter().map(|s| s.0).collect();
+        header.data_sections = StepFile::data_sections(&openers, &[], &[], data.len());
+        for (k, (_, contents)) in sections.iter().enumerate() {
+            for b in contents.iter() {
+                let flat_offset = b.as_ptr() as usize - data.as_ptr() as usize;
+                let failure = |id, kind| ParseFailure {
+                    id, flat_offset, offset: None, line: None, kind, fallback: false,
+                };
+
+                // As in the eager parser, ids beyond the file length are bogus
+                match parse_entity_fallback(b) {
+                    Ok((_, (i, _))) if i > data.len() => {
+                        warn!("Skipping huge id #{}", i);
+                        failures.push(failure(Some(i), ErrorKind::TooLarge));
+                    },
+                    Ok((_, (i, _))) => {
+                        if i >= index.len() {
+                            index.resize(i + 1, (0, &[]));
+                        }
+                        index[i] = (flat_offset, b);
+                        header.data_sections[k].ids.push(i);
+                    },
+                    Err(e) => {
+                        warn!("Skipping block without an id: {}",
+                            std::str::from_utf8(b).unwrap_or("[INVALID UTF-8]"));
+                        let kind = match e {
+                            nom::Err::Error(e) | nom::Err::Failure(e) => e.code,
+                            nom::Err::Incomplete(_) => ErrorKind::Complete,
+                        };
+                        failures.push(failure(None, kind));
+                    },
+                }
+            }
+        }
+        let cache = index.iter().map(|_| OnceLock::new()).collect();
+        Self { header, blocks: index, cache, failures: Mutex::new(failures) }
+    }
+
+    /// Returns the number of entity slots (i.e. one more than the largest id)
+    pub fn len(&self) -> usize {
+        self.blocks.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.blocks.is_empty()
+    }
+
+    /// Returns the entity with the given id, parsing it if necessary.  Unused
+    /// ids return `_EmptySlot`, and ids beyond the end of the file return
+    /// `None`.
+    pub fn get(&self, i: usize) -> Option<&Entity<'a>> {
+        let b = self.blocks.get(i)?;
+        Some(self.cache[i].get_or_init(|| Self::parse_block(*b, &self.failures)))
+    }
+
+    /// Looks up an entity by typed id, as in [`StepFile::entity`]
+    pub fn entity<'s, T: FromEntity<'s>>(&'s self, i: Id<T>) -> Option<&'s T> {
+        T::try_from_entity(self.get(i.0)?)
+    }
+
+    /// Returns the number of entities which have been parsed so far
+    pub fn parsed(&self) -> usize {
+        self.cache.iter().filter(|c| c.get().is_some()).count()
+    }
+
+    /// Returns the failures found so far, which covers blocks that were
+    /// skipped while indexing and entities which failed to parse when they
+    /// were accessed.  Entities which haven't been accessed yet aren't
+    /// checked; use [`LazyStepFile::into_step_file`] for a full report.
+    pub fn report(&self) -> ParseReport {
+        let mut failures = self.failures.lock().unwrap().clone();
+        failures.sort_by_key(|f| f.flat_offset);
+        ParseReport { failures }
+    }
+
+    /// Parses every remaining entity, returning a regular [`StepFile`] and
+    /// a report of every failure, as in [`StepFile::parse`]
+    pub fn into_step_file(self) -> (StepFile<'a>, ParseReport) {
+        let blocks = self.blocks;
+        let failures = self.failures;
+        let iter = {
+            #[cfg(feature = "rayon")]
+            { self.cache.into_par_iter() }
+            #[cfg(not(feature = "rayon"))]
+            { self.cache.into_iter() }
+        };
+        let entities = iter
+            .enumerate()
+            .map(|(i, c)| match c.into_inner() {
+                Some(e) => e,
+                None => Self::parse_block(blocks[i], &failures),
+            })
+            .collect();
+        let mut failures = failures.into_inner().unwrap();
+        failures.sort_by_key(|f| f.flat_offset);
+        (StepFile(entities, self.header), ParseReport { failures })
+    }
+
+    /// Parses a single block, recording it in `failures` if it can't be
+    /// parsed.  Blocks were checked for an id while indexing, so a failed
+    /// block is stored as `_FailedToParse`.
+    fn parse_block((offset, b): (usize, &'a [u8]), failures: &Mutex<Vec<ParseFailure>>)
+        -> Entity<'a>
+    {
+        if b.is_empty() {
+            return Entity::_EmptySlot;
+        }
+        let (_, entity, failure) = StepFile::parse_block(b, offset);
+        if let Some(f) = failure {
+            warn!("Failed to parse #{}: {:?}", f.id.unwrap_or(0), f.kind);
+            failures.lock().unwrap().push(f);
+        }
+        entity.map(|e| e.1).unwrap_or(Entity::_FailedToParse)
+    }
+}
+
+impl<'a> Entities<'a> for LazyStepFile<'a> {
+    fn len(&self) -> usize {
+        self.len()
+    }
+
+    fn get(&self, i: usize) -> Option<&Entity<'a>> {
+        self.get(i)
+    }
+
+    /// Simple entities are matched by the type name in their text, so only
+    /// complex entities are parsed.  An entity which later fails to parse is
+    /// still returned here, and becomes `_FailedToParse` when accessed.
+    fn ids_of_type(&self, name: &str) -> Vec<usize> {
+        let mut cache: HashMap<&str, bool> = HashMap::new();
+        (0..self.blocks.len())
+            .filter(|&i| {
+                let b = self.blocks[i].1;
+                if b.is_empty() {
+                    return false;
+                }
+                match type_name(b) {
+                    Some(t) => *cache.entry(t)
+                        .or_insert_with(|| is_subtype_of(t, name)),
+                    None => self.get(i).is_some_and(|e| e.is_instance_of(name)),
+                }
+            })
+            .collect()
+    }
+}
+
+impl<'a, T> std::ops::Index<Id<T>> for LazyStepFile<'a> {
+    type Output = Entity<'a>;
+
+    fn index(&self, id: Id<T>) -> &Self::Output {
+        self.by_id(id)
+    }
+}
+
+/// Reads the type name from the text of a simple entity (`#12=NAME(...)`),
+/// returning `None` for a complex entity
+fn type_name(b: &[u8]) -> Option<&str> {
+    let b = &b[memchr(b'=', b)? + 1..];
+    let name = std::str::from_utf8(&b[..memchr(b'(', b)?]).ok()?.trim();
+    if name.is_empty() {
+        None
+    } else {
+        Some(name)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ap214::ManifoldSolidBrep_;
+
+    #[test]
+    fn test_lazy() {
+        let flat = StepFile::strip_flatten(include_bytes!("../../examples/cube_hole.step"));
+        let (eager, _) = StepFile::parse(&flat);
+        let lazy = LazyStepFile::parse(&flat);
+        assert_eq!(lazy.header.name, eager.1.name);
+        assert_eq!(lazy.len(), eager.0.len());
+        assert_eq!(lazy.parsed(), 0);
+
+        // Only the entities which we look at are parsed
+        let brep = eager.0.iter()
+            .position(|e| matches!(e, Entity::ManifoldSolidBrep(_)))
+            .unwrap();
+        let b = lazy.entity(Id::<ManifoldSolidBrep_>::new(brep)).unwrap();
+        assert_eq!(lazy.parsed(), 1);
+        assert!(matches!(lazy[b.outer], Entity::ClosedShell(_)));
+        assert_eq!(lazy.parsed(), 2);
+        assert!(lazy.get(lazy.len()).is_none());
+
+        // Entities can be parsed from multiple threads at once
+        std::thread::scope(|s| {
+            for t in 0..4 {
+                let lazy = &lazy;
+                s.spawn(move || {
+                    for i in (t..lazy.len()).step_by(4) {
+                        let _ = lazy.get(i);
+                    }
+                });
+            }
+        });
+        assert_eq!(lazy.parsed(), lazy.len());
+
+        let (mut a, mut b) = (Vec::new(), Vec::new());
+        eager.write(&mut a).unwrap();
+        let (s, report) = lazy.into_step_file();
+        assert!(report.failures.is_empty());
+        s.write(&mut b).unwrap();
+        assert!(a == b);
+    }
+
+    #[test]
+    fn test_lazy_report() {
+        let data = b"ISO-10303-21;\nHEADER;\nENDSEC;\nDATA;\n\
+            #1=CARTESIAN_POINT('',(0.,0.,0.));\n\
+            #2=CARTESIAN_POINT('',(0.,0.,\n    0.,NOPE));\n\
+            #3=DIRECTION('',(1.,0.,0.));\n\
+            BOGUS;\n\
+            ENDSEC;\nEND-ISO-10303-21;\n";
+        let flat = StepFile::strip_flatten(data);
+        let (_, eager) = StepFile::parse(&flat);
+        let lazy = LazyStepFile::parse(&flat);
+
+        // The block without an id is reported while indexing, and #2 once
+        // it's been parsed
+        assert_eq!(lazy.report().failures.len(), 1);
+        assert_eq!(lazy.report().failures[0].id, None);
+        assert!(matches!(lazy.get(2), Some(Entity::_FailedToParse)));
+        let mut report = lazy.report();
+        assert_eq!(report.failures, eager.failures);
+
+        report.locate(data);
+        assert_eq!(report.failures[0].line, Some(6));
+        let (s, report) = lazy.into_step_file();
+        assert_eq!(report.failures, eager.failures);
+        assert!(matches!(s.0[3], Entity::Direction(_)));
+    }
+}
@@ -2,3 +2,58 @@ pub mod parse;
 pub mod step_file;
 pub mod ap214; // autogenerated!
 pub mod id;
+pub mod header;
+pub mod report;
+pub mod referrers;
+pub mod validate;
+pub mod assembly;
+pub mod units;
+pub mod merge;
+pub mod diff;
+pub mod stream;
+pub mod lazy;
+pub mod owned;
+pub mod presentation;
+pub mod properties;
+pub mod pmi;
+pub mod sections;
+mod decompress;
+mod subset;
+mod write;
+mod xml;
+
+/// Errors returned by [`step_file::StepFile::try_parse`].  Byte offsets are
+/// within the flattened data (see [`report::ParseReport::locate`] to map
+/// them back into the original file).
+#[derive(thiserror::Error, Debug, Eq, PartialEq)]
+pub enum Error {
+    #[error("File ended unexpectedly at byte {0}")]
+    Truncated(usize),
+
+    #[error("Missing DATA section")]
+    MissingData,
+
+    #[error("Unterminated string literal starting at byte {0}")]
+    UnterminatedString(usize),
+
+    #[error("Unbalanced parentheses in entity starting at byte {0}")]
+    UnbalancedParentheses(usize),
+
+    #[error("Entity id #{0} is too large for the file size")]
+    IdTooLarge(usize),
+
+    #[error("Could not decompress input: {0}")]
+    Decompress(String),
+
+    #[error("Zip archive must contain exactly one file, but has {0}")]
+    ZipEntries(usize),
+
+    #[error("Invalid STEP-XML: {0}")]
+    Xml(String),
+
+    #[error("Could not load referenced file {0:?}: {1}")]
+    Load(String, String),
+
+    #[error("Unknown anchor <{0}>")]
+    UnknownAnchor(String),
+}
@@ -0,0 +1,311 @@
+use log::warn;
+
+use crate::{
+    assembly::{mul, placement, AssemblyTree, Transform, IDENTITY},
+    header::StepHeader,
+    id::Id,
+    step_file::StepFile,
+    write::Write,
+};
+
+/// A file to be merged by [`StepFile::merge`], along with its placement
+pub struct MergeChild<'s, 'a> {
+    pub file: &'s StepFile<'a>,
+    /// Placement of the file's root products within the new assembly, which
+    /// must be a rotation and translation (in millimetres)
+    pub placement: Transform,
+}
+
+/// Accumulates new entities, handing out ids as they're written
+struct Out {
+    buf: String,
+    next: usize,
+}
+
+impl Out {
+    fn push(&mut self, decl: &str) -> usize {
+        let i = self.next;
+        self.buf.push_str(&format!("#{}={};\n", i, decl));
+        self.next += 1;
+        i
+    }
+
+    fn point(&mut self, p: [f64; 3], kind: &str) -> usize {
+        let mut s = format!("{}('',(", kind);
+        for (i, v) in p.iter().enumerate() {
+            if i > 0 {
+                s.push(',');
+            }
+            v.write(&mut s);
+        }
+        s.push_str("))");
+        self.push(&s)
+    }
+
+    /// Writes an `AXIS2_PLACEMENT_3D` for the frame of a rigid transform
+    fn placement(&mut self, t: &Transform) -> usize {
+        let column = |j: usize| [t[0][j], t[1][j], t[2][j]];
+        let location = self.point(column(3), "CARTESIAN_POINT");
+        let axis = self.point(column(2), "DIRECTION");
+        let ref_direction = self.point(column(0), "DIRECTION");
+        self.push(&format!("AXIS2_PLACEMENT_3D('',#{},#{},#{})",
+                           location, axis, ref_direction))
+    }
+}
+
+fn quoted(s: &str) -> String {
+    let mut out = String::new();
+    s.write(&mut out);
+    out
+}
+
+/// Finds an `AXIS2_PLACEMENT_3D` among the items of a shape representation,
+/// returning its id and the transform from its local frame (in millimetres)
+fn frame(s: &StepFile, shape: usize) -> Option<(usize, Transform)> {
+    let (i, mut t) = s.0.get(shape)?.upstream().into_iter()
+        .find_map(|i| placement(s, i).map(|t| (i, t)))?;
+
+    // The placement is in the child's length units, but the new parent's
+    // context is in millimetres
+    let k = s.units(Id::new(shape)).length / 1e-3;
+    for row in t.iter_mut().take(3) {
+        row[3] *= k;
+    }
+    Some((i, t))
+}
+
+/// Builds the text of a merged file; see [`StepFile::merge`]
+pub(crate) fn merge(name: &str, children: &[MergeChild]) -> Vec<u8> {
+    let header = StepHeader {
+        description: vec![name.into()],
+        implementation_level: "2;1".into(),
+        name: name.into(),
+        author: vec!["".into()],
+        organization: vec!["".into()],
+        schema_identifiers: match children.first() {
+            Some(c) => c.file.1.schema_identifiers.clone(),
+            None => vec!["AUTOMOTIVE_DESIGN { 1 0 10303 214 1 1 1 1 }".into()],
+        },
+        ..Default::default()
+    };
+    if children.iter().any(|c| c.file.1.schema_identifiers != header.schema_identifiers) {
+        warn!("Merging files with different schemas");
+    }
+
+    let mut out = "ISO-10303-21;\n".to_owned();
+    header.write(&mut out);
+    out.push_str("DATA;\n");
+    let mut out = out.into_bytes();
+
+    // Copy each child's entities, shifting their ids past the previous file
+    let mut offset = 0;
+    let mut roots = Vec::new();
+    for c in children {
+        let map: Vec<usize> = (0..c.file.0.len()).map(|i| i + offset).collect();
+        c.file.write_data(1..c.file.0.len(), Some(&map), &mut out)
+            .expect("Could not write to Vec");
+
+        let tree = AssemblyTree::new(c.file);
+        if tree.roots.is_empty() {
+            warn!("Merged file has no products, so it won't be placed");
+        }
+        for r in tree.roots.iter() {
+            let shape = r.shapes.first().map(|s| {
+                let frame = frame(c.file, s.0).map(|(i, t)| (i + offset, t));
+                (s.0 + offset, frame)
+            });
+            roots.push((r.definition.0 + offset, shape, &c.placement));
+        }
+        offset += c.file.0.len();
+    }
+
+    // Build the top-level product, with its own shape representation
+    let mut o = Out { buf: String::new(), next: offset.max(1) };
+    let app = o.push("APPLICATION_CONTEXT('configuration controlled 3d designs of mechanical parts and assemblies')");
+    let product_context = o.push(&format!("PRODUCT_CONTEXT('',#{},'mechanical')", app));
+    let product = o.push(&format!("PRODUCT({},{},'',(#{}))",
+                                  quoted(name), quoted(name), product_context));
+    let formation = o.push(&format!("PRODUCT_DEFINITION_FORMATION('','',#{})", product));
+    let definition_context = o.push(&format!(
+        "PRODUCT_DEFINITION_CONTEXT('part definition',#{},'design')", app));
+    let definition = o.push(&format!("PRODUCT_DEFINITION('design','',#{},#{})",
+                                     formation, definition_context));
+    let shape = o.push(&format!("PRODUCT_DEFINITION_SHAPE('','',#{})", definition));
+
+    let mm = o.push("(LENGTH_UNIT()NAMED_UNIT(*)SI_UNIT(.MILLI.,.METRE.))");
+    let rad = o.push("(NAMED_UNIT(*)PLANE_ANGLE_UNIT()SI_UNIT($,.RADIAN.))");
+    let sr = o.push("(NAMED_UNIT(*)SI_UNIT($,.STERADIAN.)SOLID_ANGLE_UNIT())");
+    let context = o.push(&format!(
+        "(GEOMETRIC_REPRESENTATION_CONTEXT(3)GLOBAL_UNIT_ASSIGNED_CONTEXT((#{},#{},#{}))REPRESENTATION_CONTEXT('',''))",
+        mm, rad, sr));
+    let origin = o.placement(&IDENTITY);
+
+    // Place each root of each child under the new product
+    let mut items = vec![origin];
+    let mut relationships = Vec::new();
+    for (i, (child, shape, placement)) in roots.into_iter().enumerate() {
+        let nauo = o.push(&format!(
+            "NEXT_ASSEMBLY_USAGE_OCCURRENCE('{}','','',#{},#{},$)",
+            i + 1, definition, child));
+        let (child_shape, frame) = match shape {
+            Some(s) => s,
+            None => {
+                warn!("Merged product #{} has no shape", child);
+                continue;
+            },
+        };
+
+        // transform_item_1 belongs to the child's shape (rep_1), and
+        // transform_item_2 is the same frame as placed in the new parent
+        let (item, frame) = frame.unwrap_or_else(|| {
+            warn!("Merged shape #{} has no AXIS2_PLACEMENT_3D, so it's placed \
+                   from the new product's origin", child_shape);
+            (origin, IDENTITY)
+        });
+        let axes = o.placement(&mul(placement, &frame));
+        items.push(axes);
+        let transform = o.push(&format!(
+            "ITEM_DEFINED_TRANSFORMATION('','',#{},#{})", item, axes));
+        relationships.push((nauo, child_shape, transform));
+    }
+
+    let items: Vec<String> = items.iter().map(|i| format!("#{}", i)).collect();
+    let rep = o.push(&format!("SHAPE_REPRESENTATION('',({}),#{})",
+                              items.join(","), context));
+    o.push(&format!("SHAPE_DEFINITION_REPRESENTATION(#{},#{})", shape, rep));
+    for (nauo, child_shape, transform) in relationships {
+        let rel = o.push(&format!(
+            "(REPRESENTATION_RELATIONSHIP('','',#{},#{})REPRESENTATION_RELATIONSHIP_WITH_TRANSFORMATION(#{})SHAPE_REPRESENTATION_RELATIONSHIP())",
+            child_shape, rep, transform));
+        let pds = o.push(&format!(
+            "PRODUCT_DEFINITION_SHAPE('Placement','Placement of an item',#{})", nauo));
+        o.push(&format!("CONTEXT_DEPENDENT_SHAPE_REPRESENTATION(#{},#{})", rel, pds));
+    }
+
+    out.extend_from_slice(o.buf.as_bytes());
+    out.extend_from_slice(b"ENDSEC;\nEND-ISO-10303-21;\n");
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        ap214::{
+            Entity, ItemDefinedTransformation_, ShapeRepresentationRelationship_,
+            RepresentationRelationshipWithTransformation_,
+        },
+        step_file::FromEntity,
+    };
+
+    #[test]
+    fn test_merge() {
+        let flat = StepFile::strip_flatten(include_bytes!("../../examples/cube_hole.step"));
+        let (cube, _) = StepFile::parse(&flat);
+        let flat = StepFile::strip_flatten(include_bytes!("../../examples/abstract_pca.step"));
+        let (pca, _) = StepFile::parse(&flat);
+
+        let mut shifted = IDENTITY;
+        shifted[0][3] = 100.0;
+        let rotated = [
+            [0.0, -1.0, 0.0, 0.0],
+            [1.0, 0.0, 0.0, 50.0],
+            [0.0, 0.0, 1.0, 0.0],
+            [0.0, 0.0, 0.0, 1.0],
+        ];
+        let files = [
+            MergeChild { file: &cube, placement: IDENTITY },
+            MergeChild { file: &cube, placement: shifted },
+            MergeChild { file: &pca, placement: rotated },
+        ];
+        let (s, report) = StepFile::merge("Machine", &files);
+        assert!(report.failures.is_empty(), "{:?}", report);
+        assert!(s.validate().is_ok(), "{:?}", s.validate());
+        assert_eq!(s.1.name, "Machine");
+
+        let tree = s.assembly();
+        assert_eq!(tree.roots.len(), 1);
+        let root = &tree.roots[0];
+        assert_eq!(root.name, "Machine");
+        let children: Vec<_> = root.children.iter().map(|c| c.name).collect();
+        assert_eq!(children[1], children[0]);
+        assert_eq!(children[2], "PCB");
+        assert_eq!(root.children[1].transform, shifted);
+        assert_eq!(root.children[2].transform, rotated);
+
+        // The same product (from different files) is counted separately
+        assert_eq!(tree.quantities().len(), 3 + pca.assembly().quantities().len());
+
+        // Each transformation goes from an item of the child's shape to an
+        // item of the new parent shape, matching rep_1 and rep_2
+        let parent = root.shapes[0].0;
+        let mut n = 0;
+        for e in &s.0 {
+            let r = match e {
+                Entity::ComplexEntity(v) => match v.iter()
+                    .find_map(RepresentationRelationshipWithTransformation_::try_from_entity)
+                {
+                    Some(r) => (r, v.iter().find_map(ShapeRepresentationRelationship_::try_from_entity)),
+                    None => continue,
+                },
+                _ => continue,
+            };
+            let (rwt, rr) = (r.0, r.1.unwrap());
+            let t = ItemDefinedTransformation_::try_from_entity(
+                &s.0[rwt.transformation_operator.0]).unwrap();
+            assert!(s.0[rr.rep_1.0].upstream().contains(&t.transform_item_1.0));
+            assert!(s.0[rr.rep_2.0].upstream().contains(&t.transform_item_2.0));
+            if rr.rep_2.0 == parent {
+                n += 1;
+            }
+        }
+        assert_eq!(n, 3);
+
+        let text = StepFile::merge_text("Machine", &files);
+        let flat = StepFile::strip_flatten(&text);
+        let (t, _) = StepFile::try_parse(&flat).unwrap();
+        assert_eq!(t.0.len(), s.0.len());
+    }
+
+    #[test]
+    fn test_merge_units() {
+        // A part in inches, whose shape is placed by a frame at (1, 2, 3)
+        let data = b"DATA;\
+            #1=PRODUCT('B','Bolt','',());\
+            #2=PRODUCT_DEFINITION_FORMATION('','',#1);\
+            #3=PRODUCT_DEFINITION('design','',#2,#20);\
+            #4=PRODUCT_DEFINITION_SHAPE('','',#3);\
+            #5=SHAPE_REPRESENTATION('',(#6),#10);\
+            #6=AXIS2_PLACEMENT_3D('',#7,$,$);\
+            #7=CARTESIAN_POINT('',(1.,2.,3.));\
+            #8=SHAPE_DEFINITION_REPRESENTATION(#4,#5);\
+            #10=(GEOMETRIC_REPRESENTATION_CONTEXT(3)GLOBAL_UNIT_ASSIGNED_CONTEXT((#12))REPRESENTATION_CONTEXT('',''));\
+            #11=(LENGTH_UNIT()NAMED_UNIT(*)SI_UNIT(.MILLI.,.METRE.));\
+            #12=(CONVERSION_BASED_UNIT('INCH',#13)LENGTH_UNIT()NAMED_UNIT(#14));\
+            #13=LENGTH_MEASURE_WITH_UNIT(LENGTH_MEASURE(25.4),#11);\
+            #14=DIMENSIONAL_EXPONENTS(1.,0.,0.,0.,0.,0.,0.);\
+            #20=PRODUCT_DEFINITION_CONTEXT('part definition',#21,'design');\
+            #21=APPLICATION_CONTEXT('');\
+            ENDSEC;";
+        let (bolt, _) = StepFile::parse(data);
+        let mut shifted = IDENTITY;
+        shifted[0][3] = 100.0;
+        let (s, report) = StepFile::merge("Machine", &[
+            MergeChild { file: &bolt, placement: shifted },
+        ]);
+        assert!(report.failures.is_empty(), "{:?}", report);
+
+        // transform_item_1 stays in inches, while transform_item_2 is the
+        // same frame in millimetres, moved by the placement
+        let t = s.0.iter()
+            .find_map(ItemDefinedTransformation_::try_from_entity)
+            .unwrap();
+        let item_1 = placement(&s, t.transform_item_1.0).unwrap();
+        assert_eq!([item_1[0][3], item_1[1][3], item_1[2][3]], [1.0, 2.0, 3.0]);
+        let item_2 = placement(&s, t.transform_item_2.0).unwrap();
+        let expected = [125.4, 50.8, 76.2];
+        for (i, v) in expected.iter().enumerate() {
+            assert!((item_2[i][3] - v).abs() < 1e-9, "{:?}", item_2);
+        }
+    }
+}
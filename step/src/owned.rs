@@ -0,0 +1,96 @@
+use std::borrow::Cow;
+use arrayvec::ArrayVec;
+
+use crate::{
+    id::Id,
+    parse::Logical,
+    step_file::StepFile,
+};
+
+/// A parsed file which owns all of its data, so it can be stored in
+/// long-lived structures or sent to other threads.  Build one with
+/// [`StepFile::into_owned`] or [`StepFile::from_reader`].
+pub type OwnedStepFile = StepFile<'static>;
+
+/// Converts a parsed value into one which doesn't borrow from the flattened
+/// input, by copying any borrowed strings.  This is implemented for every
+/// generated type, so that entities can outlive the buffer they came from.
+///
+/// Only strings are borrowed, so this is cheap for most entities.
+pub trait IntoStatic {
+    type Static: 'static;
+    fn into_static(self) -> Self::Static;
+}
+
+impl<T: IntoStatic> IntoStatic for Id<T> {
+    type Static = Id<T::Static>;
+    fn into_static(self) -> Self::Static {
+        self.cast()
+    }
+}
+impl<T: IntoStatic> IntoStatic for Vec<T> {
+    type Static = Vec<T::Static>;
+    fn into_static(self) -> Self::Static {
+        self.into_iter().map(IntoStatic::into_static).collect()
+    }
+}
+impl<T: IntoStatic, const CAP: usize> IntoStatic for ArrayVec<T, CAP> {
+    type Static = ArrayVec<T::Static, CAP>;
+    fn into_static(self) -> Self::Static {
+        self.into_iter().map(IntoStatic::into_static).collect()
+    }
+}
+impl<T: IntoStatic> IntoStatic for Option<T> {
+    type Static = Option<T::Static>;
+    fn into_static(self) -> Self::Static {
+        self.map(IntoStatic::into_static)
+    }
+}
+impl IntoStatic for Cow<'_, str> {
+    type Static = Cow<'static, str>;
+    fn into_static(self) -> Self::Static {
+        Cow::Owned(self.into_owned())
+    }
+}
+
+macro_rules! impl_into_static_copy {
+    ($($t:ty),*) => {
+        $(impl IntoStatic for $t {
+            type Static = $t;
+            fn into_static(self) -> Self::Static {
+                self
+            }
+        })*
+    };
+}
+impl_into_static_copy!(usize, i64, f64, bool, Logical);
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_owned() {
+        fn assert_send_sync<T: Send + Sync + 'static>(_t: &T) {}
+
+        let flat = StepFile::strip_flatten(include_bytes!("../../examples/cube_hole.step"));
+        let (s, _) = StepFile::parse(&flat);
+        let mut expected = Vec::new();
+        s.write(&mut expected).unwrap();
+
+        // The owned file outlives its buffer and can move between threads
+        let owned: OwnedStepFile = s.into_owned();
+        drop(flat);
+        assert_send_sync(&owned);
+
+        let (tx, rx) = std::sync::mpsc::channel();
+        tx.send(owned).unwrap();
+        let out = std::thread::spawn(move || {
+            let owned = rx.recv().unwrap();
+            let mut out = Vec::new();
+            owned.write(&mut out).unwrap();
+            out
+        }).join().unwrap();
+        assert!(out == expected);
+    }
+}
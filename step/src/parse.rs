@@ -1,7 +1,8 @@
+use std::borrow::Cow;
 use std::collections::{HashSet, HashMap};
 use nom::{
     branch::{alt},
-    bytes::complete::{is_not, tag},
+    bytes::complete::tag,
     character::complete::{char, digit1},
     combinator::{map, map_res, opt},
     error::*,
@@ -9,16 +10,17 @@ use nom::{
     multi::{separated_list0},
 };
 use memchr::{memchr, memchr3};
+use log::warn;
 use arrayvec::ArrayVec;
 
-use crate::{id::{Id, HasId}, ap214::{Entity, superclasses_of}};
+use crate::{id::{Id, HasId, IdVisitor}, ap214::{Entity, superclasses_of}};
 
 ////////////////////////////////////////////////////////////////////////////////
 
 pub type IResult<'a, U> = nom::IResult<&'a str, U, Error<&'a str>>;
 
 /// Helper function to generate a `nom` error result
-fn nom_err<'a, U>(s: &'a str, kind: nom::error::ErrorKind) -> IResult<'a, U> {
+pub(crate) fn nom_err<'a, U>(s: &'a str, kind: nom::error::ErrorKind) -> IResult<'a, U> {
     Err(nom::Err::Error(Error::new(s, kind)))
 }
 
@@ -28,10 +30,11 @@ pub fn nom_alt_err<'a, U>(s: &'a str) -> IResult<'a, U> {
 }
 
 #[derive(Debug, Copy, Clone, Eq, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct Logical(pub Option<bool>);
 
 impl HasId for Logical {
-    fn append_ids(&self, _v: &mut Vec<usize>) { /* Nothing to do here */ }
+    fn append_ids<V: IdVisitor>(&self, _v: &mut V) { /* Nothing to do here */ }
 }
 
 ////////////////////////////////////////////////////////////////////////////////
@@ -62,14 +65,111 @@ impl Parse<'_> for i64 {
             })(s)
     }
 }
-impl<'a> Parse<'a> for &'a str {
-    fn parse(s: &'a str) -> IResult<'a, &'a str> {
+impl<'a> Parse<'a> for Cow<'a, str> {
+    fn parse(s: &'a str) -> IResult<'a, Self> {
         alt((
-            map(delimited(char('\''), opt(is_not("'")), char('\'')),
-                |r| r.unwrap_or("")),
+            map(string_literal, decode_string),
             // NUL REF
-            map(char('$'), |_| "")))(s)
+            map(char('$'), |_| Cow::Borrowed(""))))(s)
+    }
+}
+
+/// Matches a quoted string literal, returning its contents (which may still
+/// contain escapes).  Apostrophes within the string are doubled.
+fn string_literal(s: &str) -> IResult<'_, &str> {
+    let (s, _) = char('\'')(s)?;
+    let b = s.as_bytes();
+    let mut i = 0;
+    loop {
+        match memchr(b'\'', &b[i..]) {
+            Some(j) if b.get(i + j + 1) == Some(&b'\'') => i += j + 2,
+            Some(j) => return Ok((&s[i + j + 1..], &s[..i + j])),
+            None => return nom_err(s, ErrorKind::Char),
+        }
+    }
+}
+
+/// Decodes the contents of a string literal, handling doubled apostrophes
+/// and backslashes, plus the `\S\`, `\P?\`, `\X\`, `\X2\` and `\X4\` control
+/// directives.  Strings without escapes are borrowed rather than copied.
+///
+/// Only the ISO 8859-1 code page is supported for `\S\`; other `\P?\` pages
+/// are decoded as if they were ISO 8859-1 (with a warning).  Malformed
+/// directives are kept verbatim.
+pub(crate) fn decode_string(s: &str) -> Cow<'_, str> {
+    if !s.contains(['\'', '\\']) {
+        return Cow::Borrowed(s);
     }
+    let b = s.as_bytes();
+    let mut out = String::with_capacity(s.len());
+    let mut i = 0;
+    while i < b.len() {
+        let rest = &b[i..];
+        if rest.starts_with(b"''") || rest.starts_with(b"\\\\") {
+            out.push(b[i] as char);
+            i += 2;
+        } else if rest.starts_with(b"\\X\\") && rest.len() >= 5 && is_hex(&rest[3..5]) {
+            out.push(hex(&rest[3..5]) as u8 as char);
+            i += 5;
+        } else if rest.starts_with(b"\\S\\") && rest.len() >= 4 && rest[3].is_ascii() {
+            out.push((rest[3] + 0x80) as char);
+            i += 4;
+        } else if rest.starts_with(b"\\P") && rest.len() >= 4 &&
+            rest[2].is_ascii_uppercase() && rest[3] == b'\\'
+        {
+            if rest[2] != b'A' {
+                warn!("Unsupported code page ISO 8859-{}, using ISO 8859-1",
+                      rest[2] - b'A' + 1);
+            }
+            i += 4;
+        } else if let Some((n, cs)) = decode_extended(rest) {
+            out.extend(cs);
+            i += n;
+        } else {
+            // Copy a single (possibly multi-byte) character
+            let c = s[i..].chars().next().unwrap();
+            out.push(c);
+            i += c.len_utf8();
+        }
+    }
+    Cow::Owned(out)
+}
+
+fn is_hex(b: &[u8]) -> bool {
+    b.iter().all(|c| c.is_ascii_hexdigit())
+}
+
+/// Converts a slice of ASCII hex digits into a number
+fn hex(b: &[u8]) -> u32 {
+    b.iter().fold(0, |acc, c| acc * 16 + (*c as char).to_digit(16).unwrap())
+}
+
+/// Decodes a `\X2\...\X0\` (UCS-2) or `\X4\...\X0\` (UCS-4) directive at the
+/// start of `b`, returning the number of bytes consumed and decoded chars.
+fn decode_extended(b: &[u8]) -> Option<(usize, Vec<char>)> {
+    let width = if b.starts_with(b"\\X2\\") {
+        4
+    } else if b.starts_with(b"\\X4\\") {
+        8
+    } else {
+        return None;
+    };
+    let end = 4 + b[4..].windows(4).position(|w| w == b"\\X0\\")?;
+    let digits = &b[4..end];
+    let chunks = digits.chunks_exact(width);
+    if digits.is_empty() || !chunks.remainder().is_empty() || !is_hex(digits) {
+        return None;
+    }
+    let codes = chunks.map(hex);
+    let chars = if width == 4 {
+        // Surrogate pairs aren't strictly UCS-2, but show up in the wild
+        std::char::decode_utf16(codes.map(|c| c as u16))
+            .collect::<Result<Vec<_>, _>>()
+            .ok()?
+    } else {
+        codes.map(std::char::from_u32).collect::<Option<Vec<_>>>()?
+    };
+    Some((end + 4, chars))
 }
 
 impl<'a, T: Parse<'a>> Parse<'a> for Vec<T> {
@@ -87,7 +187,9 @@ impl<'a, T: Parse<'a>, const CAP: usize> Parse<'a> for ArrayVec<T, CAP> {
             e => e?,
         };
         s = s_;
-        out.push(o);
+        if out.try_push(o).is_err() {
+            return nom_err(s, ErrorKind::TooLarge);
+        }
 
         loop {
             let (s_, _) = match char(',')(s) {
@@ -100,7 +202,9 @@ impl<'a, T: Parse<'a>, const CAP: usize> Parse<'a> for ArrayVec<T, CAP> {
                 e => e?,
             };
             s = s_;
-            out.push(o);
+            if out.try_push(o).is_err() {
+                return nom_err(s, ErrorKind::TooLarge);
+            }
         }
         let (s, _) = char(')')(s)?;
         Ok((s, out))
@@ -118,6 +222,7 @@ impl<'a> Parse<'a> for Logical {
         alt((
             map(tag(".T."), |_| Logical(Some(true))),
             map(tag(".F."), |_| Logical(Some(false))),
+            map(tag(".U."), |_| Logical(None)),
             map(tag(".UNKNOWN."), |_| Logical(None)),
         ))(s)
     }
@@ -155,11 +260,14 @@ impl<'a, T: ParseFromChunks<'a>> Parse<'a> for T {
 }
 
 // Simple struct so we can use param_from_chunks::<Derived> to parse a '*'
-// optionally followed by a comma
+// optionally followed by a comma.  In complex entities, the supertype's
+// partial entity often carries the value anyways (e.g. `NAMED_UNIT(#5)` next
+// to a `CONVERSION_BASED_UNIT`), so we also accept and ignore a reference.
 pub struct Derived;
 impl<'a> Parse<'a> for Derived {
     fn parse(s: &str) -> IResult<Self> {
-        map(char('*'), |_| Derived)(s)
+        alt((map(char('*'), |_| Derived),
+             map(Id::<()>::parse, |_| Derived)))(s)
     }
 }
 
@@ -218,6 +326,24 @@ pub(crate) fn parse_entity_fallback(s: &[u8]) -> IResult<(usize, Entity)> {
     map(Id::<()>::parse, |i| (i.0, Entity::_FailedToParse))(s)
 }
 
+/// Returns every supertype of an entity, followed by the entity itself, in
+/// the order that the generated structs store their attributes: supertypes
+/// are visited depth-first in declaration order, and a common ancestor (from
+/// multiple inheritance) is only included once.
+pub(crate) fn supertype_chain(name: &str) -> Vec<&str> {
+    fn recurse<'a>(name: &'a str, out: &mut Vec<&'a str>) {
+        for sup in superclasses_of(name) {
+            if !out.contains(sup) {
+                recurse(sup, out);
+            }
+        }
+        out.push(name);
+    }
+    let mut out = Vec::new();
+    recurse(name, &mut out);
+    out
+}
+
 pub(crate) fn parse_complex_mapping(s: &str) -> IResult<Entity> {
     // We'll maintain a map from sub-entity name to its argument string, then
     // use this map to figure out the tree and construct it.
@@ -262,7 +388,8 @@ pub(crate) fn parse_complex_mapping(s: &str) -> IResult<Entity> {
                 }
             },
             b'\'' => {
-                // TODO: handle escaped quotes
+                // Doubled apostrophes look like two adjacent strings, so
+                // they don't need special handling here
                 let j = match memchr(b'\'', &bstr[(index + next + 1)..]) {
                     Some(j) => j,
                     None => return nom_err(s, ErrorKind::Char),
@@ -283,38 +410,79 @@ pub(crate) fn parse_complex_mapping(s: &str) -> IResult<Entity> {
             potential_leafs.remove(sup);
         }
     }
-    // Eliminate any leaf with no arguments, since they're just addding
-    // bonus constraints (which we don't handle anyways)
-    potential_leafs.retain(|k| subentities[k] != "");
+
+    // We'll build up argument strings for each leaf by splicing together bits
+    // of arguments from the existing string (to make lifetimes happy), then
+    // parse them into leaf entities.
+    let build = |leaf: &str| -> IResult<Entity> {
+        let mut new_decl: Vec<&str> = vec![name_tags.get(leaf).unwrap()];
+        for c in supertype_chain(leaf) {
+            // Every class in the chain must be present in the mapping
+            let args = match subentities.get(c) {
+                Some(args) => *args,
+                None => return nom_err(s, ErrorKind::Verify),
+            };
+            if !args.is_empty() {
+                if new_decl.len() > 1 {
+                    new_decl.push(",");
+                }
+                new_decl.push(args);
+            }
+        }
+        new_decl.push(")");
+        Ok(("", Entity::parse_chunks(&new_decl)?.1))
+    };
+    fn cover<'a>(k: &'a str, covered: &mut HashSet<&'a str>) {
+        if covered.insert(k) {
+            for sup in superclasses_of(k) {
+                cover(sup, covered);
+            }
+        }
+    }
 
     // Sort potential leafs so that ComplexEntity is deterministic and we can
     // match against it later
     let mut potential_leafs: Vec<&str> = potential_leafs.into_iter().collect();
     potential_leafs.sort();
+    let (empty, full): (Vec<&str>, Vec<&str>) = potential_leafs.into_iter()
+        .partition(|k| subentities[k].is_empty());
 
-    // At this point, we'll build up argument strings by splicing together bits
-    // of arguments from the existing string (to make lifetimes happy), then
-    // parse into leaf entities.
-    let mut leaf_entities = Vec::with_capacity(potential_leafs.len());
-    for leaf in potential_leafs.into_iter() {
-        let mut chain = vec![leaf];
-        loop {
-            let sup = superclasses_of(chain.last().unwrap());
-            match sup.len() {
-                0 => break,
-                1 => chain.push(sup[0]),
-                _ => return nom_err(s, ErrorKind::LengthValue), // TODO: error
-            }
+    let mut leaf_entities = Vec::with_capacity(full.len() + empty.len());
+    let mut covered = HashSet::new();
+    for k in full {
+        leaf_entities.push((k, build(k)?.1));
+        cover(k, &mut covered);
+    }
+    // Leafs with no arguments are often the actual type, with attributes
+    // from their supertypes (e.g. MEASURE_REPRESENTATION_ITEM()).  Others
+    // just add bonus constraints, and can't be built from their supertypes'
+    // arguments (e.g. LENGTH_UNIT() alongside NAMED_UNIT(*)SI_UNIT(..));
+    // those are kept by name, so that they're written back out, and their
+    // nearest supertypes with unused arguments become leafs instead.
+    for k in empty {
+        if let Ok((_, e)) = build(k) {
+            leaf_entities.push((k, e));
+            cover(k, &mut covered);
+            continue;
         }
-        let mut new_decl: Vec<&str> = vec![name_tags.get(leaf).unwrap()];
-        for c in chain.iter().rev() {
-            if !subentities[c].is_empty() {
-                new_decl.push(subentities[c]);
-                new_decl.push(if *c == leaf { &")" } else { &"," });
+        leaf_entities.push((k, Entity::_EmptyPartial(Cow::Borrowed(k))));
+        let mut up: Vec<&str> = superclasses_of(k).to_vec();
+        while let Some(sup) = up.pop() {
+            if covered.contains(sup) {
+                continue;
+            } else if subentities.get(sup).map(|a| !a.is_empty()).unwrap_or(false) {
+                leaf_entities.push((sup, build(sup)?.1));
+                cover(sup, &mut covered);
+            } else {
+                up.extend(superclasses_of(sup));
             }
         }
-        leaf_entities.push(Entity::parse_chunks(&new_decl)?.1)
     }
+    leaf_entities.sort_by_key(|(k, _)| *k);
+    let mut leaf_entities: Vec<Entity> = leaf_entities.into_iter()
+        .map(|(_, e)| e)
+        .collect();
+
     // At this point, we assume that there's nothing left to parse, so we
     // return an empty string for the 'remaining' text
     if leaf_entities.len() == 1 {
@@ -336,4 +504,25 @@ mod tests {
         parse_entity_decl(b"#395359=UNCERTAINTY_MEASURE_WITH_UNIT(LENGTH_MEASURE(1.E-007),#395356,'distance_accuracy_value','confusion accuracy');").unwrap();
         parse_entity_decl(b"#1632=(LENGTH_UNIT()NAMED_UNIT(*)SI_UNIT(.MILLI.,.METRE.));").unwrap();
     }
+
+    #[test]
+    fn test_parse_string() {
+        let p = |s| <Cow<str>>::parse(s).unwrap();
+        assert_eq!(p("'Main Housing',"), (",", Cow::Borrowed("Main Housing")));
+        assert_eq!(p("'it''s'"), ("", Cow::Borrowed("it's")));
+        assert_eq!(p("''''"), ("", Cow::Borrowed("'")));
+        assert_eq!(p("$"), ("", Cow::Borrowed("")));
+        assert!(<Cow<str>>::parse("'unterminated").is_err());
+
+        assert_eq!(decode_string(r"Gr\S\v\S\_e"), "Größe");
+        assert_eq!(decode_string(r"\PA\Gr\X\F6\X\DFe"), "Größe");
+        assert_eq!(decode_string(r"\X2\90E8\X0\\X2\54C1\X0\A"), "部品A");
+        assert_eq!(decode_string(r"\X2\90E854C1\X0\"), "部品");
+        assert_eq!(decode_string(r"\X4\0001F600\X0\"), "😀");
+        assert_eq!(decode_string(r"C:\\dir"), r"C:\dir");
+
+        // Malformed directives are passed through
+        assert_eq!(decode_string(r"C:\X2\nope"), r"C:\X2\nope");
+        assert_eq!(decode_string(r"\X\G0"), r"\X\G0");
+    }
 }
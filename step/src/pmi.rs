@@ -0,0 +1,407 @@
+use std::collections::HashMap;
+use log::warn;
+
+use crate::{
+    ap214::{DatumReference, Entity, GeometricItemSpecificUsage_},
+    step_file::{FromEntity, StepFile},
+    units::si_value,
+};
+
+/// Supertypes which are combined with a specific tolerance type in complex
+/// entities, so they don't determine [`Tolerance::kind`]
+const GENERIC_TOLERANCES: &[&str] = &[
+    "GEOMETRIC_TOLERANCE",
+    "GEOMETRIC_TOLERANCE_WITH_DATUM_REFERENCE",
+    "GEOMETRIC_TOLERANCE_WITH_DEFINED_UNIT",
+    "MODIFIED_GEOMETRIC_TOLERANCE",
+];
+
+/// Whether a dimension measures one shape aspect or the distance between two
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum DimensionKind {
+    /// A `DIMENSIONAL_SIZE` (or subtype)
+    Size,
+    /// A `DIMENSIONAL_LOCATION` (or subtype)
+    Location,
+}
+
+/// A named value of a dimension, e.g. its nominal value or a limit
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct Measure<'a> {
+    pub name: &'a str,
+    /// Value in SI units (metres or radians)
+    pub value: f64,
+}
+
+/// A dimension, from a `DIMENSIONAL_SIZE` or `DIMENSIONAL_LOCATION`
+#[derive(Debug)]
+pub struct Dimension<'a> {
+    pub id: usize,
+    pub kind: DimensionKind,
+    pub name: &'a str,
+    /// Shape aspects being measured: the single aspect of a size, or the
+    /// relating and related aspects of a location
+    pub aspects: Vec<usize>,
+    /// Values from the dimension's `DIMENSIONAL_CHARACTERISTIC_REPRESENTATION`
+    pub values: Vec<Measure<'a>>,
+    /// Faces of the measured shape aspects
+    pub faces: Vec<usize>,
+}
+
+/// A `GEOMETRIC_TOLERANCE`, which may be a subtype or a complex entity
+#[derive(Debug)]
+pub struct Tolerance<'a> {
+    pub id: usize,
+    /// Type of tolerance, e.g. `FLATNESS_TOLERANCE`.  For a complex entity,
+    /// this is the member which isn't a generic supertype (e.g. the
+    /// `PARALLELISM_TOLERANCE` alongside a
+    /// `GEOMETRIC_TOLERANCE_WITH_DATUM_REFERENCE`).
+    pub kind: &'static str,
+    pub name: &'a str,
+    pub description: &'a str,
+    /// Size of the tolerance zone in SI units, if its unit could be resolved
+    pub magnitude: Option<f64>,
+    /// The toleranced `SHAPE_ASPECT`
+    pub aspect: usize,
+    /// Referenced `DATUM`s, in order of precedence
+    pub datums: Vec<usize>,
+    /// Faces of the toleranced shape aspect
+    pub faces: Vec<usize>,
+}
+
+/// A `DATUM`, along with the `DATUM_FEATURE`s which establish it
+#[derive(Debug)]
+pub struct Datum<'a> {
+    pub id: usize,
+    pub name: &'a str,
+    /// Datum label, e.g. `A`
+    pub identification: &'a str,
+    /// Datum features, related to the datum by a `SHAPE_ASPECT_RELATIONSHIP`
+    pub features: Vec<usize>,
+    /// Faces of the datum features
+    pub faces: Vec<usize>,
+}
+
+/// Product manufacturing information: dimensions, geometric tolerances, and
+/// datums.
+///
+/// Each of these annotates one or more `SHAPE_ASPECT`s, which are linked to
+/// B-rep faces by `GEOMETRIC_ITEM_SPECIFIC_USAGE` entities.
+#[derive(Debug)]
+pub struct Pmi<'a> {
+    /// Dimensions, in file order
+    pub dimensions: Vec<Dimension<'a>>,
+    /// Geometric tolerances, in file order
+    pub tolerances: Vec<Tolerance<'a>>,
+    /// Datums, in file order
+    pub datums: Vec<Datum<'a>>,
+    /// Faces identified by each shape aspect, by id
+    pub aspect_faces: HashMap<usize, Vec<usize>>,
+}
+
+impl<'a> Pmi<'a> {
+    pub fn new(s: &'a StepFile<'a>) -> Self {
+        let aspect_faces = aspect_faces(s);
+        let faces_of = |aspects: &[usize]| {
+            let mut out: Vec<usize> = aspects.iter()
+                .filter_map(|a| aspect_faces.get(a))
+                .flatten()
+                .copied()
+                .collect();
+            out.sort_unstable();
+            out.dedup();
+            out
+        };
+
+        let mut dimensions = Vec::new();
+        let mut tolerances = Vec::new();
+        let mut datums = Vec::new();
+        let mut values: HashMap<usize, Vec<Measure<'a>>> = HashMap::new();
+        let mut relationships = Vec::new();
+        for (i, e) in s.0.iter().enumerate() {
+            if let Some((kind, name, aspects)) = dimension(e) {
+                dimensions.push(Dimension {
+                    id: i, kind, name, faces: faces_of(&aspects), aspects,
+                    values: vec![],
+                });
+            } else if let Some(mut t) = tolerance(s, i, e) {
+                t.faces = faces_of(&[t.aspect]);
+                tolerances.push(t);
+            } else if let Some((name, identification)) = datum(e) {
+                datums.push(Datum {
+                    id: i, name, identification, features: vec![], faces: vec![],
+                });
+            } else if let Entity::DimensionalCharacteristicRepresentation(r) = e {
+                let items = match s.0.get(r.representation.0) {
+                    Some(Entity::ShapeDimensionRepresentation(r)) => &r.items,
+                    _ => continue,
+                };
+                let out = values.entry(r.dimension.0).or_default();
+                for item in items.iter().filter_map(|i| s.0.get(i.0)) {
+                    match si_value(s, item) {
+                        Some(value) => out.push(Measure { name: item_name(item), value }),
+                        None => warn!("Could not read dimension value from {:?}", item),
+                    }
+                }
+            } else if let Entity::ShapeAspectRelationship(r) = e {
+                relationships.push((r.relating_shape_aspect.0, r.related_shape_aspect.0));
+            }
+        }
+
+        for d in dimensions.iter_mut() {
+            d.values = values.remove(&d.id).unwrap_or_default();
+        }
+        let is_feature = |i: usize| s.0.get(i)
+            .map(|e| e.is_instance_of("DATUM_FEATURE"))
+            .unwrap_or(false);
+        for d in datums.iter_mut() {
+            d.features = relationships.iter()
+                .filter_map(|&(a, b)| if a == d.id {
+                    Some(b)
+                } else if b == d.id {
+                    Some(a)
+                } else {
+                    None
+                })
+                .filter(|f| is_feature(*f))
+                .collect();
+            d.faces = faces_of(&d.features);
+        }
+        Self { dimensions, tolerances, datums, aspect_faces }
+    }
+
+    /// Returns the dimensions which measure a face
+    pub fn dimensions_on(&self, face: usize) -> impl Iterator<Item=&Dimension<'a>> {
+        self.dimensions.iter().filter(move |d| d.faces.contains(&face))
+    }
+
+    /// Returns the tolerances which apply to a face
+    pub fn tolerances_on(&self, face: usize) -> impl Iterator<Item=&Tolerance<'a>> {
+        self.tolerances.iter().filter(move |t| t.faces.contains(&face))
+    }
+
+    /// Returns the datums which are established by a face
+    pub fn datums_on(&self, face: usize) -> impl Iterator<Item=&Datum<'a>> {
+        self.datums.iter().filter(move |d| d.faces.contains(&face))
+    }
+}
+
+/// Collects the faces identified by each `SHAPE_ASPECT`, through
+/// `GEOMETRIC_ITEM_SPECIFIC_USAGE` entities
+fn aspect_faces(s: &StepFile) -> HashMap<usize, Vec<usize>> {
+    let mut out: HashMap<usize, Vec<usize>> = HashMap::new();
+    for e in &s.0 {
+        let u = match e {
+            Entity::ComplexEntity(v) =>
+                v.iter().find_map(GeometricItemSpecificUsage_::try_from_entity),
+            e => GeometricItemSpecificUsage_::try_from_entity(e),
+        };
+        let (aspect, item) = match u {
+            Some(u) => (u.definition.0, u.identified_item.0),
+            None => continue,
+        };
+        if s.0.get(item).map(|e| e.is_instance_of("FACE")).unwrap_or(false) {
+            out.entry(aspect).or_default().push(item);
+        }
+    }
+    for faces in out.values_mut() {
+        faces.sort_unstable();
+        faces.dedup();
+    }
+    out
+}
+
+/// Reads the kind, name, and measured shape aspects of a dimension
+fn dimension<'a>(e: &'a Entity<'a>) -> Option<(DimensionKind, &'a str, Vec<usize>)> {
+    use DimensionKind::*;
+    Some(match e {
+        Entity::ComplexEntity(v) => return v.iter().find_map(dimension),
+        Entity::DimensionalSize(d) => (Size, &d.name.0, vec![d.applies_to.0]),
+        Entity::AngularSize(d) => (Size, &d.name.0, vec![d.applies_to.0]),
+        Entity::DimensionalSizeWithPath(d) => (Size, &d.name.0, vec![d.applies_to.0]),
+        Entity::ExternallyDefinedDimensionDefinition(d) =>
+            (Size, &d.name.0, vec![d.applies_to.0]),
+        Entity::DimensionalLocation(d) => (Location, &d.name.0,
+            vec![d.relating_shape_aspect.0, d.related_shape_aspect.0]),
+        Entity::AngularLocation(d) => (Location, &d.name.0,
+            vec![d.relating_shape_aspect.0, d.related_shape_aspect.0]),
+        Entity::DirectedDimensionalLocation(d) => (Location, &d.name.0,
+            vec![d.relating_shape_aspect.0, d.related_shape_aspect.0]),
+        Entity::DimensionalLocationWithPath(d) => (Location, &d.name.0,
+            vec![d.relating_shape_aspect.0, d.related_shape_aspect.0]),
+        _ => return None,
+    })
+}
+
+/// Reads a geometric tolerance, which is often a complex entity made up of
+/// a specific tolerance type and its generic supertypes.  Faces are filled
+/// in by the caller.
+fn tolerance<'a>(s: &'a StepFile<'a>, id: usize, e: &'a Entity<'a>)
+    -> Option<Tolerance<'a>>
+{
+    let parts: Vec<&Entity> = match e {
+        Entity::ComplexEntity(v) => v.iter().collect(),
+        e => vec![e],
+    };
+    let (name, description, magnitude, aspect) =
+        parts.iter().find_map(|e| tolerance_attrs(e))?;
+    let kind = parts.iter()
+        .filter_map(|e| e.type_name())
+        .find(|t| !GENERIC_TOLERANCES.contains(t))
+        .or_else(|| parts.iter().find_map(|e| e.type_name()))?;
+
+    let mut datums: Vec<(i64, usize)> = parts.iter()
+        .find_map(|e| datum_system(e))
+        .into_iter()
+        .flatten()
+        .filter_map(|r| match s.0.get(r.0)? {
+            Entity::DatumReference(d) => Some((d.precedence, d.referenced_datum.0)),
+            Entity::ReferencedModifiedDatum(d) =>
+                Some((d.precedence, d.referenced_datum.0)),
+            e => {
+                warn!("Unknown datum reference {:?}", e);
+                None
+            },
+        })
+        .collect();
+    datums.sort_by_key(|d| d.0);
+
+    let magnitude = s.0.get(magnitude).and_then(|m| si_value(s, m));
+    if magnitude.is_none() {
+        warn!("Could not read magnitude of tolerance #{}", id);
+    }
+    Some(Tolerance {
+        id, kind, name, description, magnitude, aspect,
+        datums: datums.into_iter().map(|d| d.1).collect(),
+        faces: vec![],
+    })
+}
+
+/// Reads the attributes shared by every `GEOMETRIC_TOLERANCE`: its name,
+/// description, magnitude, and toleranced shape aspect
+fn tolerance_attrs<'a>(e: &'a Entity<'a>) -> Option<(&'a str, &'a str, usize, usize)> {
+    macro_rules! attrs {
+        ($t:expr) => {
+            (&$t.name.0[..], &$t.description.0[..], $t.magnitude.0,
+             $t.toleranced_shape_aspect.0)
+        };
+    }
+    Some(match e {
+        Entity::GeometricTolerance(t) => attrs!(t),
+        Entity::GeometricToleranceWithDatumReference(t) => attrs!(t),
+        Entity::GeometricToleranceWithDefinedUnit(t) => attrs!(t),
+        Entity::ModifiedGeometricTolerance(t) => attrs!(t),
+        Entity::AngularityTolerance(t) => attrs!(t),
+        Entity::CircularRunoutTolerance(t) => attrs!(t),
+        Entity::CoaxialityTolerance(t) => attrs!(t),
+        Entity::ConcentricityTolerance(t) => attrs!(t),
+        Entity::CylindricityTolerance(t) => attrs!(t),
+        Entity::FlatnessTolerance(t) => attrs!(t),
+        Entity::LineProfileTolerance(t) => attrs!(t),
+        Entity::ParallelismTolerance(t) => attrs!(t),
+        Entity::PerpendicularityTolerance(t) => attrs!(t),
+        Entity::PositionTolerance(t) => attrs!(t),
+        Entity::RoundnessTolerance(t) => attrs!(t),
+        Entity::StraightnessTolerance(t) => attrs!(t),
+        Entity::SurfaceProfileTolerance(t) => attrs!(t),
+        Entity::SymmetryTolerance(t) => attrs!(t),
+        Entity::TotalRunoutTolerance(t) => attrs!(t),
+        _ => return None,
+    })
+}
+
+/// Returns the datum references of a tolerance with a datum system
+fn datum_system<'a>(e: &'a Entity<'a>) -> Option<&'a [DatumReference<'a>]> {
+    Some(match e {
+        Entity::GeometricToleranceWithDatumReference(t) => &t.datum_system,
+        Entity::AngularityTolerance(t) => &t.datum_system,
+        Entity::CircularRunoutTolerance(t) => &t.datum_system,
+        Entity::CoaxialityTolerance(t) => &t.datum_system,
+        Entity::ConcentricityTolerance(t) => &t.datum_system,
+        Entity::ParallelismTolerance(t) => &t.datum_system,
+        Entity::PerpendicularityTolerance(t) => &t.datum_system,
+        Entity::SymmetryTolerance(t) => &t.datum_system,
+        Entity::TotalRunoutTolerance(t) => &t.datum_system,
+        _ => return None,
+    })
+}
+
+/// Reads the name and identification (label) of a datum
+fn datum<'a>(e: &'a Entity<'a>) -> Option<(&'a str, &'a str)> {
+    match e {
+        Entity::Datum(d) => Some((&d.name.0, &d.identification.0)),
+        Entity::CommonDatum(d) =>
+            Some((&d.shape_aspect__name.0, &d.identification.0)),
+        _ => None,
+    }
+}
+
+/// Returns the name of a measure item, which may be a complex entity
+fn item_name<'a>(e: &'a Entity<'a>) -> &'a str {
+    match e {
+        Entity::ComplexEntity(v) => v.iter()
+            .map(item_name)
+            .find(|n| !n.is_empty())
+            .unwrap_or(""),
+        Entity::MeasureRepresentationItem(m) => &m.name.0,
+        Entity::RepresentationItem(r) => &r.name.0,
+        _ => "",
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn assert_close(a: f64, b: f64) {
+        assert!((a - b).abs() < 1e-12, "{} != {}", a, b);
+    }
+
+    #[test]
+    fn test_pmi() {
+        let flat = StepFile::strip_flatten(include_bytes!("../../examples/cuboid_pmi.step"));
+        let (s, report) = StepFile::parse(&flat);
+        assert!(report.failures.is_empty());
+        let pmi = s.pmi();
+
+        assert_eq!(pmi.datums.len(), 1);
+        let d = &pmi.datums[0];
+        assert_eq!(d.identification, "A");
+        assert_eq!(d.features, vec![202]);
+        assert_eq!(d.faces, vec![111]);
+
+        assert_eq!(pmi.tolerances.len(), 2);
+        let t = &pmi.tolerances[0];
+        assert_eq!(t.kind, "PARALLELISM_TOLERANCE");
+        assert_eq!(t.name, "parallelism");
+        assert_close(t.magnitude.unwrap(), 5e-5);
+        assert_eq!(t.aspect, 200);
+        assert_eq!(t.datums, vec![204]);
+        assert_eq!(t.faces, vec![110]);
+        let t = &pmi.tolerances[1];
+        assert_eq!(t.kind, "FLATNESS_TOLERANCE");
+        assert!(t.datums.is_empty());
+        assert_eq!(t.faces, vec![111]);
+
+        assert_eq!(pmi.dimensions.len(), 2);
+        let d = &pmi.dimensions[0];
+        assert_eq!(d.kind, DimensionKind::Location);
+        assert_eq!(d.name, "height");
+        assert_eq!(d.aspects, vec![202, 200]);
+        assert_eq!(d.faces, vec![110, 111]);
+        assert_eq!(d.values.len(), 1);
+        assert_eq!(d.values[0].name, "nominal value");
+        assert_close(d.values[0].value, 0.0762);
+        let d = &pmi.dimensions[1];
+        assert_eq!(d.kind, DimensionKind::Size);
+        assert_eq!(d.name, "width");
+        assert_eq!(d.faces, vec![106, 108]);
+        assert_close(d.values[0].value, 0.0508);
+
+        // Faces can be used to look up the PMI that annotates them
+        assert_eq!(pmi.tolerances_on(111).count(), 1);
+        assert_eq!(pmi.dimensions_on(111).count(), 1);
+        assert_eq!(pmi.datums_on(111).next().unwrap().id, 204);
+        assert_eq!(pmi.tolerances_on(107).count(), 0);
+    }
+}
@@ -0,0 +1,310 @@
+use std::collections::HashMap;
+use log::warn;
+
+use crate::{
+    ap214::Entity,
+    id::HasId,
+    step_file::Entities,
+};
+
+/// Limit on nested style entities, which guards against reference cycles
+const MAX_DEPTH: usize = 16;
+
+/// Visual style of an item, as set by one or more `STYLED_ITEM`s
+#[derive(Debug, Copy, Clone, Default, PartialEq)]
+pub struct Style {
+    /// RGB color, with each channel from 0 to 1
+    pub color: Option<[f64; 3]>,
+    /// Transparency, from 0 (opaque) to 1 (invisible)
+    pub transparency: Option<f64>,
+}
+
+impl Style {
+    /// Fills in any unset values from `other`
+    pub fn or(self, other: Style) -> Self {
+        Self {
+            color: self.color.or(other.color),
+            transparency: self.transparency.or(other.transparency),
+        }
+    }
+
+    fn is_empty(&self) -> bool {
+        self.color.is_none() && self.transparency.is_none()
+    }
+}
+
+/// A `PRESENTATION_LAYER_ASSIGNMENT`, which groups items into a named layer
+#[derive(Debug)]
+pub struct Layer<'a> {
+    pub name: &'a str,
+    pub description: &'a str,
+    /// Ids of the items on this layer
+    pub items: Vec<usize>,
+}
+
+/// Presentation data for a file: colors and transparency from styled items,
+/// layers, and the names of geometric items
+#[derive(Debug)]
+pub struct Presentation<'a> {
+    /// Style assigned to each item (e.g. a face, shell or solid), by id.
+    /// Overriding styles have already been applied on top of the styles
+    /// that they override.
+    pub styles: HashMap<usize, Style>,
+    pub layers: Vec<Layer<'a>>,
+    /// Non-empty names of faces, shells, and solids, plus the names of
+    /// styled items (for items which don't have a name of their own)
+    pub names: HashMap<usize, &'a str>,
+}
+
+impl<'a> Presentation<'a> {
+    pub fn new<'s: 'a, S: Entities<'s>>(s: &'a S) -> Self {
+        let mut styles: HashMap<usize, Style> = HashMap::new();
+        let mut overrides = Vec::new();
+        let mut names = HashMap::new();
+        let mut layers = Vec::new();
+
+        // Only the entity types that we're interested in are visited, so
+        // this doesn't force a lazily-parsed file to parse everything
+        for i in s.ids_of_type("STYLED_ITEM") {
+            match s.get(i) {
+                Some(Entity::StyledItem(y)) => {
+                    let style = resolve(s, &y.styles);
+                    let prev = styles.entry(y.item.0).or_default();
+                    *prev = prev.or(style);
+                    names.entry(y.item.0).or_insert(&y.name.0[..]);
+                },
+                Some(Entity::OverRidingStyledItem(y)) =>
+                    overrides.push((y.item.0, resolve(s, &y.styles))),
+                Some(Entity::ContextDependentOverRidingStyledItem(y)) =>
+                    overrides.push((y.item.0, resolve(s, &y.styles))),
+                _ => (),
+            }
+        }
+        for i in s.ids_of_type("PRESENTATION_LAYER_ASSIGNMENT") {
+            if let Some(Entity::PresentationLayerAssignment(a)) = s.get(i) {
+                layers.push(Layer {
+                    name: &a.name.0,
+                    description: &a.description.0,
+                    items: a.assigned_items.iter().map(|i| i.0).collect(),
+                });
+            }
+        }
+        // An item's own name takes priority over its styled item's name
+        for t in NAMED_TYPES {
+            for i in s.ids_of_type(t) {
+                let name = s.get(i).and_then(item_name);
+                if let Some(name) = name.filter(|n| !n.is_empty()) {
+                    names.insert(i, name);
+                }
+            }
+        }
+        for (item, style) in overrides {
+            let prev = styles.entry(item).or_default();
+            *prev = style.or(*prev);
+        }
+        styles.retain(|_, s| !s.is_empty());
+        names.retain(|_, n| !n.is_empty());
+        Self { styles, layers, names }
+    }
+
+    /// Returns the style assigned directly to an item
+    pub fn style(&self, id: usize) -> Style {
+        self.styles.get(&id).copied().unwrap_or_default()
+    }
+
+    /// Returns the style of every face in a solid (a `MANIFOLD_SOLID_BREP`,
+    /// `BREP_WITH_VOIDS`, or `SHELL_BASED_SURFACE_MODEL`), keyed by face id.
+    /// Values which aren't set on a face are inherited from its shell, then
+    /// from the solid.
+    pub fn face_styles<'b, S: Entities<'b>>(&self, s: &S, solid: usize) -> HashMap<usize, Style> {
+        let mut out = HashMap::new();
+        let base = self.style(solid);
+        if let Some(e) = s.get(solid) {
+            for shell in e.upstream() {
+                self.shell_face_styles(s, shell, self.style(shell).or(base), 0, &mut out);
+            }
+        }
+        out
+    }
+
+    fn shell_face_styles<'b, S: Entities<'b>>(&self, s: &S, shell: usize,
+                                              base: Style, depth: usize,
+                                              out: &mut HashMap<usize, Style>)
+    {
+        let e = match s.get(shell) {
+            Some(e) if e.is_instance_of("CONNECTED_FACE_SET") => e,
+            _ => return,
+        };
+        for i in e.upstream() {
+            match s.get(i) {
+                Some(f) if f.is_instance_of("FACE") => {
+                    out.insert(i, self.style(i).or(base));
+                },
+                // An ORIENTED_CLOSED_SHELL refers to the shell that it flips
+                Some(_) if depth < MAX_DEPTH => {
+                    let base = self.style(i).or(base);
+                    self.shell_face_styles(s, i, base, depth + 1, out);
+                },
+                _ => (),
+            }
+        }
+    }
+
+    /// Returns the layers which an item is assigned to
+    pub fn layers_of(&self, id: usize) -> impl Iterator<Item=&Layer<'a>> {
+        self.layers.iter().filter(move |a| a.items.contains(&id))
+    }
+
+    /// Returns the name of an item, if it has one
+    pub fn name(&self, id: usize) -> Option<&'a str> {
+        self.names.get(&id).copied()
+    }
+}
+
+/// Supertypes of the entities which [`item_name`] reads
+const NAMED_TYPES: [&str; 5] = [
+    "FACE_SURFACE", "CLOSED_SHELL", "OPEN_SHELL", "MANIFOLD_SOLID_BREP",
+    "SHELL_BASED_SURFACE_MODEL",
+];
+
+/// Returns the name of a face, shell, or solid
+fn item_name<'a>(e: &'a Entity<'a>) -> Option<&'a str> {
+    let name = match e {
+        Entity::AdvancedFace(f) => &f.name,
+        Entity::FaceSurface(f) => &f.representation_item__name,
+        Entity::ClosedShell(c) => &c.name,
+        Entity::OpenShell(c) => &c.name,
+        Entity::OrientedClosedShell(c) => &c.name,
+        Entity::ManifoldSolidBrep(b) => &b.name,
+        Entity::BrepWithVoids(b) => &b.name,
+        Entity::ShellBasedSurfaceModel(b) => &b.name,
+        _ => return None,
+    };
+    Some(&name.0)
+}
+
+/// Resolves a set of `PRESENTATION_STYLE_ASSIGNMENT`s into a single style,
+/// where the first value found wins
+fn resolve<'a, S: Entities<'a>, T: HasId>(s: &S, styles: &T) -> Style {
+    let mut out = Style::default();
+    for i in ids(styles) {
+        visit(s, i, 0, &mut out);
+    }
+    out
+}
+
+/// Returns every id referenced by a value.  Many of the style selects are
+/// ambiguous, so we match on the entities themselves rather than trusting
+/// the parsed select variant.
+fn ids<T: HasId>(t: &T) -> Vec<usize> {
+    let mut out = Vec::new();
+    t.append_ids(&mut out);
+    out
+}
+
+/// Walks the tree of style entities, filling in values in `out`
+fn visit<'a, S: Entities<'a>>(s: &S, i: usize, depth: usize, out: &mut Style) {
+    if depth > MAX_DEPTH {
+        warn!("Style tree is too deep at #{}", i);
+        return;
+    }
+    let mut next = |t: Vec<usize>| for j in t {
+        visit(s, j, depth + 1, out);
+    };
+    match s.get(i) {
+        Some(Entity::PresentationStyleAssignment(p)) => next(ids(&p.styles)),
+        Some(Entity::SurfaceStyleUsage(u)) => next(ids(&u.style)),
+        Some(Entity::SurfaceSideStyle(u)) => next(ids(&u.styles)),
+        Some(Entity::SurfaceStyleFillArea(f)) => next(ids(&f.fill_area)),
+        Some(Entity::FillAreaStyle(f)) => next(ids(&f.fill_styles)),
+        Some(Entity::FillAreaStyleColour(f)) => next(ids(&f.fill_colour)),
+        Some(Entity::SurfaceStyleRendering(r)) => next(ids(&r.surface_colour)),
+        Some(Entity::SurfaceStyleRenderingWithProperties(r)) => {
+            next(ids(&r.surface_colour));
+            next(ids(&r.properties));
+        },
+        Some(Entity::CurveStyle(c)) => next(ids(&c.curve_colour)),
+        Some(Entity::SurfaceStyleTransparent(t)) if out.transparency.is_none() =>
+            out.transparency = Some(t.transparency),
+        Some(Entity::ColourRgb(c)) if out.color.is_none() =>
+            out.color = Some([c.red, c.green, c.blue]),
+        Some(Entity::DraughtingPreDefinedColour(c)) if out.color.is_none() =>
+            out.color = predefined_colour(&c.name.0),
+        _ => (),
+    }
+}
+
+/// Looks up a `DRAUGHTING_PRE_DEFINED_COLOUR` by name
+fn predefined_colour(name: &str) -> Option<[f64; 3]> {
+    match name {
+        "black" => Some([0.0, 0.0, 0.0]),
+        "red" => Some([1.0, 0.0, 0.0]),
+        "green" => Some([0.0, 1.0, 0.0]),
+        "blue" => Some([0.0, 0.0, 1.0]),
+        "yellow" => Some([1.0, 1.0, 0.0]),
+        "magenta" => Some([1.0, 0.0, 1.0]),
+        "cyan" => Some([0.0, 1.0, 1.0]),
+        "white" => Some([1.0, 1.0, 1.0]),
+        _ => {
+            warn!("Unknown predefined colour '{}'", name);
+            None
+        },
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::step_file::StepFile;
+
+    #[test]
+    fn test_presentation() {
+        let data = b"DATA;\
+            #1=MANIFOLD_SOLID_BREP('Body',#2);\
+            #2=CLOSED_SHELL('',(#3,#4));\
+            #3=ADVANCED_FACE('top',(),#99,.T.);\
+            #4=ADVANCED_FACE('',(),#99,.T.);\
+            #10=COLOUR_RGB('',1.,0.5,0.);\
+            #11=FILL_AREA_STYLE_COLOUR('',#10);\
+            #12=FILL_AREA_STYLE('',(#11));\
+            #13=SURFACE_STYLE_FILL_AREA(#12);\
+            #14=SURFACE_SIDE_STYLE('',(#13));\
+            #15=SURFACE_STYLE_USAGE(.BOTH.,#14);\
+            #16=PRESENTATION_STYLE_ASSIGNMENT((#15));\
+            #17=STYLED_ITEM('color',(#16),#1);\
+            #18=STYLED_ITEM('',(#16),#4);\
+            #20=DRAUGHTING_PRE_DEFINED_COLOUR('blue');\
+            #21=SURFACE_STYLE_RENDERING_WITH_PROPERTIES(.NORMAL_SHADING.,#20,(#22));\
+            #22=SURFACE_STYLE_TRANSPARENT(0.25);\
+            #23=SURFACE_SIDE_STYLE('',(#21));\
+            #24=SURFACE_STYLE_USAGE(.BOTH.,#23);\
+            #25=PRESENTATION_STYLE_ASSIGNMENT((#24));\
+            #26=OVER_RIDING_STYLED_ITEM('',(#25),#4,#18);\
+            #30=PRESENTATION_LAYER_ASSIGNMENT('Layer 1','bodies',(#1,#3));\
+            ENDSEC;";
+        let (s, report) = StepFile::parse(data);
+        assert!(report.failures.is_empty());
+        let p = s.presentation();
+
+        let orange = Style { color: Some([1.0, 0.5, 0.0]), transparency: None };
+        let blue = Style { color: Some([0.0, 0.0, 1.0]), transparency: Some(0.25) };
+        assert_eq!(p.style(1), orange);
+        assert_eq!(p.style(4), blue);
+        assert_eq!(p.style(2), Style::default());
+
+        let faces = p.face_styles(&s, 1);
+        assert_eq!(faces.len(), 2);
+        assert_eq!(faces[&3], orange);
+        assert_eq!(faces[&4], blue);
+
+        let layers: Vec<_> = p.layers_of(3).map(|a| a.name).collect();
+        assert_eq!(layers, vec!["Layer 1"]);
+        assert_eq!(p.layers[0].description, "bodies");
+        assert_eq!(p.layers_of(4).count(), 0);
+
+        assert_eq!(p.name(1), Some("Body"));
+        assert_eq!(p.name(3), Some("top"));
+        assert_eq!(p.name(2), None);
+        assert_eq!(p.name(4), None);
+    }
+}
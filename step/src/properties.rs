@@ -0,0 +1,282 @@
+use std::collections::HashMap;
+use log::warn;
+
+use crate::{
+    ap214::{
+        Entity, CartesianPoint_, MeasureValue,
+        NextAssemblyUsageOccurrence_, Product,
+        ProductDefinitionShape_, Product_, PropertyDefinitionRepresentation_,
+        PropertyDefinition_, Representation, Representation_, ShapeAspect_,
+    },
+    assembly::product_definition,
+    id::Id,
+    step_file::{FromEntity, StepFile},
+    units::{length_power_scale, measure_with_unit},
+};
+
+/// Name of the `PROPERTY_DEFINITION` used by the CAx-IF recommended practices
+const PROPERTY_NAME: &str = "geometric validation property";
+
+/// Representation names which mark validation properties, for writers that
+/// don't use [`PROPERTY_NAME`]
+const REPRESENTATION_NAMES: &[&str] = &["volume", "surface area", "centroid"];
+
+/// Geometric validation properties, converted into SI units
+#[derive(Debug, Copy, Clone, Default, PartialEq)]
+pub struct GeometricProperties {
+    /// Volume, in cubic metres
+    pub volume: Option<f64>,
+    /// Surface area, in square metres
+    pub area: Option<f64>,
+    /// Centroid, in metres, in the coordinate system of the shape
+    pub centroid: Option<[f64; 3]>,
+}
+
+/// Validation properties attached to a single product or shape aspect
+#[derive(Debug)]
+pub struct ValidationTarget<'a> {
+    /// The `PRODUCT_DEFINITION_SHAPE` or `SHAPE_ASPECT` which the properties
+    /// are attached to
+    pub definition: usize,
+    /// Product which owns the shape, if it could be found
+    pub product: Option<Product<'a>>,
+    pub product_name: Option<&'a str>,
+    /// Solids (or other geometric items) which the properties describe.
+    /// This is empty when the properties describe the product as a whole.
+    pub items: Vec<usize>,
+    pub properties: GeometricProperties,
+}
+
+/// Geometric validation properties (volume, surface area, and centroid)
+/// recorded by the originating CAD system, built from
+/// `PROPERTY_DEFINITION` / `PROPERTY_DEFINITION_REPRESENTATION` entities.
+///
+/// Volumes and areas are scaled using the unit of each measure; centroids
+/// use the length unit of their representation's context.
+#[derive(Debug)]
+pub struct ValidationProperties<'a> {
+    /// One entry per property target, in file order
+    pub targets: Vec<ValidationTarget<'a>>,
+}
+
+impl<'a> ValidationProperties<'a> {
+    pub fn new(s: &'a StepFile<'a>) -> Self {
+        let items = aspect_items(s);
+        let mut targets: Vec<ValidationTarget<'a>> = Vec::new();
+        let mut index = HashMap::new();
+        for r in s.0.iter().filter_map(PropertyDefinitionRepresentation_::try_from_entity) {
+            let p = match get::<PropertyDefinition_>(s, r.definition.0) {
+                Some(p) => p,
+                None => continue,
+            };
+            let rep = match get::<Representation_>(s, r.used_representation.0) {
+                Some(rep) => rep,
+                None => continue,
+            };
+            if !p.name.0.eq_ignore_ascii_case(PROPERTY_NAME) &&
+               !REPRESENTATION_NAMES.iter().any(|n| rep.name.0.eq_ignore_ascii_case(n))
+            {
+                continue;
+            }
+
+            let definition = p.definition.0;
+            let i = *index.entry(definition).or_insert_with(|| {
+                let (product, product_name) = product_of(s, definition);
+                targets.push(ValidationTarget {
+                    definition,
+                    product,
+                    product_name,
+                    items: items.get(&definition).cloned().unwrap_or_default(),
+                    properties: GeometricProperties::default(),
+                });
+                targets.len() - 1
+            });
+            let out = &mut targets[i].properties;
+            for item in &rep.items {
+                read_item(s, item.0, r.used_representation, out);
+            }
+        }
+        Self { targets }
+    }
+
+    /// Returns the properties which describe a whole product
+    pub fn of_product(&self, p: Product) -> Option<&GeometricProperties> {
+        self.targets.iter()
+            .find(|t| t.product == Some(p) && t.items.is_empty())
+            .map(|t| &t.properties)
+    }
+
+    /// Returns the properties which describe a particular solid (or other
+    /// geometric item), by id
+    pub fn of_item(&self, id: usize) -> Option<&GeometricProperties> {
+        self.targets.iter()
+            .find(|t| t.items.contains(&id))
+            .map(|t| &t.properties)
+    }
+}
+
+/// Returns the entity at index `i`, if it's present and of type `T`
+fn get<'a, T: FromEntity<'a>>(s: &'a StepFile<'a>, i: usize) -> Option<&'a T> {
+    s.0.get(i).and_then(T::try_from_entity)
+}
+
+/// Reads a single item from a validation property's representation, filling
+/// in the matching value in `out` (where the first value found wins)
+fn read_item(s: &StepFile, i: usize, rep: Representation,
+             out: &mut GeometricProperties)
+{
+    if let Some(p) = get::<CartesianPoint_>(s, i) {
+        if out.centroid.is_none() && p.coordinates.len() == 3 {
+            let k = s.units(rep).length;
+            out.centroid = Some([p.coordinates[0].0 * k,
+                                 p.coordinates[1].0 * k,
+                                 p.coordinates[2].0 * k]);
+        }
+        return;
+    }
+
+    let (value, unit) = match s.0.get(i).and_then(measure_with_unit) {
+        Some(m) => m,
+        None => return,
+    };
+    let (slot, value, exponent) = match value {
+        MeasureValue::VolumeMeasure(v) => (&mut out.volume, v.0, 3),
+        MeasureValue::AreaMeasure(v) => (&mut out.area, v.0, 2),
+        _ => return,
+    };
+    if slot.is_none() {
+        match length_power_scale(s, unit.0, exponent) {
+            Some(k) => *slot = Some(value * k),
+            None => warn!("Could not resolve units of measure #{}", i),
+        }
+    }
+}
+
+/// Finds the product which owns a `PRODUCT_DEFINITION_SHAPE` or
+/// `SHAPE_ASPECT`, returning its id and name
+fn product_of<'a>(s: &'a StepFile<'a>, definition: usize)
+    -> (Option<Product<'a>>, Option<&'a str>)
+{
+    let shape = match get::<ShapeAspect_>(s, definition) {
+        Some(a) => a.of_shape.0,
+        None => definition,
+    };
+    let pd = match get::<ProductDefinitionShape_>(s, shape) {
+        Some(p) => p.definition.0,
+        None => return (None, None),
+    };
+    // Properties of an assembly occurrence describe the child product
+    let pd = match get::<NextAssemblyUsageOccurrence_>(s, pd) {
+        Some(n) => n.related_product_definition.0,
+        None => pd,
+    };
+    match product_definition(s, pd) {
+        Some(p) => (Some(Id::new(p)), get::<Product_>(s, p).map(|p| &p.name.0[..])),
+        None => {
+            warn!("Could not find product for #{}", definition);
+            (None, None)
+        },
+    }
+}
+
+/// Collects the geometric items identified by each `SHAPE_ASPECT`, through
+/// either a `SHAPE_DEFINITION_REPRESENTATION` (which identifies every item
+/// in its shape representation) or a `GEOMETRIC_ITEM_SPECIFIC_USAGE`
+fn aspect_items(s: &StepFile) -> HashMap<usize, Vec<usize>> {
+    let mut out: HashMap<usize, Vec<usize>> = HashMap::new();
+    for e in &s.0 {
+        let (aspect, items) = match e {
+            Entity::ShapeDefinitionRepresentation(r) => {
+                let items = s.0.get(r.used_representation.0)
+                    .into_iter()
+                    .flat_map(|e| e.upstream())
+                    .filter(|i| s.0.get(*i)
+                        .map(|e| !e.is_instance_of("REPRESENTATION_CONTEXT"))
+                        .unwrap_or(false))
+                    .collect();
+                (r.definition.0, items)
+            },
+            Entity::GeometricItemSpecificUsage(u) =>
+                (u.definition.0, vec![u.identified_item.0]),
+            Entity::ItemIdentifiedRepresentationUsage(u) =>
+                (u.definition.0, vec![u.identified_item.0]),
+            _ => continue,
+        };
+        if get::<ShapeAspect_>(s, aspect).is_some() {
+            out.entry(aspect).or_default().extend(items);
+        }
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn assert_close(a: f64, b: f64) {
+        assert!((a - b).abs() < 1e-12 * b.abs().max(1.0), "{} != {}", a, b);
+    }
+
+    #[test]
+    fn test_validation_properties() {
+        let data = b"DATA;\
+            #1=PRODUCT('P1','Bracket','',());\
+            #2=PRODUCT_DEFINITION_FORMATION('','',#1);\
+            #3=PRODUCT_DEFINITION('design','',#2,#99);\
+            #4=PRODUCT_DEFINITION_SHAPE('','',#3);\
+            #5=(LENGTH_UNIT()NAMED_UNIT(*)SI_UNIT(.MILLI.,.METRE.));\
+            #6=LENGTH_MEASURE_WITH_UNIT(LENGTH_MEASURE(25.4),#5);\
+            #7=(CONVERSION_BASED_UNIT('INCH',#6)LENGTH_UNIT()NAMED_UNIT(#98));\
+            #8=(GEOMETRIC_REPRESENTATION_CONTEXT(3)GLOBAL_UNIT_ASSIGNED_CONTEXT((#7))REPRESENTATION_CONTEXT('',''));\
+            #10=DERIVED_UNIT((#11));\
+            #11=DERIVED_UNIT_ELEMENT(#5,3.);\
+            #12=MEASURE_REPRESENTATION_ITEM('volume measure',VOLUME_MEASURE(1000.),#10);\
+            #13=REPRESENTATION('volume',(#12),#8);\
+            #14=PROPERTY_DEFINITION('geometric validation property','volume of Bracket',#4);\
+            #15=PROPERTY_DEFINITION_REPRESENTATION(#14,#13);\
+            #20=CARTESIAN_POINT('centre point',(1.,2.,3.));\
+            #21=REPRESENTATION('centroid',(#20),#8);\
+            #22=PROPERTY_DEFINITION('geometric validation property','centroid of Bracket',#4);\
+            #23=PROPERTY_DEFINITION_REPRESENTATION(#22,#21);\
+            #30=DERIVED_UNIT((#31));\
+            #31=DERIVED_UNIT_ELEMENT(#7,2.);\
+            #32=(AREA_MEASURE_WITH_UNIT()MEASURE_REPRESENTATION_ITEM()MEASURE_WITH_UNIT(AREA_MEASURE(2.),#30)REPRESENTATION_ITEM('surface area measure'));\
+            #33=REPRESENTATION('surface area',(#32),#8);\
+            #34=PROPERTY_DEFINITION('','',#40);\
+            #35=PROPERTY_DEFINITION_REPRESENTATION(#34,#33);\
+            #40=SHAPE_ASPECT('','solid',#4,.F.);\
+            #41=MANIFOLD_SOLID_BREP('',#99);\
+            #42=SHAPE_REPRESENTATION('',(#41),#8);\
+            #43=SHAPE_DEFINITION_REPRESENTATION(#40,#42);\
+            #50=MEASURE_REPRESENTATION_ITEM('density',MASS_MEASURE(7.8),#5);\
+            #51=REPRESENTATION('density',(#50),#8);\
+            #52=PROPERTY_DEFINITION('material property','',#4);\
+            #53=PROPERTY_DEFINITION_REPRESENTATION(#52,#51);\
+            ENDSEC;";
+        let (s, _) = StepFile::parse(data);
+        let v = s.validation_properties();
+        assert_eq!(v.targets.len(), 2);
+
+        let t = &v.targets[0];
+        assert_eq!(t.definition, 4);
+        assert_eq!(t.product, Some(Id::new(1)));
+        assert_eq!(t.product_name, Some("Bracket"));
+        assert!(t.items.is_empty());
+        let p = v.of_product(Id::new(1)).unwrap();
+        assert_close(p.volume.unwrap(), 1e-6);
+        assert_eq!(p.area, None);
+        let c = p.centroid.unwrap();
+        assert_close(c[0], 0.0254);
+        assert_close(c[1], 0.0508);
+        assert_close(c[2], 0.0762);
+
+        let t = &v.targets[1];
+        assert_eq!(t.definition, 40);
+        assert_eq!(t.product, Some(Id::new(1)));
+        assert_eq!(t.items, vec![41]);
+        let p = v.of_item(41).unwrap();
+        assert_close(p.area.unwrap(), 2.0 * 0.0254 * 0.0254);
+        assert_eq!(p.volume, None);
+        assert!(v.of_item(42).is_none());
+    }
+}
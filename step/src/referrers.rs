@@ -0,0 +1,128 @@
+use crate::{
+    ap214::Entity,
+    id::Id,
+    step_file::{FromEntity, StepFile},
+};
+
+/// Inverse of [`Entity::upstream`](crate::ap214::Entity::upstream): for each
+/// entity, stores the ids of every entity which refers to it.
+///
+/// The index is built once (in a single pass over the file) and stored in
+/// compressed form, so lookups are a pair of slice indexes.
+#[derive(Debug)]
+pub struct ReverseIndex {
+    /// `ids[start[i]..start[i + 1]]` are the referrers of entity `i`
+    start: Vec<usize>,
+    ids: Vec<usize>,
+}
+
+/// Returns an entity's outgoing references, sorted and deduplicated, skipping
+/// NUL references and dangling ids which point past the end of a file with
+/// `n` entity slots
+pub(crate) fn upstream_ids(e: &Entity, n: usize) -> Vec<usize> {
+    let mut v = e.upstream();
+    v.retain(|i| *i != 0 && *i < n);
+    v.sort_unstable();
+    v.dedup();
+    v
+}
+
+impl ReverseIndex {
+    pub fn new(s: &StepFile) -> Self {
+        let n = s.0.len();
+
+        let upstream: Vec<Vec<usize>> = s.0.iter().map(|e| upstream_ids(e, n)).collect();
+
+        // Count referrers of each entity, then convert counts into offsets
+        let mut start = vec![0; n + 1];
+        for i in upstream.iter().flatten() {
+            start[*i + 1] += 1;
+        }
+        for i in 0..n {
+            start[i + 1] += start[i];
+        }
+
+        // Scatter referrers into place.  Sources are visited in order, so
+        // each entity's referrers end up sorted.
+        let mut ids = vec![0; start[n]];
+        let mut next = start.clone();
+        for (src, up) in upstream.iter().enumerate() {
+            for i in up {
+                ids[next[*i]] = src;
+                next[*i] += 1;
+            }
+        }
+        Self { start, ids }
+    }
+
+    /// Returns the ids of every entity which refers to `id`, in ascending
+    /// order.  Each referrer is listed once, even if it uses `id` in more than
+    /// one attribute.
+    pub fn referrers<T>(&self, id: Id<T>) -> &[usize] {
+        match (self.start.get(id.0), self.start.get(id.0 + 1)) {
+            (Some(a), Some(b)) => &self.ids[*a..*b],
+            _ => &[],
+        }
+    }
+
+    /// Returns every entity which refers to `id` and is an instance of `T`,
+    /// including instances of its subtypes and complex entities which
+    /// contain `T` (as in [`StepFile::instances_of`]).
+    ///
+    /// Entities are returned as-is, since a subtype can't be borrowed as
+    /// `T`; use `T::try_from_entity` to get at the attributes of exact
+    /// matches.
+    pub fn referrers_of<'s, 'a: 's, T: FromEntity<'a> + 'a, U>(
+        &'s self, s: &'a StepFile<'a>, id: Id<U>,
+    ) -> impl Iterator<Item=(Id<T>, &'a Entity<'a>)> + 's {
+        self.referrers(id)
+            .iter()
+            .map(move |i| (*i, &s.0[*i]))
+            .filter(|(_, e)| e.is_instance_of(T::TYPE_NAME))
+            .map(|(i, e)| (Id::new(i), e))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ap214::{
+        CartesianPoint_, Direction_, Axis2Placement3d_, Placement_, VertexPoint_,
+    };
+
+    #[test]
+    fn test_referrers() {
+        let data = b"DATA;\
+            #1=CARTESIAN_POINT('',(0.,0.,0.));\
+            #2=DIRECTION('',(0.,0.,1.));\
+            #3=AXIS2_PLACEMENT_3D('',#1,#2,#2);\
+            #4=VERTEX_POINT('',#1);\
+            #5=AXIS2_PLACEMENT_3D('',#1,$,$);\
+            #7=VERTEX_POINT('',#99);\
+            ENDSEC;";
+        let (s, _) = StepFile::parse(data);
+        let index = s.reverse_index();
+
+        assert_eq!(index.referrers(Id::<CartesianPoint_>::new(1)), &[3, 4, 5]);
+        assert_eq!(index.referrers(Id::<Direction_>::new(2)), &[3]);
+        assert!(index.referrers(Id::<()>::new(3)).is_empty());
+        assert!(index.referrers(Id::<()>::empty()).is_empty());
+        assert!(index.referrers(Id::<()>::new(99)).is_empty());
+
+        let axes: Vec<_> = index
+            .referrers_of::<Axis2Placement3d_, _>(&s, Id::<CartesianPoint_>::new(1))
+            .map(|(i, e)| (i.0, Axis2Placement3d_::try_from_entity(e).unwrap().axis.is_some()))
+            .collect();
+        assert_eq!(axes, vec![(3, true), (5, false)]);
+
+        // Supertypes match their subtypes, as in `instances_of`
+        let placements: Vec<_> = index
+            .referrers_of::<Placement_, _>(&s, Id::<CartesianPoint_>::new(1))
+            .map(|(i, _)| i.0)
+            .collect();
+        assert_eq!(placements, vec![3, 5]);
+        assert_eq!(index
+            .referrers_of::<VertexPoint_, _>(&s, Id::<CartesianPoint_>::new(1))
+            .count(), 1);
+    }
+}
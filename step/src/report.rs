@@ -0,0 +1,85 @@
+use nom::error::ErrorKind;
+
+use crate::step_file::StepFile;
+
+/// A single entity which could not be parsed by the generated parser
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct ParseFailure {
+    /// Entity id, if the `#id=` prefix could be read
+    pub id: Option<usize>,
+    /// Byte offset of the entity within the flattened data given to
+    /// [`StepFile::parse`]
+    pub flat_offset: usize,
+    /// Byte offset and 1-indexed line within the original file, which are
+    /// filled in by [`ParseReport::locate`]
+    pub offset: Option<usize>,
+    pub line: Option<usize>,
+    /// Error returned by the entity parser
+    pub kind: ErrorKind,
+    /// Whether the fallback parser recovered the id, in which case the
+    /// entity is stored as `Entity::_FailedToParse`.  Otherwise, the entity
+    /// is dropped entirely.
+    pub fallback: bool,
+}
+
+/// Diagnostics collected while parsing a file
+#[derive(Debug, Default)]
+pub struct ParseReport {
+    /// Failed entities, sorted by `flat_offset`
+    pub failures: Vec<ParseFailure>,
+}
+
+impl ParseReport {
+    /// Fills in `offset` and `line` for every failure, given the original
+    /// (un-flattened) file from which the parsed data was built.
+    pub fn locate(&mut self, original: &[u8]) {
+        let mut failures = self.failures.iter_mut().peekable();
+        let mut flat_offset = 0;
+        let mut line = 1;
+        let mut line_checked = 0;
+        StepFile::flatten_walk(original, |i, _c| {
+            while let Some(f) = failures.next_if(|f| f.flat_offset == flat_offset) {
+                line += original[line_checked..i].iter()
+                    .filter(|c| **c == b'\n')
+                    .count();
+                line_checked = i;
+                f.offset = Some(i);
+                f.line = Some(line);
+            }
+            flat_offset += 1;
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_report() {
+        let data = b"ISO-10303-21;\nHEADER;\nENDSEC;\nDATA;\n\
+            #1=CARTESIAN_POINT('',(0.,0.,0.));\n\
+            /* a comment */\n\
+            #2=CARTESIAN_POINT('',(0.,0.,\n    0.,NOPE));\n\
+            #3=DIRECTION('',(1.,0.,0.));\n\
+            BOGUS;\n\
+            ENDSEC;\nEND-ISO-10303-21;\n";
+        let flat = StepFile::strip_flatten(data);
+        let (s, mut report) = StepFile::parse(&flat);
+        report.locate(data);
+
+        assert_eq!(report.failures.len(), 2);
+        let f = &report.failures[0];
+        assert_eq!(f.id, Some(2));
+        assert!(f.fallback);
+        assert_eq!(f.line, Some(7));
+        assert_eq!(&data[f.offset.unwrap()..][..3], b"#2=");
+        assert!(matches!(s.0[2], crate::ap214::Entity::_FailedToParse));
+
+        let f = &report.failures[1];
+        assert_eq!(f.id, None);
+        assert!(!f.fallback);
+        assert_eq!(f.line, Some(10));
+        assert_eq!(&data[f.offset.unwrap()..][..5], b"BOGUS");
+    }
+}
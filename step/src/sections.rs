@@ -0,0 +1,380 @@
+use std::borrow::Cow;
+use std::collections::HashMap;
+use nom::{
+    bytes::complete::{tag, take_till},
+    character::complete::char,
+    combinator::map,
+    error::ErrorKind,
+    sequence::{delimited, tuple},
+};
+use log::warn;
+
+use crate::{
+    Error,
+    decompress::decompress,
+    id::Id,
+    owned::IntoStatic,
+    parse::{IResult, Parse, nom_err, param_from_chunks},
+    step_file::StepFile,
+    write::Write,
+};
+
+/// An entry in the `ANCHOR` section of an edition 3 file, which gives an
+/// entity a name that other files can refer to, e.g. `<bolt>=#12;`
+#[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct Anchor<'a> {
+    pub name: Cow<'a, str>,
+    pub id: usize,
+}
+
+/// An entry in the `REFERENCE` section of an edition 3 file, which stands in
+/// for an entity in another file, e.g. `#12=<bolt.stp#head>;`
+///
+/// Other entities refer to `#12` as usual, but it has no entity of its own
+/// (so its slot in [`StepFile`] is `_EmptySlot`).
+#[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct ExternalRef<'a> {
+    pub id: usize,
+    /// The referenced file, which is empty for references within this file
+    pub uri: Cow<'a, str>,
+    /// Name of an anchor in the referenced file
+    pub anchor: Option<Cow<'a, str>>,
+}
+
+/// A `DATA` section.  Edition 3 files may have several, each with a name
+/// and its own schema; older files have one, with neither.
+#[derive(Debug, Clone, Default, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct DataSection<'a> {
+    pub name: Cow<'a, str>,
+    pub schema: Vec<Cow<'a, str>>,
+    /// Ids of the entities in this section, in file order
+    pub ids: Vec<usize>,
+}
+
+impl DataSection<'_> {
+    /// Checks whether this is a plain `DATA;` section, without parameters
+    pub fn is_plain(&self) -> bool {
+        self.name.is_empty() && self.schema.is_empty()
+    }
+
+    /// Writes the opening block of the section
+    pub(crate) fn write(&self, out: &mut String) {
+        if self.is_plain() {
+            out.push_str("DATA;\n");
+        } else {
+            out.push_str("DATA(");
+            self.name.write(out);
+            out.push(',');
+            self.schema.write(out);
+            out.push_str(");\n");
+        }
+    }
+}
+
+impl<'a> IntoStatic for Anchor<'a> {
+    type Static = Anchor<'static>;
+    fn into_static(self) -> Self::Static {
+        Anchor { name: self.name.into_static(), id: self.id }
+    }
+}
+impl<'a> IntoStatic for ExternalRef<'a> {
+    type Static = ExternalRef<'static>;
+    fn into_static(self) -> Self::Static {
+        ExternalRef {
+            id: self.id,
+            uri: self.uri.into_static(),
+            anchor: self.anchor.into_static(),
+        }
+    }
+}
+impl<'a> IntoStatic for DataSection<'a> {
+    type Static = DataSection<'static>;
+    fn into_static(self) -> Self::Static {
+        DataSection {
+            name: self.name.into_static(),
+            schema: self.schema.into_static(),
+            ids: self.ids,
+        }
+    }
+}
+
+/// Checks whether a block opens a `DATA` section, with or without parameters
+pub(crate) fn is_data_start(b: &[u8]) -> bool {
+    b == b"DATA;" || b.starts_with(b"DATA(")
+}
+
+fn uri(s: &str) -> IResult<'_, &str> {
+    delimited(char('<'), take_till(|c| c == '>'), char('>'))(s)
+}
+
+fn from_utf8(s: &[u8]) -> IResult<'_, &str> {
+    match std::str::from_utf8(s) {
+        Ok(s) => Ok(("", s)),
+        Err(_) => nom_err("", ErrorKind::Escaped),
+    }
+}
+
+/// Parses a block from the `ANCHOR` section.  Anchors may also name literal
+/// values, which aren't supported (and return an error).
+pub(crate) fn parse_anchor(s: &[u8]) -> IResult<'_, Anchor<'_>> {
+    let (_, s) = from_utf8(s)?;
+    map(tuple((uri, char('='), Id::<()>::parse, char(';'))),
+        |(name, _, i, _)| Anchor { name: Cow::Borrowed(name), id: i.0 })(s)
+}
+
+/// Parses a block from the `REFERENCE` section
+pub(crate) fn parse_reference(s: &[u8]) -> IResult<'_, ExternalRef<'_>> {
+    let (_, s) = from_utf8(s)?;
+    map(tuple((Id::<()>::parse, char('='), uri, char(';'))),
+        |(i, _, u, _)| {
+            let (uri, anchor) = match u.split_once('#') {
+                Some((uri, anchor)) => (uri, Some(Cow::Borrowed(anchor))),
+                None => (u, None),
+            };
+            ExternalRef { id: i.0, uri: Cow::Borrowed(uri), anchor }
+        })(s)
+}
+
+/// Parses the opening block of a `DATA` section, i.e. `DATA;` or
+/// `DATA('name',('SCHEMA'));`
+pub(crate) fn parse_data_section(s: &[u8]) -> IResult<'_, DataSection<'_>> {
+    let (_, s) = from_utf8(s)?;
+    if s == "DATA;" {
+        return Ok(("", DataSection::default()));
+    }
+    let strs = [s];
+    let mut i = 0;
+    let (s, _) = tag("DATA(")(s)?;
+    let (s, name) = param_from_chunks::<Cow<str>>(false, s, &mut i, &strs)?;
+    let (s, schema) = param_from_chunks::<Vec<Cow<str>>>(true, s, &mut i, &strs)?;
+    let (s, _) = char(';')(s)?;
+    Ok((s, DataSection { name, schema, ids: Vec::new() }))
+}
+
+/// Writes the `ANCHOR` and `REFERENCE` sections, if they're not empty
+pub(crate) fn write_anchors(anchors: &[Anchor], refs: &[ExternalRef], out: &mut String) {
+    if !anchors.is_empty() {
+        out.push_str("ANCHOR;\n");
+        for a in anchors {
+            out.push_str(&format!("<{}>=#{};\n", a.name, a.id));
+        }
+        out.push_str("ENDSEC;\n");
+    }
+    if !refs.is_empty() {
+        out.push_str("REFERENCE;\n");
+        for r in refs {
+            match &r.anchor {
+                Some(a) => out.push_str(&format!("#{}=<{}#{}>;\n", r.id, r.uri, a)),
+                None => out.push_str(&format!("#{}=<{}>;\n", r.id, r.uri)),
+            }
+        }
+        out.push_str("ENDSEC;\n");
+    }
+}
+
+////////////////////////////////////////////////////////////////////////////////
+
+/// A file loaded to resolve external references, with its entities shifted
+/// up by `base` in the combined output
+struct Loaded {
+    file: StepFile<'static>,
+    base: usize,
+}
+
+/// Builds a single file from `root` and every file that it (transitively)
+/// refers to, using `load` to read each file by URI.
+///
+/// Each file is loaded once, even if it's referenced many times (or there
+/// are cycles), and all of its entities are appended with shifted ids.  Each
+/// reference is then replaced by the entity that its anchor names.
+pub(crate) fn resolve<F>(root: &StepFile, mut load: F) -> Result<Vec<u8>, Error>
+    where F: FnMut(&str) -> std::io::Result<Vec<u8>>
+{
+    let mut files: Vec<Loaded> = Vec::new();
+    let mut by_uri: HashMap<String, usize> = HashMap::new();
+    let mut next = root.0.len();
+
+    // Load files breadth-first, so that each file's references are seen
+    // after it's been added to the list
+    let mut todo: Vec<String> = uris(root).collect();
+    let mut i = 0;
+    while i < todo.len() {
+        let uri = todo[i].clone();
+        i += 1;
+        if by_uri.contains_key(&uri) {
+            continue;
+        }
+        let err = |e: String| Error::Load(uri.clone(), e);
+        let raw = load(&uri).map_err(|e| err(e.to_string()))?;
+        let data = decompress(&raw)?;
+        let flat = StepFile::strip_flatten(&data);
+        let (file, report) = StepFile::parse(&flat);
+        if !report.failures.is_empty() {
+            warn!("{} entities in {} failed to parse", report.failures.len(), uri);
+        }
+        let file = file.into_owned();
+        todo.extend(uris(&file));
+
+        by_uri.insert(uri, files.len());
+        let len = file.0.len();
+        files.push(Loaded { file, base: next });
+        next += len;
+    }
+
+    // Finds the id in the combined output for a reference within `file`
+    let target = |file: &StepFile, base: usize, r: &ExternalRef| -> Result<usize, Error> {
+        let (anchors, base) = if r.uri.is_empty() {
+            (&file.1.anchors, base)
+        } else {
+            let f = &files[by_uri[&r.uri[..]]];
+            (&f.file.1.anchors, f.base)
+        };
+        let name = r.anchor.as_deref().unwrap_or("");
+        anchors.iter()
+            .find(|a| a.name == name)
+            .map(|a| a.id + base)
+            .ok_or_else(|| Error::UnknownAnchor(format!("{}#{}", r.uri, name)))
+    };
+    let map_for = |file: &StepFile, base: usize| -> Result<Vec<usize>, Error> {
+        let mut map: Vec<usize> = (0..file.0.len()).map(|i| i + base).collect();
+        for r in &file.1.references {
+            if r.id >= map.len() {
+                map.resize(r.id + 1, 0);
+            }
+            map[r.id] = target(file, base, r)?;
+        }
+        Ok(map)
+    };
+
+    let mut line = "ISO-10303-21;\n".to_owned();
+    root.1.write(&mut line);
+    write_anchors(&root.1.anchors, &[], &mut line);
+    line.push_str("DATA;\n");
+    let mut out = line.into_bytes();
+    let io = |e: std::io::Error| Error::Load(String::new(), e.to_string());
+    root.write_data(0..root.0.len(), Some(&map_for(root, 0)?), &mut out).map_err(io)?;
+    for f in &files {
+        let map = map_for(&f.file, f.base)?;
+        f.file.write_data(0..f.file.0.len(), Some(&map), &mut out).map_err(io)?;
+    }
+    out.extend_from_slice(b"ENDSEC;\nEND-ISO-10303-21;\n");
+    Ok(out)
+}
+
+/// Returns the other files referenced by a file
+fn uris<'s>(f: &'s StepFile) -> impl Iterator<Item=String> + 's {
+    f.1.references.iter()
+        .filter(|r| !r.uri.is_empty())
+        .map(|r| r.uri.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const MAIN: &[u8] = b"ISO-10303-21;
+HEADER;
+FILE_DESCRIPTION((''),'3;1');
+FILE_NAME('main.stp','',(''),(''),'','','');
+FILE_SCHEMA(('AP242_MANAGED_MODEL_BASED_3D_ENGINEERING_MIM_LF'));
+ENDSEC;
+ANCHOR;
+<origin>=#1;
+ENDSEC;
+REFERENCE;
+#10=<bolt.stp#tip>;
+#11=<#origin>;
+ENDSEC;
+DATA('geometry',('AP242_MANAGED_MODEL_BASED_3D_ENGINEERING_MIM_LF'));
+#1=CARTESIAN_POINT('origin',(0.,0.,0.));
+ENDSEC;
+DATA('topology',('AP242_MANAGED_MODEL_BASED_3D_ENGINEERING_MIM_LF'));
+#2=VERTEX_POINT('',#10);
+#3=VERTEX_POINT('',#11);
+ENDSEC;
+END-ISO-10303-21;
+";
+
+    const BOLT: &[u8] = b"ISO-10303-21;
+HEADER;
+ENDSEC;
+ANCHOR;
+<tip>=#2;
+ENDSEC;
+DATA;
+#1=CARTESIAN_POINT('base',(0.,0.,0.));
+#2=CARTESIAN_POINT('tip',(0.,0.,5.));
+ENDSEC;
+END-ISO-10303-21;
+";
+
+    #[test]
+    fn test_sections() {
+        let flat = StepFile::strip_flatten(MAIN);
+        let (s, report) = StepFile::try_parse(&flat).unwrap();
+        assert!(report.failures.is_empty());
+        assert_eq!(s.1.name, "main.stp");
+        assert_eq!(s.1.anchors, vec![Anchor { name: "origin".into(), id: 1 }]);
+        assert_eq!(s.1.references, vec![
+            ExternalRef { id: 10, uri: "bolt.stp".into(), anchor: Some("tip".into()) },
+            ExternalRef { id: 11, uri: "".into(), anchor: Some("origin".into()) },
+        ]);
+        let names: Vec<_> = s.1.data_sections.iter().map(|d| &d.name).collect();
+        assert_eq!(names, vec!["geometry", "topology"]);
+        assert_eq!(s.1.data_sections[1].ids, vec![2, 3]);
+        assert_eq!(s.1.data_sections[1].schema.len(), 1);
+
+        // The streaming and lazy parsers find the same sections
+        let (t, _) = crate::stream::StepReader::new(MAIN).chunk_size(50).parse().unwrap();
+        assert_eq!(s.1.references, t.1.references);
+        assert_eq!(s.1.data_sections, t.1.data_sections);
+        let lazy = crate::lazy::LazyStepFile::parse(&flat);
+        assert_eq!(s.1.anchors, lazy.header.anchors);
+        assert_eq!(s.1.data_sections, lazy.header.data_sections);
+
+        // Sections survive a round trip through the writer
+        let mut out = Vec::new();
+        s.write(&mut out).unwrap();
+        let flat = StepFile::strip_flatten(&out);
+        let (t, _) = StepFile::parse(&flat);
+        assert_eq!(s.1.anchors, t.1.anchors);
+        assert_eq!(s.1.references, t.1.references);
+        assert_eq!(s.1.data_sections, t.1.data_sections);
+    }
+
+    #[test]
+    fn test_resolve() {
+        let flat = StepFile::strip_flatten(MAIN);
+        let (s, _) = StepFile::parse(&flat);
+        let mut loaded = Vec::new();
+        let (r, report) = s.resolve_references(|uri| {
+            loaded.push(uri.to_owned());
+            match uri {
+                "bolt.stp" => Ok(BOLT.to_vec()),
+                _ => Err(std::io::ErrorKind::NotFound.into()),
+            }
+        }).unwrap();
+        assert_eq!(loaded, vec!["bolt.stp"]);
+
+        // The bolt's entities come after the main file's, so its tip is #6
+        assert!(report.failures.is_empty());
+        assert!(r.1.references.is_empty());
+        let vertex = |i: usize| match &r.0[i] {
+            crate::ap214::Entity::VertexPoint(v) => v.vertex_geometry.0,
+            e => panic!("Unexpected entity {:?}", e),
+        };
+        assert_eq!(vertex(2), 6);
+        assert_eq!(vertex(3), 1);
+        assert!(matches!(&r.0[6], crate::ap214::Entity::CartesianPoint(p) if p.name.0 == "tip"));
+
+        let err = s.resolve_references(|_| Err(std::io::ErrorKind::NotFound.into()));
+        assert!(matches!(err, Err(Error::Load(uri, _)) if uri == "bolt.stp"));
+        let mut bad = r;
+        bad.1.references.push(ExternalRef { id: 20, uri: "".into(), anchor: Some("nope".into()) });
+        assert_eq!(bad.resolve_references(|_| unreachable!()).unwrap_err(),
+                   Error::UnknownAnchor("#nope".to_owned()));
+    }
+}
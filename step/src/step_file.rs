@@ -1,100 +1,423 @@
+use std::borrow::Cow;
+use std::collections::HashMap;
+use std::path::Path;
 use memchr::{memchr, memchr2, memchr_iter};
 use log::warn;
+use nom::error::ErrorKind;
 
 #[cfg(feature = "rayon")]
 use rayon::prelude::*;
 
 use crate::{
-    ap214::Entity,
+    Error,
+    ap214::{Entity, Representation, superclasses_of},
+    assembly::AssemblyTree,
+    decompress::decompress,
+    diff::{Diff, diff},
+    header::{Schema, StepHeader},
     id::Id,
+    merge::{MergeChild, merge},
+    owned::IntoStatic,
     parse::{parse_entity_decl, parse_entity_fallback},
+    pmi::Pmi,
+    presentation::Presentation,
+    properties::ValidationProperties,
+    referrers::ReverseIndex,
+    report::{ParseFailure, ParseReport},
+    sections::{
+        DataSection, is_data_start, parse_anchor, parse_data_section,
+        parse_reference, resolve, write_anchors,
+    },
+    subset::{closure, remap_refs},
+    units::Units,
+    validate::{ValidationReport, validate},
+    write::write_entity_decl,
 };
 
+/// Result of [`StepFile::parse_block`]: the block's offset within the
+/// flattened data, the entity and its id, and a failure record
+pub(crate) type ParsedBlock<'a> = (usize, Option<(usize, Entity<'a>)>, Option<ParseFailure>);
+
+/// Result of [`StepFile::split_sections`]: blocks outside of `DATA` sections,
+/// then the opening block and contents of each `DATA` section
+pub(crate) type Sections<'a, 'b> = (Vec<&'a [u8]>, Vec<(&'a [u8], &'b [&'a [u8]])>);
+
 #[derive(Debug)]
-pub struct StepFile<'a>(pub Vec<Entity<'a>>);
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct StepFile<'a>(
+    /// Entities, indexed by their `#id` (unused ids are `_EmptySlot`)
+    pub Vec<Entity<'a>>,
+    /// Values from the `HEADER` section
+    pub StepHeader<'a>,
+);
 impl<'a> StepFile<'a> {
     /// Parses a STEP file from a raw array of bytes
     /// `data` must be preprocessed by [`strip_flatten`] first
-    pub fn parse(data: &'a [u8]) -> Self {
-        let blocks = Self::into_blocks(&data);
+    ///
+    /// Entities which can't be parsed are recorded in the returned report
+    /// (rather than stopping the parse); see [`ParseReport::locate`] to find
+    /// them in the original file.  If the file is truncated, everything up to
+    /// the last complete entity is parsed; use [`StepFile::try_parse`] to
+    /// reject malformed files instead.
+    ///
+    /// Entities are parsed with the schema named in `FILE_SCHEMA`, although
+    /// files which declare AP203 or AP242 currently fall back to the AP214
+    /// types (with a warning).
+    pub fn parse(data: &'a [u8]) -> (Self, ParseReport) {
+        let mut blocks = Vec::new();
+        if let Err(e) = Self::into_blocks(data, &mut blocks) {
+            warn!("Ignoring end of file: {}", e);
+        }
+        Self::parse_blocks(data, &blocks)
+    }
+
+    /// Strict version of [`StepFile::parse`], which returns an error (rather
+    /// than panicking or silently dropping data) if the file is truncated,
+    /// is missing its `DATA` section, contains an unterminated string or
+    /// unbalanced parentheses, or uses unreasonably large entity ids.
+    ///
+    /// Individual entities which are well-formed but don't match the schema
+    /// are still recorded in the report, as in [`StepFile::parse`].
+    pub fn try_parse(data: &'a [u8]) -> Result<(Self, ParseReport), Error> {
+        let mut blocks = Vec::new();
+        Self::into_blocks(data, &mut blocks)?;
+
         let data_start = blocks.iter()
-            .position(|b| b == b"DATA;")
-            .unwrap_or(0) + 1;
-        let data_end = blocks.iter()
-            .skip(data_start)
-            .position(|b| b == b"ENDSEC;")
-            .unwrap_or(0) + data_start;
+            .position(|b| is_data_start(b))
+            .ok_or(Error::MissingData)?;
+        if !blocks[data_start..].iter().any(|b| b == b"ENDSEC;") ||
+            blocks.last() != Some(&&b"END-ISO-10303-21;"[..])
+        {
+            return Err(Error::Truncated(data.len()));
+        }
+        for b in &blocks {
+            if !Self::balanced(b) {
+                let offset = b.as_ptr() as usize - data.as_ptr() as usize;
+                return Err(Error::UnbalancedParentheses(offset));
+            }
+        }
+
+        let (out, report) = Self::parse_blocks(data, &blocks);
+        match report.failures.iter().filter_map(|f| f.id).find(|i| *i > data.len()) {
+            Some(i) => Err(Error::IdTooLarge(i)),
+            None => Ok((out, report)),
+        }
+    }
+
+    /// Checks whether parentheses are balanced within a single block,
+    /// ignoring anything inside of a string literal
+    fn balanced(b: &[u8]) -> bool {
+        let mut depth = 0;
+        let mut in_string = false;
+        for c in b {
+            match c {
+                b'\'' => in_string = !in_string,
+                b'(' if !in_string => depth += 1,
+                b')' if !in_string => {
+                    if depth == 0 {
+                        return false;
+                    }
+                    depth -= 1;
+                },
+                _ => (),
+            }
+        }
+        depth == 0
+    }
+
+    fn parse_blocks(data: &'a [u8], blocks: &[&'a [u8]]) -> (Self, ParseReport) {
+        let (other, sections) = Self::split_sections(blocks);
+        let mut header = Self::parse_header(&other);
+        let parse_block = Self::block_parser(&header);
 
         // Parse every block, accumulating a Vec of Results.  We parse in
         // single-threaded mode in WASM builds, because there's no thread
         // pool.
+        let block_vec: Vec<(usize, &[u8])> = sections.iter()
+            .enumerate()
+            .flat_map(|(i, s)| s.1.iter().map(move |b| (i, *b)))
+            .collect();
         let block_iter = {
-            let block_slice = &blocks[data_start..data_end];
             #[cfg(feature = "rayon")]
-            { block_slice.par_iter() }
+            { block_vec.par_iter() }
             #[cfg(not(feature = "rayon"))]
-            { block_slice.iter() }
+            { block_vec.iter() }
         };
 
-        let parsed: Vec<(usize, Entity)> = block_iter
-            .filter_map(|b| parse_entity_decl(*b)
-                .or_else(|e| {
-                    warn!("Failed to parse {}: {:?}",
-                        std::str::from_utf8(b).unwrap_or("[INVALID UTF-8]"),
-                              e);
-                    parse_entity_fallback(*b)
-                })
-                .ok())
-            .map(|b| b.1)
+        let parsed: Vec<_> = block_iter
+            .map(|(_, b)| {
+                // Blocks are slices of `data`, so we can recover their
+                // position with a little pointer math
+                let offset = b.as_ptr() as usize - data.as_ptr() as usize;
+                parse_block(b, offset)
+            })
+            .collect();
+
+        let owners: Vec<usize> = block_vec.iter().map(|b| b.0).collect();
+        let openers: Vec<&[u8]> = sections.iter().map(|s| s.0).collect();
+        header.data_sections = Self::data_sections(&openers, &owners, &parsed, data.len());
+        let (out, report) = Self::collect_entities(parsed, data.len());
+        (Self(out, header), report)
+    }
+
+    /// Picks the parser for entity blocks from the `FILE_SCHEMA` header.
+    ///
+    /// Only the `ap214` module has been generated so far; the `ap203` and
+    /// `ap242` features are reserved for the others, which need their
+    /// long-form EXPRESS schemas.  Until then, files which declare another
+    /// schema are parsed with the AP214 types.
+    fn block_parser(header: &StepHeader) -> fn(&'a [u8], usize) -> ParsedBlock<'a> {
+        match header.schema() {
+            Some(Schema::Ap214) => (),
+            Some(s) => warn!("No module has been generated for {:?}; \
+                              falling back to AP214", s),
+            None => warn!("Unknown schema {:?}; falling back to AP214",
+                          header.schema_identifiers),
+        }
+        Self::parse_block
+    }
+
+    /// Splits blocks into `DATA` sections and everything else.  Edition 3
+    /// files may have several `DATA` sections.
+    ///
+    /// If there's no `DATA` section, every block after the first (up to an
+    /// `ENDSEC;`) is treated as an entity, so that garbage is reported as
+    /// parse failures rather than silently ignored.
+    pub(crate) fn split_sections<'b>(blocks: &'b [&'a [u8]]) -> Sections<'a, 'b> {
+        let section_end = |start: usize| blocks.iter()
+            .skip(start)
+            .position(|b| b == b"ENDSEC;")
+            .map(|i| i + start)
+            .unwrap_or(blocks.len());
+
+        if !blocks.iter().any(|b| is_data_start(b)) {
+            let start = blocks.len().min(1);
+            return (vec![], vec![(b"DATA;", &blocks[start..section_end(start)])]);
+        }
+
+        let mut other = Vec::new();
+        let mut sections = Vec::new();
+        let mut i = 0;
+        while i < blocks.len() {
+            if is_data_start(blocks[i]) {
+                let end = section_end(i + 1);
+                sections.push((blocks[i], &blocks[i + 1..end]));
+                i = end + 1; // Skip the ENDSEC
+            } else {
+                other.push(blocks[i]);
+                i += 1;
+            }
+        }
+        (other, sections)
+    }
+
+    /// Parses the blocks outside of the `DATA` sections, which make up the
+    /// header and the edition 3 `ANCHOR` and `REFERENCE` sections.  Section
+    /// delimiters and the `SIGNATURE` section are ignored.
+    pub(crate) fn parse_header(blocks: &[&'a [u8]]) -> StepHeader<'a> {
+        let mut header = StepHeader::default();
+        let mut section: &[u8] = b"HEADER;";
+        for b in blocks {
+            let r = match (section, *b) {
+                (_, b"ANCHOR;") | (_, b"REFERENCE;") | (_, b"SIGNATURE;") => {
+                    section = b;
+                    continue;
+                },
+                (_, b"ENDSEC;") => {
+                    section = b"HEADER;";
+                    continue;
+                },
+                (b"ANCHOR;", b) => parse_anchor(b).map(|(_, a)| header.anchors.push(a)),
+                (b"REFERENCE;", b) =>
+                    parse_reference(b).map(|(_, r)| header.references.push(r)),
+                (b"SIGNATURE;", _) => continue,
+                (_, b) => header.parse_block(b).map(|_| ()),
+            };
+            if let Err(e) = r {
+                warn!("Failed to parse header {}: {:?}",
+                    std::str::from_utf8(b).unwrap_or("[INVALID UTF-8]"), e);
+            }
+        }
+        header
+    }
+
+    /// Builds the list of `DATA` sections from their opening blocks, given
+    /// the section which owns each parsed block.  Ids beyond `max_allowed`
+    /// are left out, as in [`StepFile::collect_entities`].
+    pub(crate) fn data_sections(openers: &[&'a [u8]], owners: &[usize],
+                                parsed: &[ParsedBlock], max_allowed: usize)
+        -> Vec<DataSection<'a>>
+    {
+        let mut sections: Vec<DataSection> = openers.iter()
+            .map(|b| match parse_data_section(b) {
+                Ok((_, d)) => d,
+                Err(e) => {
+                    warn!("Failed to parse section {}: {:?}",
+                        std::str::from_utf8(b).unwrap_or("[INVALID UTF-8]"), e);
+                    DataSection::default()
+                },
+            })
             .collect();
+        for (owner, p) in owners.iter().zip(parsed) {
+            match p.1 {
+                Some((i, _)) if i <= max_allowed => sections[*owner].ids.push(i),
+                _ => (),
+            }
+        }
+        sections
+    }
 
+    /// Parses a single entity block, which begins at `flat_offset` in the
+    /// flattened data.  Returns the parsed entity (if its id could be read)
+    /// and a failure record (if it couldn't be fully parsed).
+    pub(crate) fn parse_block(b: &'a [u8], flat_offset: usize) -> ParsedBlock<'a> {
+        match parse_entity_decl(b) {
+            Ok((_, p)) => (flat_offset, Some(p), None),
+            Err(e) => {
+                let fallback = parse_entity_fallback(b).ok().map(|b| b.1);
+                let failure = ParseFailure {
+                    id: fallback.as_ref().map(|b| b.0),
+                    flat_offset,
+                    offset: None,
+                    line: None,
+                    kind: match e {
+                        nom::Err::Error(e) | nom::Err::Failure(e) => e.code,
+                        nom::Err::Incomplete(_) => ErrorKind::Complete,
+                    },
+                    fallback: fallback.is_some(),
+                };
+                (flat_offset, fallback, Some(failure))
+            },
+        }
+    }
+
+    /// Stores parsed blocks densely by id.  `max_allowed` is the length of
+    /// the flattened data, which bounds the largest reasonable id.
+    pub(crate) fn collect_entities(parsed: Vec<ParsedBlock<'a>>, max_allowed: usize)
+        -> (Vec<Entity<'a>>, ParseReport)
+    {
+        // Entities are stored densely by id, so a single huge id would
+        // allocate an enormous Vec.  Every entity takes at least one byte, so
+        // ids beyond the file length are treated as parse failures.
+        //
         // Awkward construction because `Entity` is not `Clone`
-        let max_id = parsed.iter().map(|b| b.0).max().unwrap_or(0);
+        let max_id = parsed.iter()
+            .filter_map(|b| b.1.as_ref())
+            .map(|b| b.0)
+            .filter(|i| *i <= max_allowed)
+            .max()
+            .unwrap_or(0);
         let mut out: Vec<Entity> = (0..=max_id)
             .map(|_| Entity::_EmptySlot)
             .collect();
 
-        for p in parsed.into_iter() {
-            out[p.0] = p.1;
+        let mut report = ParseReport::default();
+        for (flat_offset, entity, failure) in parsed.into_iter() {
+            match entity {
+                Some((i, _)) if i > max_allowed => {
+                    report.failures.push(ParseFailure {
+                        id: Some(i),
+                        flat_offset,
+                        offset: None,
+                        line: None,
+                        kind: ErrorKind::TooLarge,
+                        fallback: false,
+                    });
+                    continue;
+                },
+                Some((i, e)) => out[i] = e,
+                None => (),
+            }
+            if let Some(f) = failure {
+                report.failures.push(f);
+            }
         }
+        (out, report)
+    }
+
+    /// Decompresses a gzip-compressed file (e.g. `.stpZ` or `.stp.gz`) or a
+    /// zip archive containing a single file, detected by its magic bytes.
+    /// Uncompressed data is returned unchanged, so this can be called on any
+    /// input before [`strip_flatten`].
+    pub fn decompress(data: &[u8]) -> Result<Cow<'_, [u8]>, Error> {
+        decompress(data)
+    }
 
-        Self(out)
+    /// Reads a file from disk, decompressing it if necessary (see
+    /// [`StepFile::decompress`]).  The result should then be passed to
+    /// [`strip_flatten`].
+    pub fn read_file<P: AsRef<Path>>(path: P) -> std::io::Result<Vec<u8>> {
+        let data = std::fs::read(path)?;
+        match decompress(&data) {
+            Ok(Cow::Borrowed(_)) => Ok(data),
+            Ok(Cow::Owned(d)) => Ok(d),
+            Err(e) => Err(std::io::Error::new(std::io::ErrorKind::InvalidData, e)),
+        }
     }
 
-    /// Flattens a STEP file, removing comments and whitespace
+    /// Flattens a STEP file, removing comments and whitespace (except within
+    /// strings, which are kept as-is apart from line breaks)
     pub fn strip_flatten(data: &[u8]) -> Vec<u8> {
         let mut out = Vec::with_capacity(data.len());
+        Self::flatten_walk(data, |_i, c| out.push(c));
+        out
+    }
+
+    /// Walks through a STEP file, calling `f` with the index and value of
+    /// each byte that is kept by [`strip_flatten`]
+    pub(crate) fn flatten_walk<F: FnMut(usize, u8)>(data: &[u8], mut f: F) {
         let mut i = 0;
+        let mut in_string = false;
         while i < data.len() {
             match data[i] {
+                // Doubled apostrophes toggle twice, so they don't need
+                // special handling
+                b'\'' => {
+                    in_string = !in_string;
+                    f(i, b'\'');
+                },
+                // Line breaks are ignored, even within strings
+                b'\n' | b'\r' => (),
+                c if in_string => f(i, c),
                 b'/' => if i + 1 < data.len() && data[i + 1] == b'*' {
-                    for j in memchr_iter(b'/', &data[i + 2..]) {
-                        if data[i + j + 1] == b'*' {
-                            i += j + 2;
-                            break;
-                        }
-                    }
-                }
-                // TODO: don't skip whitespace inside of strings
+                    // Search for the closing `*/` after the opening `/*`
+                    // (so that `/*/` doesn't close itself).  An unclosed
+                    // comment runs to the end of the file.
+                    let from = (i + 3).min(data.len());
+                    i = memchr_iter(b'/', &data[from..])
+                        .map(|j| from + j)
+                        .find(|j| data[j - 1] == b'*')
+                        .unwrap_or(data.len());
+                } else {
+                    f(i, b'/');
+                },
                 c if c.is_ascii_whitespace() => (),
-                c => out.push(c),
+                c => f(i, c),
             }
             i += 1;
         }
-        out
     }
 
-    /// Splits a STEP file into individual blocks.  The input must be pre-processed
-    /// by [`strip_flatten`] beforehand.
-    fn into_blocks(data: &[u8]) -> Vec<&[u8]> {
-        let mut blocks = Vec::new();
+    /// Splits a STEP file into individual blocks, which are pushed into
+    /// `blocks`.  The input must be pre-processed by [`strip_flatten`]
+    /// beforehand.
+    ///
+    /// If the data ends partway through a block, the complete blocks are kept
+    /// and an error is returned.
+    pub(crate) fn into_blocks(data: &'a [u8], blocks: &mut Vec<&'a [u8]>) -> Result<(), Error> {
         let mut i = 0;
         let mut start = 0;
         while i < data.len() {
-            let next = memchr2(b'\'', b';', &data[i..]).unwrap();
+            let next = match memchr2(b'\'', b';', &data[i..]) {
+                Some(next) => next,
+                None => return Err(Error::Truncated(start)),
+            };
             match data[i + next] {
                 // Skip over quoted blocks
-                b'\'' => i += next + memchr(b'\'', &data[i + next..]).unwrap() + 1,
+                b'\'' => match memchr(b'\'', &data[i + next + 1..]) {
+                    Some(j) => i += next + j + 2,
+                    None => return Err(Error::UnterminatedString(i + next)),
+                },
                 b';' => {
                     blocks.push(&data[start..=(i + next)]);
 
@@ -104,12 +427,253 @@ impl<'a> StepFile<'a> {
                 _ => unreachable!(),
             }
         }
-        blocks
+        Ok(())
+    }
+
+    /// Copies any strings borrowed from the flattened data, returning a file
+    /// which can outlive it (see [`OwnedStepFile`](crate::owned::OwnedStepFile))
+    pub fn into_owned(self) -> StepFile<'static> {
+        StepFile(self.0.into_static(), self.1.into_static())
+    }
+
+    /// Writes the file in STEP Part 21 format
+    ///
+    /// Entities which failed to parse are skipped (with a warning), since
+    /// their original text isn't kept around.
+    ///
+    /// Edition 3 sections are kept.  Entities which aren't in any `DATA`
+    /// section (e.g. because they were added after parsing) are written in
+    /// the last one.
+    pub fn write<W: std::io::Write>(&self, w: &mut W) -> std::io::Result<()> {
+        let sections = &self.1.data_sections;
+        if sections.len() <= 1 && sections.iter().all(|d| d.is_plain()) {
+            return self.write_entities(0..self.0.len(), None, w);
+        }
+
+        let mut seen = vec![false; self.0.len()];
+        for i in sections.iter().flat_map(|d| d.ids.iter()) {
+            if let Some(s) = seen.get_mut(*i) {
+                *s = true;
+            }
+        }
+        let mut line = "ISO-10303-21;\n".to_owned();
+        self.1.write(&mut line);
+        write_anchors(&self.1.anchors, &self.1.references, &mut line);
+        w.write_all(line.as_bytes())?;
+        for (k, d) in sections.iter().enumerate() {
+            line.clear();
+            d.write(&mut line);
+            w.write_all(line.as_bytes())?;
+            let ids = d.ids.iter().cloned().filter(|i| *i < self.0.len());
+            self.write_data(ids, None, w)?;
+            if k == sections.len() - 1 {
+                let rest = (0..self.0.len()).filter(|i| !seen[*i]);
+                self.write_data(rest, None, w)?;
+            }
+            w.write_all(b"ENDSEC;\n")?;
+        }
+        w.write_all(b"END-ISO-10303-21;\n")
+    }
+
+    /// Formats a single entity as a Part 21 instance, e.g.
+    /// `#12=VERTEX_POINT('',#13);`.  Returns `None` if there's no entity
+    /// with that id, or if it failed to parse.
+    pub fn format_entity(&self, i: usize) -> Option<String> {
+        match self.0.get(i)? {
+            Entity::_EmptySlot | Entity::_FailedToParse => None,
+            e => {
+                let mut out = String::new();
+                write_entity_decl(i, e, &mut out);
+                out.truncate(out.trim_end().len());
+                Some(out)
+            },
+        }
+    }
+
+    /// Writes a self-contained file made up of the entities in `roots` and
+    /// everything that they refer to (through [`Entity::upstream`]), with
+    /// the header copied from this file.  Edition 3 anchors and references
+    /// aren't copied, and everything is written in a single `DATA` section.
+    ///
+    /// Entities are renumbered densely from `#1`, preserving their order.
+    /// To extract a single part, pass its `SHAPE_DEFINITION_REPRESENTATION`
+    /// (which pulls in the product and its shape); a brep or any other entity
+    /// also works.
+    pub fn write_subset<W: std::io::Write>(&self, roots: &[usize], w: &mut W)
+        -> std::io::Result<()>
+    {
+        let ids = closure(self, roots);
+        let mut map = vec![0; self.0.len()];
+        for (new, old) in ids.iter().enumerate() {
+            map[*old] = new + 1;
+        }
+        self.write_entities(ids.into_iter(), Some(&map), w)
+    }
+
+    /// Merges several files into a single assembly, under a new top-level
+    /// product named `name`.  Each child's root products are attached with a
+    /// `NEXT_ASSEMBLY_USAGE_OCCURRENCE`, at the child's placement.
+    ///
+    /// Ids are shifted so that files don't collide.  The merged file is
+    /// parsed and returned as an owned file, along with its parse report;
+    /// use [`StepFile::merge_text`] to get the Part 21 text instead.
+    pub fn merge(name: &str, children: &[MergeChild])
+        -> (StepFile<'static>, ParseReport)
+    {
+        let flat = Self::strip_flatten(&merge(name, children));
+        let (s, report) = StepFile::parse(&flat);
+        (s.into_owned(), report)
+    }
+
+    /// Like [`StepFile::merge`], but returns the merged file as Part 21 text,
+    /// which can be written out directly
+    pub fn merge_text(name: &str, children: &[MergeChild]) -> Vec<u8> {
+        merge(name, children)
+    }
+
+    /// Resolves the edition 3 external references in this file, calling
+    /// `load` to read each referenced file by its URI (e.g. from disk, or
+    /// relative to this file's location).  Loaded files are decompressed if
+    /// necessary, and their own references are resolved in turn.
+    ///
+    /// The result is a single owned file, as in [`StepFile::merge`], with
+    /// every referenced file's entities appended (with shifted ids) and each
+    /// reference replaced by the entity that its anchor names.  It's returned
+    /// along with the report from parsing the combined file, and can be
+    /// triangulated as a multi-file assembly.
+    pub fn resolve_references<F>(&self, load: F)
+        -> Result<(StepFile<'static>, ParseReport), Error>
+        where F: FnMut(&str) -> std::io::Result<Vec<u8>>
+    {
+        let flat = Self::strip_flatten(&resolve(self, load)?);
+        let (s, report) = StepFile::parse(&flat);
+        Ok((s.into_owned(), report))
+    }
+
+    /// Writes a complete file with the given entities
+    fn write_entities<W, I>(&self, ids: I, map: Option<&[usize]>, w: &mut W)
+        -> std::io::Result<()>
+        where W: std::io::Write, I: Iterator<Item=usize>
+    {
+        let mut line = "ISO-10303-21;\n".to_owned();
+        self.1.write(&mut line);
+        if map.is_none() {
+            write_anchors(&self.1.anchors, &self.1.references, &mut line);
+        }
+        line.push_str("DATA;\n");
+        w.write_all(line.as_bytes())?;
+        self.write_data(ids, map, w)?;
+        w.write_all(b"ENDSEC;\nEND-ISO-10303-21;\n")
+    }
+
+    /// Writes the given entities (without a header or section delimiters),
+    /// renumbering references with `map` if given
+    pub(crate) fn write_data<W, I>(&self, ids: I, map: Option<&[usize]>, w: &mut W)
+        -> std::io::Result<()>
+        where W: std::io::Write, I: Iterator<Item=usize>
+    {
+        let mut line = String::new();
+        for i in ids {
+            let e = &self.0[i];
+            match e {
+                Entity::_EmptySlot => continue,
+                Entity::_FailedToParse => {
+                    warn!("Skipping #{} when writing (failed to parse)", i);
+                    continue;
+                },
+                _ => (),
+            }
+            line.clear();
+            write_entity_decl(i, e, &mut line);
+            if let Some(map) = map {
+                line = remap_refs(&line, map);
+            }
+            w.write_all(line.as_bytes())?;
+        }
+        Ok(())
     }
 
     pub fn entity<T: FromEntity<'a>>(&'a self, i: Id<T>) -> Option<&'a T> {
         T::try_from_entity(&self.0[i.0])
     }
+
+    /// Iterates over every instance of `T`, including instances of its
+    /// subtypes and complex entities which contain `T`.
+    ///
+    /// Entities are returned as-is, since a subtype can't be borrowed as
+    /// `T`; use [`StepFile::entity`] or `T::try_from_entity` to get at the
+    /// attributes of exact matches.
+    pub fn instances_of<T: FromEntity<'a>>(&'a self)
+        -> impl Iterator<Item=(Id<T>, &'a Entity<'a>)>
+    {
+        // Many entities share a type, so cache the result for each type name
+        let mut cache: HashMap<&str, bool> = HashMap::new();
+        self.0.iter()
+            .enumerate()
+            .filter(move |(_, e)| match e {
+                Entity::ComplexEntity(_) => e.is_instance_of(T::TYPE_NAME),
+                e => match e.type_name() {
+                    Some(t) => *cache.entry(t)
+                        .or_insert_with(|| is_subtype_of(t, T::TYPE_NAME)),
+                    None => false,
+                },
+            })
+            .map(|(i, e)| (Id::new(i), e))
+    }
+
+    /// Checks that every reference in the file points to an entity of the
+    /// declared type, and that there are no reference cycles.
+    ///
+    /// References to entities which failed to parse aren't checked, since
+    /// those are already listed in the [`ParseReport`].
+    pub fn validate(&self) -> Result<(), ValidationReport> {
+        let report = validate(self);
+        if report.problems.is_empty() {
+            Ok(())
+        } else {
+            Err(report)
+        }
+    }
+
+    /// Builds an index which maps each entity to the entities that refer to
+    /// it.  This walks the whole file, so it should be built once and reused.
+    pub fn reverse_index(&self) -> ReverseIndex {
+        ReverseIndex::new(self)
+    }
+
+    /// Compares this file against a newer revision, matching entities by
+    /// their structure rather than their ids.
+    pub fn diff(&self, new: &StepFile) -> Diff {
+        diff(self, new)
+    }
+
+    /// Extracts the product structure (assembly tree) of the file
+    pub fn assembly(&'a self) -> AssemblyTree<'a> {
+        AssemblyTree::new(self)
+    }
+
+    /// Collects presentation data: colors and transparency for each styled
+    /// item, layers, and item names
+    pub fn presentation(&'a self) -> Presentation<'a> {
+        Presentation::new(self)
+    }
+
+    /// Extracts the geometric validation properties (volume, surface area,
+    /// and centroid) recorded for each product and solid
+    pub fn validation_properties(&'a self) -> ValidationProperties<'a> {
+        ValidationProperties::new(self)
+    }
+
+    /// Extracts product manufacturing information (dimensions, geometric
+    /// tolerances, and datums), linked to the faces that it annotates
+    pub fn pmi(&'a self) -> Pmi<'a> {
+        Pmi::new(self)
+    }
+
+    /// Resolves the units used by a representation (via its context)
+    pub fn units(&self, rep: Representation) -> Units {
+        Units::of_representation(self, rep)
+    }
 }
 
 impl<'a, T> std::ops::Index<Id<T>> for StepFile<'a> {
@@ -121,5 +685,213 @@ impl<'a, T> std::ops::Index<Id<T>> for StepFile<'a> {
 }
 
 pub trait FromEntity<'a> {
+    /// Name of the entity type in the schema, e.g. `CARTESIAN_POINT`
+    const TYPE_NAME: &'static str;
     fn try_from_entity(e: &'a Entity<'a>) -> Option<&'a Self>;
 }
+
+/// Read access to the entities in a file, by id.  This is implemented by both
+/// [`StepFile`] and [`LazyStepFile`](crate::lazy::LazyStepFile), so code
+/// which only follows references (e.g. triangulation) can run on either.
+pub trait Entities<'a>: Sync {
+    /// Returns the number of entity slots (i.e. one more than the largest id)
+    fn len(&self) -> usize;
+
+    fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Returns the entity with the given id, or `None` if it's out of range
+    fn get(&self, i: usize) -> Option<&Entity<'a>>;
+
+    /// Returns the ids of every instance of the type `name`, including
+    /// instances of its subtypes and complex entities which contain it, in
+    /// ascending order
+    fn ids_of_type(&self, name: &str) -> Vec<usize>;
+
+    /// Looks up an entity by id, panicking if it's out of range (as when
+    /// indexing a [`StepFile`])
+    fn by_id<T>(&self, id: Id<T>) -> &Entity<'a> {
+        match self.get(id.0) {
+            Some(e) => e,
+            None => panic!("Entity #{} is out of range (len {})", id.0, self.len()),
+        }
+    }
+
+    /// Looks up an entity by typed id, returning `None` if it's out of range
+    /// or of the wrong type
+    fn entity<'s, T: FromEntity<'s>>(&'s self, i: Id<T>) -> Option<&'s T>
+        where 'a: 's
+    {
+        T::try_from_entity(self.get(i.0)?)
+    }
+}
+
+impl<'a> Entities<'a> for StepFile<'a> {
+    fn len(&self) -> usize {
+        self.0.len()
+    }
+
+    fn get(&self, i: usize) -> Option<&Entity<'a>> {
+        self.0.get(i)
+    }
+
+    fn ids_of_type(&self, name: &str) -> Vec<usize> {
+        let mut cache: HashMap<&str, bool> = HashMap::new();
+        self.0.iter()
+            .enumerate()
+            .filter(|(_, e)| match e.type_name() {
+                Some(t) => *cache.entry(t).or_insert_with(|| is_subtype_of(t, name)),
+                None => e.is_instance_of(name),
+            })
+            .map(|(i, _)| i)
+            .collect()
+    }
+}
+
+/// Checks whether the entity type `sub` is `sup` or one of its subtypes,
+/// following the (possibly multiple) inheritance in [`superclasses_of`]
+pub fn is_subtype_of(sub: &str, sup: &str) -> bool {
+    sub == sup || superclasses_of(sub).iter().any(|s| is_subtype_of(s, sup))
+}
+
+impl<'a> Entity<'a> {
+    /// Checks whether this entity is an instance of the type `name`, either
+    /// directly, through a subtype, or as a member of a complex entity
+    pub fn is_instance_of(&self, name: &str) -> bool {
+        match self {
+            Entity::ComplexEntity(v) => v.iter().any(|e| e.is_instance_of(name)),
+            Entity::_EmptyPartial(t) => is_subtype_of(t, name),
+            e => e.type_name().map(|t| is_subtype_of(t, name)).unwrap_or(false),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rand::{Rng, SeedableRng};
+
+    fn corpus() -> Vec<(String, Vec<u8>)> {
+        let dir = concat!(env!("CARGO_MANIFEST_DIR"), "/fuzz/corpus");
+        let mut out: Vec<_> = std::fs::read_dir(dir).unwrap()
+            .map(|e| e.unwrap().path())
+            .map(|p| (p.file_stem().unwrap().to_string_lossy().into_owned(),
+                      std::fs::read(p).unwrap()))
+            .collect();
+        out.sort();
+        out
+    }
+
+    #[test]
+    fn test_try_parse_corpus() {
+        for (name, data) in corpus() {
+            let flat = StepFile::strip_flatten(&data);
+            let r = StepFile::try_parse(&flat).map(|r| r.0.0.len());
+            match name.as_str() {
+                "empty" | "missing_data" => assert_eq!(r, Err(Error::MissingData)),
+                "truncated" | "missing_end" =>
+                    assert!(matches!(r, Err(Error::Truncated(_))), "{}", name),
+                "unterminated_string" =>
+                    assert!(matches!(r, Err(Error::UnterminatedString(_)))),
+                "unbalanced_parens" | "extra_parens" | "garbage" =>
+                    assert!(matches!(r, Err(Error::UnbalancedParentheses(_))),
+                            "{}", name),
+                "huge_id" => assert_eq!(r, Err(Error::IdTooLarge(99999999999999))),
+                "comments" => assert_eq!(r, Ok(2)),
+                _ => assert!(r.is_ok(), "{}", name),
+            }
+
+            // The lenient parser must not panic either
+            StepFile::parse(&flat);
+        }
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn test_serde_round_trip() {
+        let dir = concat!(env!("CARGO_MANIFEST_DIR"), "/../examples");
+        for p in std::fs::read_dir(dir).unwrap().map(|e| e.unwrap().path()) {
+            let data = std::fs::read(&p).unwrap();
+            let flat = StepFile::strip_flatten(&data);
+            let (s, _) = StepFile::parse(&flat);
+
+            let json = serde_json::to_string(&s).unwrap();
+            let t: StepFile = serde_json::from_str(&json).unwrap();
+            assert_eq!(serde_json::to_string(&t).unwrap(), json, "{:?}", p);
+
+            let (mut a, mut b) = (Vec::new(), Vec::new());
+            s.write(&mut a).unwrap();
+            t.write(&mut b).unwrap();
+            assert!(a == b, "{:?}", p);
+        }
+
+        // References are written as plain ids
+        let flat = StepFile::strip_flatten(b"DATA;#1=VERTEX_POINT('v',#2);ENDSEC;");
+        let (s, _) = StepFile::parse(&flat);
+        assert_eq!(serde_json::to_string(&s.0[1]).unwrap(),
+                   r#"{"VertexPoint":{"representation_item__name":"v","vertex_geometry":2}}"#);
+    }
+
+    #[test]
+    fn test_instances_of() {
+        use crate::ap214::{NamedUnit_, Representation_, ShapeRepresentation_};
+        let data = include_bytes!("../../examples/cube_hole.step");
+        let flat = StepFile::strip_flatten(data);
+        let (s, _) = StepFile::parse(&flat);
+
+        let reps: Vec<_> = s.instances_of::<Representation_>()
+            .map(|(i, e)| (i.0, e.type_name().unwrap()))
+            .collect();
+        assert_eq!(reps.len(), 5);
+        assert!(reps.contains(&(12, "REPRESENTATION")));
+        assert!(reps.iter().any(|r| r.1 == "ADVANCED_BREP_SHAPE_REPRESENTATION"));
+        assert!(reps.iter().any(|r| r.1 == "SHAPE_REPRESENTATION"));
+
+        assert_eq!(s.instances_of::<ShapeRepresentation_>().count(), 2);
+
+        // Units are only found in complex entities
+        let units: Vec<_> = s.instances_of::<NamedUnit_>()
+            .map(|(i, _)| i.0)
+            .collect();
+        assert_eq!(units, vec![221, 222, 223]);
+    }
+
+    #[test]
+    fn test_format_entity() {
+        let (s, _) = StepFile::parse(b"DATA;#1=VERTEX_POINT('v',#3);#3=FOO();ENDSEC;");
+        assert_eq!(s.format_entity(1).unwrap(), "#1=VERTEX_POINT('v',#3);");
+        assert_eq!(s.format_entity(2), None);
+        assert_eq!(s.format_entity(3), None);
+        assert_eq!(s.format_entity(4), None);
+    }
+
+    #[test]
+    fn test_try_parse_fuzz() {
+        let mut rng = rand_chacha::ChaCha8Rng::seed_from_u64(0x57e9);
+        let seeds = corpus().into_iter()
+            .map(|c| c.1)
+            .chain([
+                &include_bytes!("../../examples/cube_hole.step")[..],
+                &include_bytes!("../../examples/cuboid.step")[..],
+            ].iter().map(|d| d.to_vec()))
+            .filter(|d| !d.is_empty())
+            .collect::<Vec<_>>();
+        const SPECIAL: &[u8] = b"'();,#=$*./\\ \n0123456789E-";
+        for _ in 0..2000 {
+            let mut data = seeds[rng.gen_range(0..seeds.len())].clone();
+            for _ in 0..rng.gen_range(1..8) {
+                let i = rng.gen_range(0..data.len());
+                match rng.gen_range(0..4) {
+                    0 => data.truncate(i.max(1)),
+                    1 => data[i] = SPECIAL[rng.gen_range(0..SPECIAL.len())],
+                    2 => data.insert(i, SPECIAL[rng.gen_range(0..SPECIAL.len())]),
+                    _ => data[i] = rng.gen(),
+                }
+            }
+            let flat = StepFile::strip_flatten(&data);
+            let _ = StepFile::try_parse(&flat);
+            StepFile::parse(&flat);
+        }
+    }
+}
@@ -0,0 +1,315 @@
+use std::io::Read;
+use log::warn;
+
+#[cfg(feature = "rayon")]
+use rayon::prelude::*;
+
+use crate::{
+    Error,
+    header::StepHeader,
+    owned::IntoStatic,
+    report::ParseReport,
+    sections::is_data_start,
+    step_file::{ParsedBlock, StepFile},
+};
+
+/// Default number of flattened bytes which are parsed at once
+const DEFAULT_CHUNK_SIZE: usize = 16 << 20;
+
+/// Number of bytes requested from the reader at a time
+const READ_SIZE: usize = 64 << 10;
+
+/// State of [`Flattener`], which may be partway through a comment or string
+/// when it reaches the end of a buffer
+#[derive(Copy, Clone, Debug, PartialEq)]
+enum State {
+    Code,
+    String,
+    /// Just saw a `/`, which may begin a comment
+    Slash,
+    Comment,
+    /// Just saw a `*` within a comment, which may end it
+    CommentStar,
+}
+
+/// Incremental equivalent of [`StepFile::strip_flatten`], which can be fed
+/// a file in arbitrary pieces
+struct Flattener(State);
+
+impl Flattener {
+    fn feed(&mut self, data: &[u8], out: &mut Vec<u8>) {
+        for &c in data {
+            self.0 = match (self.0, c) {
+                (State::Slash, b'*') => State::Comment,
+                (State::Slash, c) => {
+                    out.push(b'/');
+                    Self::code(c, out)
+                },
+                (State::Code, c) => Self::code(c, out),
+                (State::String, b'\'') => {
+                    out.push(c);
+                    State::Code
+                },
+                // Line breaks are ignored, even within strings
+                (State::String, b'\n') | (State::String, b'\r') => State::String,
+                (State::String, c) => {
+                    out.push(c);
+                    State::String
+                },
+                (State::Comment, b'*') | (State::CommentStar, b'*') => State::CommentStar,
+                (State::CommentStar, b'/') => State::Code,
+                (State::Comment, _) | (State::CommentStar, _) => State::Comment,
+            };
+        }
+    }
+
+    fn code(c: u8, out: &mut Vec<u8>) -> State {
+        match c {
+            b'\'' => {
+                out.push(c);
+                State::String
+            },
+            b'/' => State::Slash,
+            c if c.is_ascii_whitespace() => State::Code,
+            c => {
+                out.push(c);
+                State::Code
+            },
+        }
+    }
+
+    /// Flushes a trailing `/` at the end of the file
+    fn finish(&mut self, out: &mut Vec<u8>) {
+        if self.0 == State::Slash {
+            out.push(b'/');
+            self.0 = State::Code;
+        }
+    }
+}
+
+/// Which section of the file we're reading
+enum Section {
+    /// Outside of a `DATA` section, e.g. in the header
+    Other,
+    Data,
+    /// After `END-ISO-10303-21;`
+    Done,
+}
+
+/// Parses a STEP file from any [`std::io::Read`], without holding the whole
+/// file (or its flattened copy) in memory.
+///
+/// Input is flattened and split into blocks as it's read, then parsed in
+/// chunks of roughly `chunk_size` bytes (in parallel, if the `parallel`
+/// feature is enabled).  Parsed entities are copied out of each chunk, so
+/// the resulting [`StepFile`] doesn't borrow from any buffer, and only one
+/// chunk of flattened data is alive at a time.
+///
+/// The result is the same as calling [`StepFile::strip_flatten`] and
+/// [`StepFile::parse`] on the whole file, including the byte offsets in the
+/// [`ParseReport`].
+pub struct StepReader<R> {
+    inner: R,
+    chunk_size: usize,
+}
+
+impl<R: Read> StepReader<R> {
+    pub fn new(inner: R) -> Self {
+        Self { inner, chunk_size: DEFAULT_CHUNK_SIZE }
+    }
+
+    /// Sets the number of flattened bytes to accumulate before parsing
+    pub fn chunk_size(mut self, chunk_size: usize) -> Self {
+        self.chunk_size = chunk_size.max(1);
+        self
+    }
+
+    /// Reads and parses the whole input.  Errors are only returned if the
+    /// reader fails; malformed data is handled as in [`StepFile::parse`].
+    pub fn parse(mut self) -> std::io::Result<(StepFile<'static>, ParseReport)> {
+        let mut raw = vec![0; READ_SIZE];
+        let mut flattener = Flattener(State::Code);
+
+        // Flattened data which hasn't been parsed yet, which begins at
+        // `base` within the complete flattened file
+        let mut flat = Vec::new();
+        let mut base = 0;
+
+        // Splitting state: the end of each complete block in `flat`, and
+        // how far we've scanned for the next one
+        let mut ends = Vec::new();
+        let mut scan = 0;
+        let mut in_string = false;
+
+        // Blocks outside of `DATA` sections are kept until the end, since
+        // edition 3 files may have several `DATA` sections between them.
+        // We also track the `DATA` section which owns each parsed block.
+        let mut section = Section::Other;
+        let mut other: Vec<Vec<u8>> = Vec::new();
+        let mut openers: Vec<Vec<u8>> = Vec::new();
+        let mut owners = Vec::new();
+        let mut parsed = Vec::new();
+        loop {
+            let n = match self.inner.read(&mut raw) {
+                Ok(n) => n,
+                Err(e) if e.kind() == std::io::ErrorKind::Interrupted => continue,
+                Err(e) => return Err(e),
+            };
+            flattener.feed(&raw[..n], &mut flat);
+            if n == 0 {
+                flattener.finish(&mut flat);
+            }
+
+            if let Section::Done = section {
+                // Keep counting bytes, because the total length limits ids
+                base += flat.len();
+                flat.clear();
+                if n == 0 {
+                    break;
+                }
+                continue;
+            }
+
+            for (i, c) in flat.iter().enumerate().skip(scan) {
+                match c {
+                    b'\'' => in_string = !in_string,
+                    b';' if !in_string => ends.push(i + 1),
+                    _ => (),
+                }
+            }
+            scan = flat.len();
+
+            let complete = ends.last().cloned().unwrap_or(0);
+            if complete >= self.chunk_size || (n == 0 && complete > 0) {
+                let mut start = 0;
+                let mut blocks = Vec::new();
+                for end in ends.drain(..) {
+                    let b = &flat[start..end];
+                    match section {
+                        Section::Other if is_data_start(b) => {
+                            openers.push(b.to_vec());
+                            section = Section::Data;
+                        },
+                        Section::Other if b == b"END-ISO-10303-21;" => section = Section::Done,
+                        Section::Other => other.push(b.to_vec()),
+                        Section::Data if b == b"ENDSEC;" => section = Section::Other,
+                        Section::Data => {
+                            blocks.push((base + start, b));
+                            owners.push(openers.len() - 1);
+                        },
+                        Section::Done => (),
+                    }
+                    start = end;
+                }
+                parsed.extend(Self::parse_chunk(&blocks));
+
+                flat.drain(..start);
+                base += start;
+                scan -= start;
+            }
+
+            if n == 0 {
+                break;
+            }
+        }
+
+        if !flat.is_empty() {
+            warn!("Ignoring end of file: {}", Error::Truncated(base));
+        }
+        let len = base + flat.len();
+
+        // Without a DATA section, the parser treats every block after the
+        // first as an entity (so that garbage is reported as failures)
+        let mut header = StepHeader::default();
+        if openers.is_empty() {
+            let mut offset = other.first().map(|b| b.len()).unwrap_or(0);
+            let mut blocks = Vec::new();
+            for b in other.iter().skip(1).take_while(|b| *b != b"ENDSEC;") {
+                blocks.push((offset, &b[..]));
+                offset += b.len();
+            }
+            parsed.extend(Self::parse_chunk(&blocks));
+            owners.resize(parsed.len(), 0);
+            openers.push(b"DATA;".to_vec());
+        } else {
+            let other: Vec<&[u8]> = other.iter().map(|b| &b[..]).collect();
+            header = StepFile::parse_header(&other).into_static();
+        }
+
+        let openers: Vec<&[u8]> = openers.iter().map(|b| &b[..]).collect();
+        header.data_sections = StepFile::data_sections(&openers, &owners, &parsed, len)
+            .into_static();
+        let (out, report) = StepFile::collect_entities(parsed, len);
+        Ok((StepFile(out, header), report))
+    }
+
+    /// Parses a set of blocks (with their offsets in the flattened file),
+    /// copying the results so that they outlive the chunk's buffer
+    fn parse_chunk(blocks: &[(usize, &[u8])]) -> Vec<ParsedBlock<'static>> {
+        let block_iter = {
+            #[cfg(feature = "rayon")]
+            { blocks.par_iter() }
+            #[cfg(not(feature = "rayon"))]
+            { blocks.iter() }
+        };
+        block_iter
+            .map(|(offset, b)| {
+                let (offset, entity, failure) = StepFile::parse_block(b, *offset);
+                (offset, entity.map(|(i, e)| (i, e.into_static())), failure)
+            })
+            .collect()
+    }
+}
+
+impl StepFile<'static> {
+    /// Parses a file from a reader, using a [`StepReader`] with the default
+    /// chunk size.  The input must not be compressed.
+    pub fn from_reader<R: Read>(r: R) -> std::io::Result<(Self, ParseReport)> {
+        StepReader::new(r).parse()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_flattener() {
+        let data: &[u8] = b"ISO-10303-21;\n/* a comment; with 'quotes' */\nDATA;\n\
+            #1 = FOO('it''s /* not\r\n a comment', /**/ 1.0 / 2);/*/ x */\n/";
+        let expected = StepFile::strip_flatten(data);
+        for size in 1..data.len() {
+            let mut f = Flattener(State::Code);
+            let mut out = Vec::new();
+            for c in data.chunks(size) {
+                f.feed(c, &mut out);
+            }
+            f.finish(&mut out);
+            assert_eq!(out, expected, "chunk size {}", size);
+        }
+    }
+
+    #[test]
+    fn test_stream() {
+        for name in ["cube_hole.step", "abstract_pca.step"].iter() {
+            let path = format!("{}/../examples/{}", env!("CARGO_MANIFEST_DIR"), name);
+            let data = std::fs::read(path).unwrap();
+            let flat = StepFile::strip_flatten(&data);
+            let (a, report_a) = StepFile::parse(&flat);
+
+            // Tiny chunks, to exercise the carry-over between them
+            let (b, report_b) = StepReader::new(&data[..])
+                .chunk_size(100)
+                .parse()
+                .unwrap();
+            assert_eq!(report_a.failures, report_b.failures);
+            assert_eq!(a.1.name, b.1.name);
+            assert_eq!(a.0.len(), b.0.len());
+
+            let (mut out_a, mut out_b) = (Vec::new(), Vec::new());
+            a.write(&mut out_a).unwrap();
+            b.write(&mut out_b).unwrap();
+            assert!(out_a == out_b);
+        }
+    }
+}
@@ -0,0 +1,114 @@
+use log::warn;
+
+use crate::{
+    ap214::Entity,
+    step_file::StepFile,
+};
+
+/// Returns `roots` plus every entity that they refer to (directly or
+/// indirectly), in ascending order.  Dangling references and entities which
+/// failed to parse are left out, with a warning.
+pub(crate) fn closure(s: &StepFile, roots: &[usize]) -> Vec<usize> {
+    let mut seen = vec![false; s.0.len()];
+    let mut out = closure_into(s, &mut seen, roots);
+    out.sort_unstable();
+    out
+}
+
+/// Like [`closure`], but with a `seen` buffer which is shared between calls.
+/// Entities which are already marked (and so everything below them, from an
+/// earlier call) are skipped, so a series of calls is linear in the size of
+/// the file.  Returns the newly marked entities, in no particular order.
+pub(crate) fn closure_into(s: &StepFile, seen: &mut [bool], roots: &[usize]) -> Vec<usize> {
+    let mut out = Vec::new();
+    let mut todo = roots.to_vec();
+    while let Some(i) = todo.pop() {
+        match s.0.get(i) {
+            Some(_) if seen[i] => (),
+            None | Some(Entity::_EmptySlot) => warn!("Skipping missing entity #{}", i),
+            Some(Entity::_FailedToParse) => warn!("Skipping #{} (failed to parse)", i),
+            Some(e) => {
+                seen[i] = true;
+                out.push(i);
+                todo.extend(e.upstream().into_iter().filter(|j| *j != 0));
+            },
+        }
+    }
+    out
+}
+
+/// Rewrites every `#id` in a line of Part 21 output using `map`, skipping
+/// string literals.  Ids which aren't in the map become `$` (NUL).
+pub(crate) fn remap_refs(line: &str, map: &[usize]) -> String {
+    replace_refs(line, |i, out| match map.get(i) {
+        Some(n) if *n != 0 => out.push_str(&format!("#{}", n)),
+        _ => out.push('$'),
+    })
+}
+
+/// Copies a line of Part 21 output, calling `f` to write a replacement for
+/// each `#id` outside of string literals
+pub(crate) fn replace_refs<F: FnMut(usize, &mut String)>(line: &str, mut f: F) -> String {
+    let mut out = String::with_capacity(line.len());
+    let mut chars = line.char_indices().peekable();
+    let mut in_string = false;
+    while let Some((i, c)) = chars.next() {
+        if c == '\'' {
+            // Doubled apostrophes toggle twice, so they need no special case
+            in_string = !in_string;
+        } else if c == '#' && !in_string {
+            let mut end = i + 1;
+            while let Some((j, _)) = chars.next_if(|(_, d)| d.is_ascii_digit()) {
+                end = j + 1;
+            }
+            match line[i + 1..end].parse::<usize>() {
+                Ok(n) => f(n, &mut out),
+                Err(_) => out.push('$'),
+            }
+            continue;
+        }
+        out.push(c);
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_remap_refs() {
+        let map = [0, 0, 1, 0, 2];
+        assert_eq!(remap_refs("#4=FOO('#2',#2,(#3,#4));", &map),
+                   "#2=FOO('#2',#1,($,#2));");
+        assert_eq!(remap_refs("#2=BAR('it''s #4',#4);", &map),
+                   "#1=BAR('it''s #4',#2);");
+    }
+
+    #[test]
+    fn test_subset() {
+        let flat = StepFile::strip_flatten(include_bytes!("../../examples/cube_hole.step"));
+        let (s, _) = StepFile::parse(&flat);
+
+        // Pick out the brep, then check that the subset is dense and valid
+        let brep = s.0.iter()
+            .position(|e| matches!(e, Entity::ManifoldSolidBrep(_)))
+            .unwrap();
+        let ids = closure(&s, &[brep]);
+        assert!(ids.len() < s.0.len() - 1);
+
+        let mut out = Vec::new();
+        s.write_subset(&[brep], &mut out).unwrap();
+        let flat = StepFile::strip_flatten(&out);
+        let (t, report) = StepFile::try_parse(&flat).unwrap();
+        assert!(report.failures.is_empty());
+        assert!(t.validate().is_ok(), "{:?}", t.validate());
+        assert_eq!(t.0.len(), ids.len() + 1);
+        assert!(t.0[1..].iter().all(|e| !matches!(e, Entity::_EmptySlot)));
+        assert_eq!(t.1.name, s.1.name);
+
+        // Entities keep their relative order
+        let new = ids.iter().position(|i| *i == brep).unwrap() + 1;
+        assert!(matches!(t.0[new], Entity::ManifoldSolidBrep(_)));
+    }
+}
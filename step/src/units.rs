@@ -0,0 +1,323 @@
+use log::warn;
+
+use crate::{
+    ap214::{
+        Entity, ConversionBasedUnit_, DerivedUnit_, DerivedUnitElement_,
+        GlobalUnitAssignedContext_, MeasureValue,
+        Representation, RepresentationContext, SiPrefix, SiUnitName, SiUnit_,
+        Unit,
+    },
+    step_file::{Entities, FromEntity},
+};
+
+/// Scale factors from the units of a representation context into SI units
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct Units {
+    /// Metres per length unit
+    pub length: f64,
+    /// Radians per plane angle unit
+    pub plane_angle: f64,
+    /// Steradians per solid angle unit
+    pub solid_angle: f64,
+}
+
+impl Default for Units {
+    /// Millimetres and radians, which is what we assumed before reading units
+    fn default() -> Self {
+        Self { length: 1e-3, plane_angle: 1.0, solid_angle: 1.0 }
+    }
+}
+
+/// Common length units, for callers which want output in a particular unit
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum LengthUnit {
+    Micrometre,
+    Millimetre,
+    Centimetre,
+    Metre,
+    Inch,
+    Foot,
+}
+
+impl LengthUnit {
+    /// Metres per unit
+    pub fn metres(self) -> f64 {
+        match self {
+            LengthUnit::Micrometre => 1e-6,
+            LengthUnit::Millimetre => 1e-3,
+            LengthUnit::Centimetre => 1e-2,
+            LengthUnit::Metre => 1.0,
+            LengthUnit::Inch => 0.0254,
+            LengthUnit::Foot => 0.3048,
+        }
+    }
+}
+
+impl std::str::FromStr for LengthUnit {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "um" => Ok(LengthUnit::Micrometre),
+            "mm" => Ok(LengthUnit::Millimetre),
+            "cm" => Ok(LengthUnit::Centimetre),
+            "m" => Ok(LengthUnit::Metre),
+            "in" => Ok(LengthUnit::Inch),
+            "ft" => Ok(LengthUnit::Foot),
+            _ => Err(format!("Unknown length unit '{}'", s)),
+        }
+    }
+}
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+enum Kind {
+    Length,
+    PlaneAngle,
+    SolidAngle,
+}
+
+impl Units {
+    /// Resolves the units assigned to a representation context.  Units
+    /// which aren't assigned (or can't be resolved) keep their defaults.
+    pub fn of_context<'a, S: Entities<'a>>(s: &S, ctx: RepresentationContext) -> Self {
+        let mut out = Self::default();
+        let units = match s.get(ctx.0) {
+            Some(Entity::ComplexEntity(v)) => v.iter()
+                .find_map(GlobalUnitAssignedContext_::try_from_entity),
+            Some(e) => GlobalUnitAssignedContext_::try_from_entity(e),
+            None => None,
+        };
+        for u in units.iter().flat_map(|u| u.units.iter()) {
+            match unit(s, u.0, 0) {
+                Some((Kind::Length, f)) => out.length = f,
+                Some((Kind::PlaneAngle, f)) => out.plane_angle = f,
+                Some((Kind::SolidAngle, f)) => out.solid_angle = f,
+                None => (),
+            }
+        }
+        out
+    }
+
+    /// Resolves the units of a representation's context of items
+    pub fn of_representation<'a, S: Entities<'a>>(s: &S, rep: Representation) -> Self {
+        // Every subtype of REPRESENTATION refers to exactly one context
+        let ctx = s.get(rep.0)
+            .into_iter()
+            .flat_map(|e| e.upstream())
+            .find(|i| s.get(*i)
+                .map(|e| e.is_instance_of("REPRESENTATION_CONTEXT"))
+                .unwrap_or(false));
+        match ctx {
+            Some(c) => Self::of_context(s, RepresentationContext::new(c)),
+            None => {
+                warn!("Could not find context for #{}", rep.0);
+                Self::default()
+            },
+        }
+    }
+}
+
+/// Returns the SI scale of a unit which measures a power of length (e.g.
+/// cubic metres per unit for a volume, with `exponent = 3`).  The unit is
+/// usually a `DERIVED_UNIT` built from length units, but some writers use a
+/// plain length unit, which we take to mean its `exponent`th power.
+pub(crate) fn length_power_scale<'a, S: Entities<'a>>(s: &S, i: usize, exponent: i32) -> Option<f64> {
+    let derived = match s.get(i)? {
+        Entity::ComplexEntity(v) => v.iter().find_map(DerivedUnit_::try_from_entity),
+        e => DerivedUnit_::try_from_entity(e),
+    };
+    let d = match derived {
+        Some(d) => d,
+        None => return match unit(s, i, 0)? {
+            (Kind::Length, f) => Some(f.powi(exponent)),
+            _ => {
+                warn!("Unit #{} is not a length unit", i);
+                None
+            },
+        },
+    };
+    let mut scale = 1.0;
+    let mut total = 0.0;
+    for e in &d.elements {
+        let e = s.get(e.0).and_then(DerivedUnitElement_::try_from_entity)?;
+        match unit(s, e.unit.0, 0)? {
+            (Kind::Length, f) => scale *= f.powf(e.exponent),
+            _ => {
+                warn!("Derived unit #{} is not made from length units", i);
+                return None;
+            },
+        }
+        total += e.exponent;
+    }
+    if (total - f64::from(exponent)).abs() < 1e-9 {
+        Some(scale)
+    } else {
+        warn!("Derived unit #{} has dimension {}, expected {}", i, total, exponent);
+        None
+    }
+}
+
+/// Returns the value and unit of a `MEASURE_WITH_UNIT` (or one of its
+/// common subtypes), which may be part of a complex entity
+pub(crate) fn measure_with_unit<'a>(e: &'a Entity<'a>)
+    -> Option<(&'a MeasureValue<'a>, Unit<'a>)>
+{
+    match e {
+        Entity::ComplexEntity(v) => v.iter().find_map(measure_with_unit),
+        Entity::MeasureWithUnit(m) => Some((&m.value_component, m.unit_component)),
+        Entity::LengthMeasureWithUnit(m) => Some((&m.value_component, m.unit_component)),
+        Entity::PlaneAngleMeasureWithUnit(m) => Some((&m.value_component, m.unit_component)),
+        Entity::AreaMeasureWithUnit(m) => Some((&m.value_component, m.unit_component)),
+        Entity::VolumeMeasureWithUnit(m) => Some((&m.value_component, m.unit_component)),
+        Entity::MeasureRepresentationItem(m) => Some((&m.value_component, m.unit_component)),
+        _ => None,
+    }
+}
+
+/// Returns the value of a `MEASURE_WITH_UNIT` (or one of its subtypes),
+/// converted into SI units
+pub(crate) fn si_value<'a, S: Entities<'a>>(s: &S, e: &Entity) -> Option<f64> {
+    let (value, unit_component) = measure_with_unit(e)?;
+    let (_, value) = measure(value)?;
+    let (_, scale) = unit(s, unit_component.0, 0)?;
+    Some(value * scale)
+}
+
+/// Conversion-based units refer to other units, so `depth` guards against
+/// reference loops
+const MAX_DEPTH: usize = 8;
+
+/// Returns the kind of unit and its scale relative to the SI unit
+fn unit<'a, S: Entities<'a>>(s: &S, i: usize, depth: usize) -> Option<(Kind, f64)> {
+    if depth > MAX_DEPTH {
+        warn!("Unit #{} is nested too deeply", i);
+        return None;
+    }
+    match s.get(i)? {
+        // Units are usually complex entities, e.g. LENGTH_UNIT() alongside
+        // SI_UNIT(..), so we work out the kind from the member with a value
+        Entity::ComplexEntity(v) => v.iter().find_map(|e| unit_entity(s, e, depth)),
+        e => unit_entity(s, e, depth),
+    }
+}
+
+fn unit_entity<'a, S: Entities<'a>>(s: &S, e: &Entity, depth: usize) -> Option<(Kind, f64)> {
+    if let Some(u) = SiUnit_::try_from_entity(e) {
+        si_unit(u)
+    } else if let Some(u) = ConversionBasedUnit_::try_from_entity(e) {
+        let (value, unit_component) = match s.get(u.conversion_factor.0)? {
+            Entity::MeasureWithUnit(m) => (&m.value_component, m.unit_component),
+            Entity::LengthMeasureWithUnit(m) => (&m.value_component, m.unit_component),
+            Entity::PlaneAngleMeasureWithUnit(m) => (&m.value_component, m.unit_component),
+            e => {
+                warn!("Unknown conversion factor {:?}", e);
+                return None;
+            },
+        };
+        let (kind, value) = measure(value)?;
+        let (base, scale) = unit(s, unit_component.0, depth + 1)?;
+        Some((kind.unwrap_or(base), value * scale))
+    } else {
+        None
+    }
+}
+
+fn si_unit(u: &SiUnit_) -> Option<(Kind, f64)> {
+    let kind = match u.name {
+        SiUnitName::Metre => Kind::Length,
+        SiUnitName::Radian => Kind::PlaneAngle,
+        SiUnitName::Steradian => Kind::SolidAngle,
+        _ => return None,
+    };
+    let scale = match u.prefix {
+        None => 1.0,
+        Some(SiPrefix::Exa) => 1e18,
+        Some(SiPrefix::Peta) => 1e15,
+        Some(SiPrefix::Tera) => 1e12,
+        Some(SiPrefix::Giga) => 1e9,
+        Some(SiPrefix::Mega) => 1e6,
+        Some(SiPrefix::Kilo) => 1e3,
+        Some(SiPrefix::Hecto) => 1e2,
+        Some(SiPrefix::Deca) => 1e1,
+        Some(SiPrefix::Deci) => 1e-1,
+        Some(SiPrefix::Centi) => 1e-2,
+        Some(SiPrefix::Milli) => 1e-3,
+        Some(SiPrefix::Micro) => 1e-6,
+        Some(SiPrefix::Nano) => 1e-9,
+        Some(SiPrefix::Pico) => 1e-12,
+        Some(SiPrefix::Femto) => 1e-15,
+        Some(SiPrefix::Atto) => 1e-18,
+        Some(SiPrefix::_Unused(_)) => unreachable!(),
+    };
+    Some((kind, scale))
+}
+
+/// Returns the value of a measure, along with its kind if the measure type
+/// specifies one (otherwise, it's taken from the measure's unit)
+fn measure(m: &MeasureValue) -> Option<(Option<Kind>, f64)> {
+    Some(match m {
+        MeasureValue::LengthMeasure(v) => (Some(Kind::Length), v.0),
+        MeasureValue::NonNegativeLengthMeasure(v) => (Some(Kind::Length), v.0.0),
+        MeasureValue::PositiveLengthMeasure(v) => (Some(Kind::Length), v.0.0.0),
+        MeasureValue::PlaneAngleMeasure(v) => (Some(Kind::PlaneAngle), v.0),
+        MeasureValue::PositivePlaneAngleMeasure(v) => (Some(Kind::PlaneAngle), v.0.0),
+        MeasureValue::SolidAngleMeasure(v) => (Some(Kind::SolidAngle), v.0),
+        MeasureValue::RatioMeasure(v) => (None, v.0),
+        MeasureValue::PositiveRatioMeasure(v) => (None, v.0.0),
+        MeasureValue::NumericMeasure(v) => (None, v.0),
+        m => {
+            warn!("Unknown measure {:?}", m);
+            return None;
+        },
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::step_file::StepFile;
+
+    fn assert_close(a: f64, b: f64) {
+        assert!((a - b).abs() < 1e-12, "{} != {}", a, b);
+    }
+
+    #[test]
+    fn test_units() {
+        let data = b"DATA;\
+            #1=(LENGTH_UNIT()NAMED_UNIT(*)SI_UNIT(.MILLI.,.METRE.));\
+            #2=(NAMED_UNIT(*)PLANE_ANGLE_UNIT()SI_UNIT($,.RADIAN.));\
+            #3=(NAMED_UNIT(*)SI_UNIT($,.STERADIAN.)SOLID_ANGLE_UNIT());\
+            #4=DIMENSIONAL_EXPONENTS(1.,0.,0.,0.,0.,0.,0.);\
+            #5=LENGTH_MEASURE_WITH_UNIT(LENGTH_MEASURE(25.4),#1);\
+            #6=(CONVERSION_BASED_UNIT('INCH',#5)LENGTH_UNIT()NAMED_UNIT(#4));\
+            #7=DIMENSIONAL_EXPONENTS(0.,0.,0.,0.,0.,0.,0.);\
+            #8=PLANE_ANGLE_MEASURE_WITH_UNIT(PLANE_ANGLE_MEASURE(0.0174532925),#2);\
+            #9=(CONVERSION_BASED_UNIT('DEGREE',#8)NAMED_UNIT(#7)PLANE_ANGLE_UNIT());\
+            #10=(GEOMETRIC_REPRESENTATION_CONTEXT(3)GLOBAL_UNIT_ASSIGNED_CONTEXT((#6,#9,#3))REPRESENTATION_CONTEXT('',''));\
+            #11=(GEOMETRIC_REPRESENTATION_CONTEXT(3)GLOBAL_UNIT_ASSIGNED_CONTEXT((#1,#2,#3))REPRESENTATION_CONTEXT('',''));\
+            #12=SHAPE_REPRESENTATION('',(),#10);\
+            #13=ADVANCED_BREP_SHAPE_REPRESENTATION('',(),#11);\
+            #14=GEOMETRIC_REPRESENTATION_CONTEXT('','',3);\
+            #15=SHAPE_REPRESENTATION('',(),#14);\
+            ENDSEC;";
+        let (s, _) = StepFile::parse(data);
+
+        let u = s.units(Representation::new(12));
+        assert_close(u.length, 0.0254);
+        assert_close(u.plane_angle, 0.0174532925);
+        assert_close(u.solid_angle, 1.0);
+
+        let u = s.units(Representation::new(13));
+        assert_close(u.length, 1e-3);
+        assert_close(u.plane_angle, 1.0);
+
+        // No units are assigned, so we fall back to the defaults
+        assert_eq!(s.units(Representation::new(15)), Units::default());
+    }
+
+    #[test]
+    fn test_length_unit() {
+        assert_eq!("in".parse(), Ok(LengthUnit::Inch));
+        assert!("furlong".parse::<LengthUnit>().is_err());
+        assert_close(LengthUnit::Foot.metres() / LengthUnit::Inch.metres(), 12.0);
+    }
+}
@@ -0,0 +1,179 @@
+use crate::{
+    ap214::Entity,
+    id::{HasId, IdVisitor},
+    referrers::upstream_ids,
+    step_file::StepFile,
+};
+
+/// A single problem found by [`StepFile::validate`]
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub enum Problem {
+    /// `from` refers to `to`, which isn't defined in the file
+    DanglingReference { from: usize, to: usize },
+    /// `from` refers to `to`, which isn't an instance of any of the
+    /// `expected` types (or their subtypes).  `found` is `None` for complex
+    /// entities.
+    TypeMismatch {
+        from: usize,
+        to: usize,
+        expected: &'static [&'static str],
+        found: Option<&'static str>,
+    },
+    /// Entities which refer to each other in a loop, in reference order
+    Cycle(Vec<usize>),
+}
+
+/// Problems collected by [`StepFile::validate`]
+#[derive(Debug, Default)]
+pub struct ValidationReport {
+    /// Reference problems (sorted by source entity), then cycles
+    pub problems: Vec<Problem>,
+}
+
+/// Collects every reference along with its declared target types
+struct Refs(Vec<(usize, &'static [&'static str])>);
+impl IdVisitor for Refs {
+    fn visit(&mut self, id: usize, targets: &'static [&'static str]) {
+        self.0.push((id, targets));
+    }
+}
+
+pub(crate) fn validate(s: &StepFile) -> ValidationReport {
+    let mut report = ValidationReport::default();
+    let mut refs = Refs(Vec::new());
+    for (from, e) in s.0.iter().enumerate() {
+        refs.0.clear();
+        e.append_ids(&mut refs);
+        refs.0.sort_by_key(|r| r.0);
+
+        let start = report.problems.len();
+        for &(to, expected) in &refs.0 {
+            let p = match s.0.get(to) {
+                // NUL reference
+                _ if to == 0 => continue,
+                None | Some(Entity::_EmptySlot) =>
+                    Problem::DanglingReference { from, to },
+                // Already recorded in the ParseReport
+                Some(Entity::_FailedToParse) => continue,
+                Some(t) => if expected.is_empty() ||
+                    expected.iter().any(|n| t.is_instance_of(n))
+                {
+                    continue;
+                } else {
+                    Problem::TypeMismatch {
+                        from, to, expected, found: t.type_name(),
+                    }
+                },
+            };
+            // Skip repeats of the same problem from a single entity
+            if report.problems.len() == start || report.problems.last() != Some(&p) {
+                report.problems.push(p);
+            }
+        }
+    }
+    find_cycles(s, &mut report);
+    report
+}
+
+/// Runs an iterative depth-first search over references, recording a cycle
+/// for every back edge that's found.
+fn find_cycles(s: &StepFile, report: &mut ValidationReport) {
+    #[derive(Copy, Clone, PartialEq)]
+    enum Mark { New, Active, Done }
+
+    let n = s.0.len();
+    let upstream: Vec<Vec<usize>> = s.0.iter().map(|e| upstream_ids(e, n)).collect();
+
+    let mut mark = vec![Mark::New; n];
+    let mut stack: Vec<(usize, usize)> = Vec::new();
+    for root in 0..n {
+        if mark[root] != Mark::New {
+            continue;
+        }
+        mark[root] = Mark::Active;
+        stack.push((root, 0));
+        while let Some((node, child)) = stack.last_mut() {
+            match upstream[*node].get(*child) {
+                Some(&next) => {
+                    *child += 1;
+                    match mark[next] {
+                        Mark::New => {
+                            mark[next] = Mark::Active;
+                            stack.push((next, 0));
+                        },
+                        Mark::Active => {
+                            let i = stack.iter()
+                                .position(|(j, _)| *j == next)
+                                .unwrap();
+                            report.problems.push(Problem::Cycle(
+                                stack[i..].iter().map(|(j, _)| *j).collect()));
+                        },
+                        Mark::Done => (),
+                    }
+                },
+                None => {
+                    mark[*node] = Mark::Done;
+                    stack.pop();
+                },
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_validate() {
+        let data = b"DATA;\
+            #1=CARTESIAN_POINT('',(0.,0.,0.));\
+            #2=DIRECTION('',(0.,0.,1.));\
+            #3=AXIS2_PLACEMENT_3D('',#2,#99,$);\
+            #4=VERTEX_POINT('',#1);\
+            #5=EDGE_CURVE('',#4,#6,#7,.T.);\
+            #7=VERTEX_POINT('',#8);\
+            #8=VERTEX_POINT('',#7);\
+            ENDSEC;";
+        let (s, _) = StepFile::parse(data);
+        let problems = s.validate().unwrap_err().problems;
+        assert_eq!(problems, vec![
+            Problem::TypeMismatch {
+                from: 3, to: 2,
+                expected: &["CARTESIAN_POINT"],
+                found: Some("DIRECTION"),
+            },
+            Problem::DanglingReference { from: 3, to: 99 },
+            Problem::DanglingReference { from: 5, to: 6 },
+            Problem::TypeMismatch {
+                from: 5, to: 7,
+                expected: &["CURVE"],
+                found: Some("VERTEX_POINT"),
+            },
+            Problem::TypeMismatch {
+                from: 7, to: 8,
+                expected: &["POINT"],
+                found: Some("VERTEX_POINT"),
+            },
+            Problem::TypeMismatch {
+                from: 8, to: 7,
+                expected: &["POINT"],
+                found: Some("VERTEX_POINT"),
+            },
+            Problem::Cycle(vec![7, 8]),
+        ]);
+    }
+
+    #[test]
+    fn test_validate_examples() {
+        for data in [
+            &include_bytes!("../../examples/abstract_pca.step")[..],
+            &include_bytes!("../../examples/cube_hole.step")[..],
+            &include_bytes!("../../examples/cuboid.step")[..],
+        ].iter() {
+            let flat = StepFile::strip_flatten(data);
+            let (s, _) = StepFile::parse(&flat);
+            assert!(s.validate().is_ok(), "{:?}", s.validate());
+        }
+    }
+}
@@ -0,0 +1,322 @@
+use std::borrow::Cow;
+use std::collections::BTreeMap;
+use std::fmt::Write as _;
+use arrayvec::ArrayVec;
+
+use crate::{
+    id::Id,
+    parse::{Logical, Derived, supertype_chain},
+    ap214::{Entity, attributes_of},
+};
+
+////////////////////////////////////////////////////////////////////////////////
+
+/// Writes a value in STEP Part 21 syntax; this is the inverse of `Parse`
+pub(crate) trait Write {
+    fn write(&self, out: &mut String);
+}
+
+impl Write for f64 {
+    fn write(&self, out: &mut String) {
+        // Part 21 requires a decimal point in every real and an uppercase
+        // exponent marker, so we patch up Rust's shortest round-trip format
+        let s = format!("{:?}", self);
+        match s.find('e') {
+            Some(i) => {
+                let (mantissa, exponent) = s.split_at(i);
+                out.push_str(mantissa);
+                if !mantissa.contains('.') {
+                    out.push('.');
+                }
+                out.push('E');
+                out.push_str(&exponent[1..]);
+            },
+            None => out.push_str(&s),
+        }
+    }
+}
+
+impl Write for i64 {
+    fn write(&self, out: &mut String) {
+        write!(out, "{}", self).unwrap();
+    }
+}
+
+impl Write for &str {
+    fn write(&self, out: &mut String) {
+        // Strings may only contain printable ASCII, so everything else is
+        // encoded with `\X2\` (or `\X4\` outside the Basic Multilingual
+        // Plane) control directives.  This is the inverse of `decode_string`.
+        out.push('\'');
+        let mut chars = self.chars().peekable();
+        while let Some(c) = chars.next() {
+            match c {
+                '\'' => out.push_str("''"),
+                '\\' => out.push_str("\\\\"),
+                ' '..='~' => out.push(c),
+                c => {
+                    let wide = c as u32 > 0xFFFF;
+                    out.push_str(if wide { "\\X4\\" } else { "\\X2\\" });
+                    write_hex(c, wide, out);
+                    // Group runs of similar characters into one directive
+                    while let Some(c) = chars.next_if(|n|
+                        !(' '..='~').contains(n) && (*n as u32 > 0xFFFF) == wide)
+                    {
+                        write_hex(c, wide, out);
+                    }
+                    out.push_str("\\X0\\");
+                },
+            }
+        }
+        out.push('\'');
+    }
+}
+
+fn write_hex(c: char, wide: bool, out: &mut String) {
+    if wide {
+        write!(out, "{:08X}", c as u32).unwrap();
+    } else {
+        write!(out, "{:04X}", c as u32).unwrap();
+    }
+}
+
+impl Write for Cow<'_, str> {
+    fn write(&self, out: &mut String) {
+        self.as_ref().write(out);
+    }
+}
+
+impl Write for bool {
+    fn write(&self, out: &mut String) {
+        out.push_str(if *self { ".T." } else { ".F." });
+    }
+}
+
+impl Write for Logical {
+    fn write(&self, out: &mut String) {
+        out.push_str(match self.0 {
+            Some(true) => ".T.",
+            Some(false) => ".F.",
+            None => ".U.",
+        });
+    }
+}
+
+impl<T> Write for Id<T> {
+    fn write(&self, out: &mut String) {
+        // Id 0 is how we store a NUL reference
+        if self.0 == 0 {
+            out.push('$');
+        } else {
+            write!(out, "#{}", self.0).unwrap();
+        }
+    }
+}
+
+impl<T: Write> Write for Option<T> {
+    fn write(&self, out: &mut String) {
+        match self {
+            Some(t) => t.write(out),
+            None => out.push('$'),
+        }
+    }
+}
+
+fn write_list<'b, T: Write + 'b, I: Iterator<Item=&'b T>>(iter: I, out: &mut String) {
+    out.push('(');
+    for (i, t) in iter.enumerate() {
+        if i > 0 {
+            out.push(',');
+        }
+        t.write(out);
+    }
+    out.push(')');
+}
+
+impl<T: Write> Write for Vec<T> {
+    fn write(&self, out: &mut String) {
+        write_list(self.iter(), out);
+    }
+}
+
+impl<T: Write, const CAP: usize> Write for ArrayVec<T, CAP> {
+    fn write(&self, out: &mut String) {
+        write_list(self.iter(), out);
+    }
+}
+
+impl Write for Derived {
+    fn write(&self, out: &mut String) {
+        out.push('*');
+    }
+}
+
+/// Writes a typed parameter (e.g. `LENGTH_MEASURE(1.)`), which is how
+/// non-entity members of a SELECT are disambiguated
+pub(crate) fn write_typed<T: Write>(name: &str, t: &T, out: &mut String) {
+    out.push_str(name);
+    out.push('(');
+    t.write(out);
+    out.push(')');
+}
+
+////////////////////////////////////////////////////////////////////////////////
+
+/// Writes each attribute of an entity (including derived attributes, which
+/// are written as `*`) to its own string.  Keeping attributes separate lets
+/// us split them between the partial entities of a complex entity.
+pub(crate) trait WriteAttrs {
+    fn write_attrs(&self, out: &mut Vec<String>);
+}
+
+pub(crate) fn write_attr<T: Write>(t: &T, out: &mut Vec<String>) {
+    let mut s = String::new();
+    t.write(&mut s);
+    out.push(s);
+}
+
+fn write_params(name: &str, attrs: &[String], out: &mut String) {
+    out.push_str(name);
+    out.push('(');
+    out.push_str(&attrs.join(","));
+    out.push(')');
+}
+
+/// Writes a complete entity instance, e.g. `#12=VERTEX_POINT('',#13);`
+///
+/// `_EmptySlot` and `_FailedToParse` entities have nothing to write, so
+/// callers should skip them.
+pub(crate) fn write_entity_decl(i: usize, e: &Entity, out: &mut String) {
+    write!(out, "#{}=", i).unwrap();
+    match e {
+        Entity::ComplexEntity(v) => write_complex_mapping(v, out),
+        e => {
+            let mut attrs = Vec::new();
+            e.write_attrs(&mut attrs);
+            write_params(e.type_name().expect("Entity has no type name"),
+                         &attrs, out);
+        },
+    }
+    out.push_str(";\n");
+}
+
+/// Inverse of `parse_complex_mapping`: splits the attributes of each leaf
+/// entity among its supertypes, then writes every partial entity in
+/// alphabetical order (as required by Part 21).
+fn write_complex_mapping(leafs: &[Entity], out: &mut String) {
+    let mut subentities: BTreeMap<&str, Vec<String>> = BTreeMap::new();
+    for leaf in leafs {
+        if let Entity::_EmptyPartial(name) = leaf {
+            subentities.entry(name).or_default();
+            continue;
+        }
+        let name = leaf.type_name().expect("Nested complex entity");
+        let mut attrs = Vec::new();
+        leaf.write_attrs(&mut attrs);
+
+        let mut start = 0;
+        for c in supertype_chain(name) {
+            let end = start + own_attribute_count(c);
+            subentities.insert(c, attrs[start..end].to_vec());
+            start = end;
+        }
+    }
+    out.push('(');
+    for (name, attrs) in subentities.iter() {
+        write_params(name, attrs, out);
+    }
+    out.push(')');
+}
+
+/// Returns the number of attributes which an entity declares itself, rather
+/// than inheriting from its supertypes
+pub(crate) fn own_attribute_count(name: &str) -> usize {
+    let chain = supertype_chain(name);
+    let inherited: usize = chain[..chain.len() - 1].iter()
+        .map(|c| own_attribute_count(c))
+        .sum();
+    attributes_of(name).len() - inherited
+}
+
+////////////////////////////////////////////////////////////////////////////////
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::step_file::StepFile;
+
+    fn to_str<T: Write>(t: T) -> String {
+        let mut s = String::new();
+        t.write(&mut s);
+        s
+    }
+
+    #[test]
+    fn test_write_primitives() {
+        assert_eq!(to_str(1.0), "1.0");
+        assert_eq!(to_str(1.0e-7), "1.E-7");
+        assert_eq!(to_str(-2.5e20), "-2.5E20");
+        assert_eq!(to_str("it's"), "'it''s'");
+        assert_eq!(to_str(r"C:\dir"), r"'C:\\dir'");
+        assert_eq!(to_str("Größe"), r"'Gr\X2\00F600DF\X0\e'");
+        assert_eq!(to_str("部品 😀"), r"'\X2\90E854C1\X0\ \X4\0001F600\X0\'");
+        assert_eq!(to_str(Logical(None)), ".U.");
+        assert_eq!(to_str(Id::<()>::empty()), "$");
+        assert_eq!(to_str(vec![Some(Id::<()>::new(3)), None]), "(#3,$)");
+    }
+
+    #[test]
+    fn test_write_complex_entity() {
+        let data = b"DATA;#1=(BOUNDED_CURVE()B_SPLINE_CURVE(2,(#2,#3,#4),.UNSPECIFIED.,.F.,.F.)B_SPLINE_CURVE_WITH_KNOTS((3,3),(0.,1.),.UNSPECIFIED.)CURVE()GEOMETRIC_REPRESENTATION_ITEM()RATIONAL_B_SPLINE_CURVE((1.,0.5,1.))REPRESENTATION_ITEM(''));ENDSEC;";
+        let (s, _) = StepFile::parse(data);
+        let mut out = String::new();
+        write_entity_decl(1, &s.0[1], &mut out);
+        assert_eq!(out, "#1=(BOUNDED_CURVE()B_SPLINE_CURVE(2,(#2,#3,#4),.UNSPECIFIED.,.F.,.F.)B_SPLINE_CURVE_WITH_KNOTS((3,3),(0.0,1.0),.UNSPECIFIED.)CURVE()GEOMETRIC_REPRESENTATION_ITEM()RATIONAL_B_SPLINE_CURVE((1.0,0.5,1.0))REPRESENTATION_ITEM(''));\n");
+    }
+
+    #[test]
+    fn test_write_empty_partials() {
+        let data = b"DATA;\
+            #1=(LENGTH_UNIT()NAMED_UNIT(*)SI_UNIT(.MILLI.,.METRE.));\
+            #2=(CONVERSION_BASED_UNIT('INCH',#3)LENGTH_UNIT()NAMED_UNIT(#4));\
+            #5=(AREA_MEASURE_WITH_UNIT()MEASURE_REPRESENTATION_ITEM()MEASURE_WITH_UNIT(AREA_MEASURE(2.),#6)REPRESENTATION_ITEM('area'));\
+            ENDSEC;";
+        let (s, report) = StepFile::parse(data);
+        assert!(report.failures.is_empty());
+        assert!(s.0[1].is_instance_of("LENGTH_UNIT"));
+        assert!(s.0[2].is_instance_of("LENGTH_UNIT"));
+        assert!(s.0[5].is_instance_of("MEASURE_REPRESENTATION_ITEM"));
+
+        let mut out = String::new();
+        for i in [1, 2, 5].iter() {
+            write_entity_decl(*i, &s.0[*i], &mut out);
+        }
+        assert_eq!(out, "\
+            #1=(LENGTH_UNIT()NAMED_UNIT(*)SI_UNIT(.MILLI.,.METRE.));\n\
+            #2=(CONVERSION_BASED_UNIT('INCH',#3)LENGTH_UNIT()NAMED_UNIT(#4));\n\
+            #5=(AREA_MEASURE_WITH_UNIT()MEASURE_REPRESENTATION_ITEM()MEASURE_WITH_UNIT(AREA_MEASURE(2.0),#6)REPRESENTATION_ITEM('area'));\n");
+    }
+
+    #[test]
+    fn test_round_trip() {
+        for data in [
+            &include_bytes!("../../examples/abstract_pca.step")[..],
+            &include_bytes!("../../examples/cube_hole.step")[..],
+            &include_bytes!("../../examples/cuboid.step")[..],
+        ].iter() {
+            let flat = StepFile::strip_flatten(data);
+            let (a, _) = StepFile::parse(&flat);
+
+            let mut written = Vec::new();
+            a.write(&mut written).unwrap();
+            let flat_written = StepFile::strip_flatten(&written);
+            let (b, _) = StepFile::parse(&flat_written);
+            assert_eq!(format!("{:?}", a), format!("{:?}", b));
+
+            // Partial entities without attributes must survive as well
+            let count = |d: &[u8]| d.windows(7).filter(|w| w == b"_UNIT()").count();
+            assert!(count(&flat) > 0);
+            assert_eq!(count(&flat), count(&flat_written));
+        }
+    }
+}
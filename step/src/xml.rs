@@ -0,0 +1,416 @@
+use std::borrow::Cow;
+use std::collections::HashMap;
+use std::fmt::Write as _;
+use nom::error::ErrorKind;
+use roxmltree::{Document, Node};
+
+use crate::{
+    Error,
+    ap214::{
+        Entity, attributes_of, attribute_kinds_of, defined_type_kind, is_entity_type,
+    },
+    header::StepHeader,
+    parse::supertype_chain,
+    report::{ParseFailure, ParseReport},
+    step_file::StepFile,
+    write::{Write, own_attribute_count},
+};
+
+const HEADER_TAG: &str = "iso_10303_28_header";
+const DATA_START: &str = "ISO-10303-21;HEADER;ENDSEC;DATA;";
+const XSI: &str = "http://www.w3.org/2001/XMLSchema-instance";
+
+/// Converts the entity instances in an ISO 10303-28 document into Part 21
+/// text, which is then handled by the regular parser.
+///
+/// The XML names entities and attributes but not their types, so values are
+/// encoded based on the generated [`attribute_kinds_of`] table.  Instance
+/// `id` strings are mapped to sequential numeric ids, in order of first
+/// appearance (as either an `id` or a `ref`).
+///
+/// Complex instances are elements whose name joins their leaf types with `-`
+/// (e.g. `<Length_unit-Si_unit>`), and are written as Part 21 complex
+/// entities.  Partial types which aren't in the schema are recorded as
+/// failures in the [`ParseReport`].
+#[derive(Default)]
+struct Converter {
+    ids: HashMap<String, usize>,
+    next_id: usize,
+    out: String,
+    /// Id, offset in `out`, and element name of each complex instance
+    complex: Vec<(usize, usize, String)>,
+}
+
+impl Converter {
+    fn id(&mut self, name: &str) -> usize {
+        if let Some(i) = self.ids.get(name) {
+            return *i;
+        }
+        let i = self.fresh_id();
+        self.ids.insert(name.to_owned(), i);
+        i
+    }
+
+    fn fresh_id(&mut self) -> usize {
+        self.next_id += 1;
+        self.next_id
+    }
+
+    /// Writes an entity instance (plus any instances nested within it) and
+    /// returns its id.  Unknown entity types are written without attributes,
+    /// so that they show up as failures in the [`ParseReport`].
+    fn entity(&mut self, node: Node) -> Result<usize, Error> {
+        let i = match node.attribute("id") {
+            Some(s) => self.id(s),
+            None => self.fresh_id(),
+        };
+        let name = node.tag_name().name().to_ascii_uppercase();
+        if name.contains('-') {
+            return self.complex(node, i, &name);
+        }
+        let params = self.params(node, attributes_of(&name), attribute_kinds_of(&name))?;
+        write!(self.out, "#{}={}({});", i, name, params.join(",")).unwrap();
+        Ok(i)
+    }
+
+    /// Writes a complex instance, with one partial entity for each of its
+    /// leaf types and their supertypes.  Each partial has the attributes that
+    /// it declares itself, which are derived if any leaf type derives them.
+    fn complex(&mut self, node: Node, i: usize, name: &str) -> Result<usize, Error> {
+        let mut derived: HashMap<&str, Vec<bool>> = HashMap::new();
+        for leaf in name.split('-') {
+            let kinds = attribute_kinds_of(leaf);
+            let mut k = 0;
+            for p in supertype_chain(leaf) {
+                let n = own_attribute_count(p);
+                let d = derived.entry(p).or_insert_with(|| vec![false; n]);
+                for (j, flag) in d.iter_mut().enumerate() {
+                    *flag |= kinds.get(k + j) == Some(&"*");
+                }
+                k += n;
+            }
+        }
+        let mut parts: Vec<_> = derived.into_iter().collect();
+        parts.sort_unstable();
+        self.complex.push((i, self.out.len(), name.to_owned()));
+
+        let mut out = String::new();
+        for (p, derived) in parts {
+            let start = attributes_of(p).len() - derived.len();
+            let kinds: Vec<&str> = attribute_kinds_of(p)[start..].iter()
+                .zip(derived)
+                .map(|(k, d)| if d { "*" } else { *k })
+                .collect();
+            let params = self.params(node, &attributes_of(p)[start..], &kinds)?;
+            write!(out, "{}({})", p, params.join(",")).unwrap();
+        }
+        write!(self.out, "#{}=({});", i, out).unwrap();
+        Ok(i)
+    }
+
+    /// Encodes a list of attributes with the given kinds, where derived
+    /// attributes have the kind `*`
+    fn params(&mut self, node: Node, attrs: &[&str], kinds: &[&str])
+        -> Result<Vec<String>, Error>
+    {
+        let mut params = Vec::new();
+        for (a, k) in attrs.iter().zip(kinds) {
+            params.push(if *k == "*" {
+                "*".to_owned()
+            } else {
+                // Attributes which were renamed to avoid clashes are stored
+                // as `superclass__name`, but XML uses the original name
+                self.attribute(node, a.rsplit("__").next().unwrap(), k)?
+            });
+        }
+        Ok(params)
+    }
+
+    /// Encodes an attribute, which may be a child element or (for simple
+    /// types) an XML attribute.  Missing attributes are written as `$`.
+    fn attribute(&mut self, node: Node, name: &str, kind: &str) -> Result<String, Error> {
+        if let Some(c) = node.children()
+            .find(|c| c.is_element() && c.tag_name().name().eq_ignore_ascii_case(name))
+        {
+            self.value(c, kind)
+        } else if let Some(a) = node.attributes()
+            .find(|a| a.namespace().is_none() && a.name().eq_ignore_ascii_case(name))
+        {
+            self.text(a.value(), kind)
+        } else {
+            Ok("$".to_owned())
+        }
+    }
+
+    /// Encodes the contents of an attribute element
+    fn value(&mut self, node: Node, kind: &str) -> Result<String, Error> {
+        if let Some(r) = node.attribute("ref") {
+            return Ok(format!("#{}", self.id(r)));
+        } else if node.attribute((XSI, "nil")) == Some("true") {
+            return Ok("$".to_owned());
+        }
+        let child = node.children().find(|c| c.is_element());
+        if let Some(inner) = kind.strip_prefix("list of ") {
+            // Items are either child elements or whitespace-separated text
+            let items = if child.is_some() {
+                node.children()
+                    .filter(|c| c.is_element())
+                    .map(|c| self.item(c, inner))
+                    .collect::<Result<Vec<_>, _>>()?
+            } else {
+                node.text().unwrap_or("")
+                    .split_whitespace()
+                    .map(|t| self.text(t, inner))
+                    .collect::<Result<Vec<_>, _>>()?
+            };
+            Ok(format!("({})", items.join(",")))
+        } else if let Some(c) = child {
+            self.item(c, kind)
+        } else {
+            self.text(node.text().unwrap_or(""), kind)
+        }
+    }
+
+    /// Encodes an element which is itself a value: a list item, a reference
+    /// (or nested instance), or a typed member of a select.
+    fn item(&mut self, node: Node, kind: &str) -> Result<String, Error> {
+        if let Some(r) = node.attribute("ref") {
+            return Ok(format!("#{}", self.id(r)));
+        }
+        let name = node.tag_name().name().to_ascii_uppercase();
+        match kind {
+            "ref" => Ok(format!("#{}", self.entity(node)?)),
+            "select" if node.has_attribute("id") || !attributes_of(&name).is_empty() =>
+                Ok(format!("#{}", self.entity(node)?)),
+            "select" => Ok(format!("{}({})", name, self.typed(node, &name)?)),
+            k if k.starts_with("list of ") => self.value(node, k),
+            _ => self.text(node.text().unwrap_or(""), kind),
+        }
+    }
+
+    /// Encodes the value of a defined type within a select, using the
+    /// generated [`defined_type_kind`] table to find its underlying type
+    fn typed(&mut self, node: Node, name: &str) -> Result<String, Error> {
+        if let Some(c) = node.children().find(|c| c.is_element()) {
+            return self.item(c, "select");
+        }
+        match defined_type_kind(name) {
+            Some(kind) => self.value(node, kind),
+            None => Err(Error::Xml(format!("Unknown type {} in select", name))),
+        }
+    }
+
+    /// Encodes a value which is stored as text
+    fn text(&mut self, t: &str, kind: &str) -> Result<String, Error> {
+        let err = || Error::Xml(format!("Invalid {} value {:?}", kind, t));
+        if kind == "string" {
+            return Ok(Self::write(t));
+        }
+        let t = t.trim();
+        Ok(match kind {
+            "real" => Self::write(t.parse::<f64>().map_err(|_| err())?),
+            "integer" => t.parse::<i64>().map_err(|_| err())?.to_string(),
+            "boolean" | "logical" => match t {
+                "true" | "1" => ".T.",
+                "false" | "0" => ".F.",
+                "unknown" if kind == "logical" => ".U.",
+                _ => return Err(err()),
+            }.to_owned(),
+            "enum" => format!(".{}.", t.to_ascii_uppercase()),
+            _ => return Err(err()),
+        })
+    }
+
+    fn write<T: Write>(t: T) -> String {
+        let mut out = String::new();
+        t.write(&mut out);
+        out
+    }
+}
+
+fn header(node: Node) -> StepHeader<'static> {
+    let text = |tag: &str| -> Vec<Cow<'static, str>> {
+        node.children()
+            .filter(|c| c.is_element() && c.tag_name().name().eq_ignore_ascii_case(tag))
+            .map(|c| Cow::Owned(c.text().unwrap_or("").to_owned()))
+            .collect()
+    };
+    let first = |tag: &str| text(tag).into_iter().next().unwrap_or_default();
+
+    // Part 28 calls the file description `documentation`
+    let mut description = text("description");
+    if description.is_empty() {
+        description = text("documentation");
+    }
+    StepHeader {
+        description,
+        implementation_level: first("implementation_level"),
+        name: first("name"),
+        time_stamp: first("time_stamp"),
+        author: text("author"),
+        organization: text("organization"),
+        preprocessor_version: first("preprocessor_version"),
+        originating_system: first("originating_system"),
+        authorization: first("authorization"),
+        ..StepHeader::default()
+    }
+}
+
+impl StepFile<'static> {
+    /// Parses an ISO 10303-28 (STEP-XML) document, in the default late-bound
+    /// configuration used by e.g. `ifcXML`.
+    ///
+    /// Entities are matched to types by their element names, ignoring case
+    /// and namespaces.  Attributes may be child elements or XML attributes;
+    /// references use the `ref` attribute or nest the referenced instance
+    /// inline.  The schema is taken from the `schema` attribute of each
+    /// `uos` element, if present.
+    ///
+    /// The document is converted into equivalent Part 21 data before parsing,
+    /// so byte offsets in the [`ParseReport`] refer to that data rather than
+    /// the XML.  Returns an error if the XML is malformed or contains values
+    /// which don't match their attribute's type.
+    pub fn parse_xml(xml: &str) -> Result<(Self, ParseReport), Error> {
+        let doc = Document::parse(xml).map_err(|e| Error::Xml(e.to_string()))?;
+
+        let mut c = Converter::default();
+        let mut h = StepHeader::default();
+        let mut schemas = Vec::new();
+        for n in doc.root_element().children().filter(|n| n.is_element()) {
+            if n.tag_name().name().eq_ignore_ascii_case(HEADER_TAG) {
+                h = header(n);
+                continue;
+            }
+            if let Some(s) = n.attribute("schema") {
+                schemas.push(Cow::Owned(s.to_owned()));
+            }
+            for e in n.children().filter(|e| e.is_element()) {
+                c.entity(e)?;
+            }
+        }
+        h.schema_identifiers = schemas;
+
+        let data = format!("{}{}ENDSEC;END-ISO-10303-21;", DATA_START, c.out);
+        let (s, mut report) = StepFile::parse(data.as_bytes());
+        let mut s = s.into_owned();
+
+        // The Part 21 parser keeps partial entities of unknown types by name,
+        // so check that each complex instance only uses types in the schema
+        for (i, offset, name) in c.complex {
+            match s.0.get(i) {
+                Some(Entity::_FailedToParse) | None => continue,
+                Some(_) if name.split('-').all(is_entity_type) => continue,
+                Some(_) => (),
+            }
+            report.failures.push(ParseFailure {
+                id: Some(i),
+                flat_offset: DATA_START.len() + offset,
+                offset: None,
+                line: None,
+                kind: ErrorKind::Tag,
+                fallback: true,
+            });
+            s.0[i] = Entity::_FailedToParse;
+        }
+        report.failures.sort_by_key(|f| f.flat_offset);
+        h.data_sections = std::mem::take(&mut s.1.data_sections);
+        s.1 = h;
+        Ok((s, report))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const XML: &str = r#"<?xml version="1.0" encoding="UTF-8"?>
+<iso_10303_28 xmlns="urn:iso10303-28:ex" xmlns:ex="urn:iso10303-28:ex"
+              xmlns:xsi="http://www.w3.org/2001/XMLSchema-instance" version="2.0">
+  <iso_10303_28_header>
+    <name>cube.stpx</name>
+    <author>Alice</author>
+    <author>Bob</author>
+    <documentation>A cube</documentation>
+    <implementation_level>2;1</implementation_level>
+  </iso_10303_28_header>
+  <uos id="uos_1" schema="AUTOMOTIVE_DESIGN">
+    <Cartesian_point id="p1" Name="origin">
+      <Coordinates>0 0 1.5</Coordinates>
+    </Cartesian_point>
+    <Vertex_point id="v1">
+      <Name>it's a vertex</Name>
+      <Vertex_geometry><Cartesian_point ref="p1" xsi:nil="true"/></Vertex_geometry>
+    </Vertex_point>
+    <Vertex_point id="v2">
+      <Name/>
+      <Vertex_geometry>
+        <Cartesian_point Name="inline">
+          <Coordinates ex:cType="list">
+            <ex:double>1</ex:double><ex:double>2e3</ex:double><ex:double>-3.25</ex:double>
+          </Coordinates>
+        </Cartesian_point>
+      </Vertex_geometry>
+    </Vertex_point>
+    <Oriented_edge id="e1" Name="" Orientation="false">
+      <Edge_element ref="e0"/>
+    </Oriented_edge>
+    <Measure_representation_item id="m1" Name="len">
+      <Value_component><Length_measure>2.5</Length_measure></Value_component>
+      <Unit_component ref="u1"/>
+    </Measure_representation_item>
+    <Surface_style_usage id="s1" Side="both">
+      <Style ref="st1"/>
+    </Surface_style_usage>
+    <Measure_representation_item id="m2" Name="part number">
+      <Value_component><Descriptive_measure>42</Descriptive_measure></Value_component>
+      <Unit_component ref="u1"/>
+    </Measure_representation_item>
+    <Measure_representation_item id="m3" Name="holes">
+      <Value_component><Count_measure>3</Count_measure></Value_component>
+      <Unit_component ref="u1"/>
+    </Measure_representation_item>
+    <Length_unit-Si_unit id="u1" Prefix="milli" Name="metre"/>
+  </uos>
+</iso_10303_28>"#;
+
+    const STEP: &[u8] = b"ISO-10303-21;HEADER;ENDSEC;DATA;
+        #1=CARTESIAN_POINT('origin',(0.,0.,1.5));
+        #2=VERTEX_POINT('it''s a vertex',#1);
+        #3=VERTEX_POINT('',#4);
+        #4=CARTESIAN_POINT('inline',(1.,2000.,-3.25));
+        #5=ORIENTED_EDGE('',*,*,#6,.F.);
+        #7=MEASURE_REPRESENTATION_ITEM('len',LENGTH_MEASURE(2.5),#8);
+        #9=SURFACE_STYLE_USAGE(.BOTH.,#10);
+        #11=MEASURE_REPRESENTATION_ITEM('part number',DESCRIPTIVE_MEASURE('42'),#8);
+        #12=MEASURE_REPRESENTATION_ITEM('holes',COUNT_MEASURE(3.),#8);
+        #8=(LENGTH_UNIT()NAMED_UNIT(*)SI_UNIT(.MILLI.,.METRE.));
+        ENDSEC;END-ISO-10303-21;";
+
+    #[test]
+    fn test_parse_xml() {
+        let (s, report) = StepFile::parse_xml(XML).unwrap();
+        assert!(report.failures.is_empty());
+        assert_eq!(s.1.name, "cube.stpx");
+        assert_eq!(s.1.author, vec!["Alice", "Bob"]);
+        assert_eq!(s.1.description, vec!["A cube"]);
+        assert_eq!(s.1.implementation_level, "2;1");
+        assert_eq!(s.1.schema_identifiers, vec!["AUTOMOTIVE_DESIGN"]);
+
+        let flat = StepFile::strip_flatten(STEP);
+        let (expected, _) = StepFile::parse(&flat);
+        assert_eq!(format!("{:?}", s.0), format!("{:?}", expected.0));
+
+        assert!(matches!(StepFile::parse_xml("<iso_10303_28>"), Err(Error::Xml(_))));
+        let bad = XML.replace("Orientation=\"false\"", "Orientation=\"maybe\"");
+        assert!(matches!(StepFile::parse_xml(&bad), Err(Error::Xml(_))));
+        let bad = XML.replace("Count_measure", "Bogus_measure");
+        assert!(matches!(StepFile::parse_xml(&bad), Err(Error::Xml(_))));
+
+        // Complex instances with unknown types are reported, not dropped
+        let bad = XML.replace("Length_unit-Si_unit", "Length_unit-Bogus_unit");
+        let (t, report) = StepFile::parse_xml(&bad).unwrap();
+        assert_eq!(report.failures.len(), 1);
+        assert_eq!(report.failures[0].id, Some(8));
+        assert!(matches!(t.0[8], Entity::_FailedToParse));
+    }
+}
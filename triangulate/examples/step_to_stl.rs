@@ -1,7 +1,8 @@
+use std::path::Path;
 use clap::{Arg, App};
 
-use triangulate::triangulate::triangulate;
-use step::step_file::StepFile;
+use triangulate::triangulate::triangulate_with_unit;
+use step::{step_file::StepFile, units::LengthUnit};
 
 fn main() -> Result<(), Box<dyn std::error::Error>> {
     env_logger::init();
@@ -15,24 +16,42 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
             .help("stl file to target")
             .takes_value(true)
             .required(true))
+        .arg(Arg::with_name("units")
+            .short("u")
+            .long("units")
+            .help("output length unit (um, mm, cm, m, in, ft)")
+            .takes_value(true)
+            .default_value("mm"))
         .arg(Arg::with_name("input")
             .takes_value(true)
             .required(true))
         .get_matches();
     let input = matches.value_of("input")
         .expect("Could not get input file");
+    let unit: LengthUnit = matches.value_of("units")
+        .expect("Could not get units")
+        .parse()?;
 
     let start = std::time::SystemTime::now();
-    let data = std::fs::read(input)?;
+    let data = StepFile::read_file(input)?;
     let flat = StepFile::strip_flatten(&data);
-    let entities = StepFile::parse(&flat);
+    let (entities, _report) = StepFile::parse(&flat);
+
+    // Edition 3 files may refer to parts in other files, which are loaded
+    // relative to the input file
+    let entities = if entities.1.references.is_empty() {
+        entities
+    } else {
+        let dir = Path::new(input).parent().unwrap_or_else(|| Path::new("."));
+        entities.resolve_references(|uri| StepFile::read_file(dir.join(uri)))?.0
+    };
     let end = std::time::SystemTime::now();
     let since_the_epoch = end.duration_since(start)
         .expect("Time went backwards");
     println!("Loaded + parsed in {:?}", since_the_epoch);
 
     let start = std::time::SystemTime::now();
-    let tri = triangulate(&entities);
+    let tri = triangulate_with_unit(&entities, unit);
     let end = std::time::SystemTime::now();
     let since_the_epoch = end.duration_since(start)
         .expect("Time went backwards");
@@ -9,7 +9,9 @@ use log::{info, warn, error};
 use rayon::prelude::*;
 
 use step::{
-    ap214, ap214::*, step_file::{FromEntity, StepFile}, id::Id, ap214::Entity,
+    ap214, ap214::*, step_file::{Entities, FromEntity}, id::Id, ap214::Entity,
+    presentation::{Presentation, Style},
+    units::{LengthUnit, Units},
 };
 use crate::{
     Error,
@@ -26,19 +28,25 @@ const SAVE_PANIC_SVGS: bool = false;
 /// `TransformStack` is a mapping of representations to transformed children.
 type TransformStack<'a> =
     HashMap<Representation<'a>, Vec<(Representation<'a>, DMat4)>>;
-fn build_transform_stack<'a>(s: &'a StepFile, flip: bool) -> TransformStack<'a> {
+fn build_transform_stack<'a, 's: 'a, S: Entities<'s>>(s: &'a S, flip: bool,
+                                                       unit: LengthUnit)
+    -> TransformStack<'a>
+{
     // Store a map of parent -> (child, transform)
     let mut transform_stack: HashMap<_, Vec<_>> = HashMap::new();
-    for r in s.0.iter()
-        .filter_map(|e|
-            RepresentationRelationshipWithTransformation_::try_from_entity(e))
+    for r in s.ids_of_type("REPRESENTATION_RELATIONSHIP_WITH_TRANSFORMATION")
+        .into_iter()
+        .filter_map(|i| s.get(i))
+        .filter_map(RepresentationRelationshipWithTransformation_::try_from_entity)
     {
         let (a, b) = if flip {
             (r.rep_2, r.rep_1)
         } else {
             (r.rep_1, r.rep_2)
         };
-        let mut mat = item_defined_transformation(s, r.transformation_operator.cast());
+        // Each placement is in the units of its own representation
+        let mut mat = item_defined_transformation(s, r.transformation_operator.cast(),
+            length_scale(s, r.rep_1, unit), length_scale(s, r.rep_2, unit));
         if flip {
             mat = mat.try_inverse().expect("Could not invert transform matrix");
         }
@@ -63,24 +71,27 @@ fn transform_stack_roots<'a>(transform_stack: &TransformStack<'a>) -> Vec<Repres
         .collect()
 }
 
-pub fn triangulate(s: &StepFile) -> (Mesh, Stats) {
-    let styled_items: Vec<_> = s.0.iter()
-        .filter_map(|e| MechanicalDesignGeometricPresentationRepresentation_::try_from_entity(e))
-        .flat_map(|m| m.items.iter())
-        .filter_map(|item| s.entity(item.cast::<StyledItem_>()))
-        .collect();
-    let brep_colors: HashMap<_, DVec3> = styled_items.iter()
-        .filter_map(|styled|
-            if styled.styles.len() != 1 {
-                None
-            } else {
-                presentation_style_color(s, styled.styles[0])
-                    .map(|c| (styled.item, c))
-            })
-        .collect();
+/// Returns the scale from a representation's length unit to `unit`
+fn length_scale<'a, S: Entities<'a>>(s: &S, rep: Representation, unit: LengthUnit)
+    -> f64
+{
+    Units::of_representation(s, rep).length / unit.metres()
+}
+
+/// Triangulates every solid in the file, with output in millimetres
+pub fn triangulate<'a, S: Entities<'a>>(s: &S) -> (Mesh, Stats) {
+    triangulate_with_unit(s, LengthUnit::Millimetre)
+}
+
+/// Triangulates every solid in the file, converting from the units declared
+/// by each representation into the given length unit
+pub fn triangulate_with_unit<'a, S: Entities<'a>>(s: &S, unit: LengthUnit)
+    -> (Mesh, Stats)
+{
+    let presentation = Presentation::new(s);
 
     // Store a map of parent -> (child, transform)
-    let mut transform_stack = build_transform_stack(s, false);
+    let mut transform_stack = build_transform_stack(s, false, unit);
     let mut roots = transform_stack_roots(&transform_stack);
     // The transformation graph isn't directional (because STEP is a Good File
     // Format), so if it's got more than one root, assume it's backwards.  We
@@ -88,7 +99,7 @@ pub fn triangulate(s: &StepFile) -> (Mesh, Stats) {
     // until we find a counterexample.
     if roots.len() > 1 {
         info!("Flipping transform stack");
-        transform_stack = build_transform_stack(s, true);
+        transform_stack = build_transform_stack(s, true, unit);
         roots = transform_stack_roots(&transform_stack);
     }
     let mut todo: Vec<_> = roots.into_iter()
@@ -99,9 +110,13 @@ pub fn triangulate(s: &StepFile) -> (Mesh, Stats) {
     }
 
     // Store a map of ShapeRepresentationRelationships, which some models
-    // use to map from axes to specific instances
+    // use to map from axes to specific instances (relationships with a
+    // transformation are already in the transform stack)
     let mut shape_rep_relationship: HashMap<Id<_>, Vec<Id<_>>> = HashMap::new();
-    for (r1, r2) in s.0.iter()
+    for (r1, r2) in s.ids_of_type("SHAPE_REPRESENTATION_RELATIONSHIP")
+        .into_iter()
+        .filter_map(|i| s.get(i))
+        .filter(|e| !e.is_instance_of("REPRESENTATION_RELATIONSHIP_WITH_TRANSFORMATION"))
         .filter_map(|e| ShapeRepresentationRelationship_::try_from_entity(e))
         .map(|e| (e.rep_1, e.rep_2))
     {
@@ -109,6 +124,8 @@ pub fn triangulate(s: &StepFile) -> (Mesh, Stats) {
     }
 
     let mut to_mesh: HashMap<Id<_>, Vec<_>> = HashMap::new();
+    // Units of the representation which contains each item
+    let mut item_units: HashMap<Id<_>, Units> = HashMap::new();
     while let Some((id, mat)) = todo.pop() {
         for child in shape_rep_relationship.get(&id).unwrap_or(&vec![]) {
             todo.push((*child, mat));
@@ -120,7 +137,7 @@ pub fn triangulate(s: &StepFile) -> (Mesh, Stats) {
         } else {
             // Bind this transform to the RepresentationItem, which is
             // either a ManifoldSolidBrep or a ShellBasedSurfaceModel
-            let items = match &s[id] {
+            let items = match s.by_id(id) {
                 Entity::AdvancedBrepShapeRepresentation(b) => &b.items,
                 Entity::ShapeRepresentation(b) => &b.items,
                 Entity::ManifoldSurfaceShapeRepresentation(b) => &b.items,
@@ -128,11 +145,14 @@ pub fn triangulate(s: &StepFile) -> (Mesh, Stats) {
             };
 
             for m in items.iter() {
-                match &s[*m] {
+                match s.by_id(*m) {
                     Entity::ManifoldSolidBrep(_)
                     | Entity::BrepWithVoids(_)
-                    | Entity::ShellBasedSurfaceModel(_) =>
-                        to_mesh.entry(*m).or_default().push(mat),
+                    | Entity::ShellBasedSurfaceModel(_) => {
+                        item_units.entry(*m)
+                            .or_insert_with(|| Units::of_representation(s, id));
+                        to_mesh.entry(*m).or_default().push(mat);
+                    },
                     Entity::Axis2Placement3d(_) => (),
                     e => warn!("Skipping {:?}", e),
                 }
@@ -142,18 +162,28 @@ pub fn triangulate(s: &StepFile) -> (Mesh, Stats) {
     // If there are items in breps that aren't attached to a transformation
     // chain, then draw them individually (with an identity matrix)
     if to_mesh.is_empty() {
-        s.0.iter()
-            .enumerate()
-            .filter(|(_i, e)|
-                match e {
-                    Entity::ManifoldSolidBrep(_)
-                    | Entity::BrepWithVoids(_)
-                    | Entity::ShellBasedSurfaceModel(_) => true,
+        let reps = s.ids_of_type("REPRESENTATION");
+        s.ids_of_type("MANIFOLD_SOLID_BREP")
+            .into_iter()
+            .chain(s.ids_of_type("SHELL_BASED_SURFACE_MODEL"))
+            .filter(|i|
+                match s.get(*i) {
+                    Some(Entity::ManifoldSolidBrep(_))
+                    | Some(Entity::BrepWithVoids(_))
+                    | Some(Entity::ShellBasedSurfaceModel(_)) => true,
                     _ => false,
                 }
             )
-            .map(|(i, _e)| Id::new(i))
-            .for_each(|i| to_mesh.entry(i).or_default().push(DMat4::identity()));
+            .map(Id::new)
+            .for_each(|i| {
+                // Look up units through whichever representation uses this item
+                if let Some(r) = reps.iter().find(|r| s.get(**r)
+                    .is_some_and(|e| e.upstream().contains(&i.0)))
+                {
+                    item_units.insert(i, Units::of_representation(s, Id::new(*r)));
+                }
+                to_mesh.entry(i).or_default().push(DMat4::identity())
+            });
     }
 
     let (to_mesh_iter, empty) = {
@@ -171,40 +201,42 @@ pub fn triangulate(s: &StepFile) -> (Mesh, Stats) {
             |(mut mesh, mut stats), (id, mats)| {
                 let v_start = mesh.verts.len();
                 let t_start = mesh.triangles.len();
-                match &s[*id] {
+                let units = item_units.get(id).copied().unwrap_or_default();
+                let styles = presentation.face_styles(s, id.0);
+                let f = Faces { units: &units, styles: &styles };
+                match s.by_id(*id) {
                     Entity::ManifoldSolidBrep(b) =>
-                        closed_shell(s, b.outer, &mut mesh, &mut stats),
+                        closed_shell(s, b.outer, &f, &mut mesh, &mut stats),
                     Entity::ShellBasedSurfaceModel(b) =>
                         for v in &b.sbsm_boundary {
-                            shell(s, *v, &mut mesh, &mut stats);
+                            shell(s, *v, &f, &mut mesh, &mut stats);
                         },
                     Entity::BrepWithVoids(b) =>
                         // TODO: handle voids
-                        closed_shell(s, b.outer, &mut mesh, &mut stats),
+                        closed_shell(s, b.outer, &f, &mut mesh, &mut stats),
                     _ => {
-                        warn!("Skipping {:?} (not a known solid)", s[*id]);
+                        warn!("Skipping {:?} (not a known solid)", s.by_id(*id));
                         return (mesh, stats);
                     },
                 };
 
-                // Pick out a color from the color map and apply it to each
-                // newly-created vertex
-                let color = brep_colors.get(id)
-                    .map(|c| *c)
-                    .unwrap_or(DVec3::new(0.5, 0.5, 0.5));
+                // Vertices are in the item's own units, so they're scaled
+                // before applying transforms (which are already in `unit`)
+                let scale = units.length / unit.metres();
 
                 // Build copies of the mesh by copying and applying transforms
                 let v_end = mesh.verts.len();
                 let t_end = mesh.triangles.len();
                 for mat in &mats[1..] {
                     for v in v_start..v_end {
-                        let p = mesh.verts[v].pos;
+                        let p = mesh.verts[v].pos * scale;
                         let p_h = DVec4::new(p.x, p.y, p.z, 1.0);
                         let pos = (mat * p_h).xyz();
 
                         let n = mesh.verts[v].norm;
                         let norm = (mat * glm::vec3_to_vec4(&n)).xyz();
 
+                        let color = mesh.verts[v].color;
                         mesh.verts.push(mesh::Vertex { pos, norm, color });
                     }
                     let offset = mesh.verts.len() - v_end;
@@ -219,14 +251,12 @@ pub fn triangulate(s: &StepFile) -> (Mesh, Stats) {
                 // re-use the original mesh and apply the first transform
                 let mat = mats[0];
                 for v in v_start..v_end {
-                    let p = mesh.verts[v].pos;
+                    let p = mesh.verts[v].pos * scale;
                     let p_h = DVec4::new(p.x, p.y, p.z, 1.0);
                     mesh.verts[v].pos = (mat * p_h).xyz();
 
                     let n = mesh.verts[v].norm;
                     mesh.verts[v].norm = (mat * glm::vec3_to_vec4(&n)).xyz();
-
-                    mesh.verts[v].color = color;
                 }
                 (mesh, stats)
             });
@@ -248,7 +278,9 @@ pub fn triangulate(s: &StepFile) -> (Mesh, Stats) {
     (mesh, stats)
 }
 
-fn item_defined_transformation(s: &StepFile, t: Id<ItemDefinedTransformation_>) -> DMat4 {
+fn item_defined_transformation<'a, S: Entities<'a>>(s: &S,
+                                                   t: Id<ItemDefinedTransformation_>,
+                                                   scale_1: f64, scale_2: f64) -> DMat4 {
     let i = s.entity(t).expect("Could not get ItemDefinedTransform");
 
     let (location, axis, ref_direction) = axis2_placement_3d(s,
@@ -256,68 +288,33 @@ fn item_defined_transformation(s: &StepFile, t: Id<ItemDefinedTransformation_>)
     let t1 = Surface::make_affine_transform(axis,
         ref_direction,
         axis.cross(&ref_direction),
-        location);
+        location * scale_1);
 
     let (location, axis, ref_direction) = axis2_placement_3d(s,
         i.transform_item_2.cast());
     let t2 = Surface::make_affine_transform(axis,
         ref_direction,
         axis.cross(&ref_direction),
-        location);
+        location * scale_2);
 
     t2 * t1.try_inverse().expect("Could not invert transform matrix")
 }
 
-fn presentation_style_color(s: &StepFile, p: PresentationStyleAssignment)
-    -> Option<DVec3>
-{
-    // AAAAAHHHHH
-    s.entity(p)
-        .and_then(|p: &PresentationStyleAssignment_| {
-                let mut surf = p.styles.iter().filter_map(|y| {
-                    // This is an ambiguous parse, so we hard-code the first
-                    // Entity item in the enum
-                    use PresentationStyleSelect::PreDefinedPresentationStyle;
-                    if let PreDefinedPresentationStyle(u) = y {
-                        s.entity(u.cast::<SurfaceStyleUsage_>())
-                    } else {
-                        None
-                    }});
-                let out = surf.next();
-                out
-            })
-        .and_then(|surf: &SurfaceStyleUsage_|
-            s.entity(surf.style.cast::<SurfaceSideStyle_>()))
-        .and_then(|surf: &SurfaceSideStyle_| if surf.styles.len() != 1 {
-                None
-            } else {
-                s.entity(surf.styles[0].cast::<SurfaceStyleFillArea_>())
-            })
-        .map(|surf: &SurfaceStyleFillArea_|
-            s.entity(surf.fill_area).expect("Could not get fill_area"))
-        .and_then(|fill: &FillAreaStyle_| if fill.fill_styles.len() != 1 {
-                None
-            } else {
-                s.entity(fill.fill_styles[0].cast::<FillAreaStyleColour_>())
-            })
-        .and_then(|f: &FillAreaStyleColour_|
-            s.entity(f.fill_colour.cast::<ColourRgb_>()))
-        .map(|c| DVec3::new(c.red, c.green, c.blue))
-}
-
-fn cartesian_point(s: &StepFile, a: Id<CartesianPoint_>) -> DVec3 {
+fn cartesian_point<'a, S: Entities<'a>>(s: &S, a: Id<CartesianPoint_>) -> DVec3 {
     let p = s.entity(a).expect("Could not get cartesian point");
     DVec3::new(p.coordinates[0].0, p.coordinates[1].0, p.coordinates[2].0)
 }
 
-fn direction(s: &StepFile, a: Direction) -> DVec3 {
+fn direction<'a, S: Entities<'a>>(s: &S, a: Direction) -> DVec3 {
     let p = s.entity(a).expect("Could not get cartesian point");
     DVec3::new(p.direction_ratios[0],
                p.direction_ratios[1],
                p.direction_ratios[2])
 }
 
-fn axis2_placement_3d(s: &StepFile, t: Id<Axis2Placement3d_>) -> (DVec3, DVec3, DVec3) {
+fn axis2_placement_3d<'a, S: Entities<'a>>(s: &S, t: Id<Axis2Placement3d_>)
+    -> (DVec3, DVec3, DVec3)
+{
     let a = s.entity(t).expect("Could not get Axis2Placement3d");
     let location = cartesian_point(s, a.location);
     // TODO: this doesn't necessarily match the behavior of `build_axes`
@@ -329,42 +326,67 @@ fn axis2_placement_3d(s: &StepFile, t: Id<Axis2Placement3d_>) -> (DVec3, DVec3,
     (location, axis, ref_direction)
 }
 
-fn shell(s: &StepFile, c: Shell, mesh: &mut Mesh, stats: &mut Stats) {
-    match &s[c] {
-        Entity::ClosedShell(_) => closed_shell(s, c.cast(), mesh, stats),
-        Entity::OpenShell(_) => open_shell(s, c.cast(), mesh, stats),
+/// Per-solid state for triangulating faces: the solid's units, and the
+/// style of each face (from [`step::presentation::Presentation::face_styles`])
+struct Faces<'a> {
+    units: &'a Units,
+    styles: &'a HashMap<usize, Style>,
+}
+
+impl Faces<'_> {
+    /// Triangulates a face, coloring its vertices by the face's style
+    fn face<'a, S: Entities<'a>>(&self, s: &S, f: Face, mesh: &mut Mesh,
+                                 stats: &mut Stats) {
+        let start = mesh.verts.len();
+        if let Err(err) = advanced_face(s, f.cast(), self.units, mesh, stats) {
+            error!("Failed to triangulate {:?}: {}", s.by_id(f), err);
+        }
+        let color = self.styles.get(&f.0)
+            .and_then(|style| style.color)
+            .map(|c| DVec3::new(c[0], c[1], c[2]))
+            .unwrap_or(DVec3::new(0.5, 0.5, 0.5));
+        for v in &mut mesh.verts[start..] {
+            v.color = color;
+        }
+    }
+}
+
+fn shell<'a, S: Entities<'a>>(s: &S, c: Shell, f: &Faces, mesh: &mut Mesh,
+                              stats: &mut Stats) {
+    match s.by_id(c) {
+        Entity::ClosedShell(_) => closed_shell(s, c.cast(), f, mesh, stats),
+        Entity::OpenShell(_) => open_shell(s, c.cast(), f, mesh, stats),
         h => warn!("Skipping {:?} (unknown Shell type)", h),
     }
 }
 
-fn open_shell(s: &StepFile, c: OpenShell, mesh: &mut Mesh, stats: &mut Stats) {
+fn open_shell<'a, S: Entities<'a>>(s: &S, c: OpenShell, f: &Faces, mesh: &mut Mesh,
+                                   stats: &mut Stats) {
     let cs = s.entity(c).expect("Could not get OpenShell");
     for face in &cs.cfs_faces {
-        if let Err(err) = advanced_face(s, face.cast(), mesh, stats) {
-            error!("Failed to triangulate {:?}: {}", s[*face], err);
-        }
+        f.face(s, *face, mesh, stats);
     }
     stats.num_shells += 1;
 }
 
-fn closed_shell(s: &StepFile, c: ClosedShell, mesh: &mut Mesh, stats: &mut Stats) {
+fn closed_shell<'a, S: Entities<'a>>(s: &S, c: ClosedShell, f: &Faces,
+                                     mesh: &mut Mesh, stats: &mut Stats) {
     let cs = s.entity(c).expect("Could not get ClosedShell");
     for face in &cs.cfs_faces {
-        if let Err(err) = advanced_face(s, face.cast(), mesh, stats) {
-            error!("Failed to triangulate {:?}: {}", s[*face], err);
-        }
+        f.face(s, *face, mesh, stats);
     }
     stats.num_shells += 1;
 }
 
-fn advanced_face(s: &StepFile, f: AdvancedFace, mesh: &mut Mesh,
-                 stats: &mut Stats) -> Result<(), Error>
+fn advanced_face<'a, S: Entities<'a>>(s: &S, f: AdvancedFace, u: &Units,
+                                      mesh: &mut Mesh, stats: &mut Stats)
+    -> Result<(), Error>
 {
     let face = s.entity(f).expect("Could not get AdvancedFace");
     stats.num_faces += 1;
 
     // Grab the surface, returning early if it's unimplemented
-    let mut surf = get_surface(s, face.face_geometry)?;
+    let mut surf = get_surface(s, face.face_geometry, u)?;
 
     // This is the starting point at which we insert new vertices
     let offset = mesh.verts.len();
@@ -500,8 +522,10 @@ fn advanced_face(s: &StepFile, f: AdvancedFace, mesh: &mut Mesh,
     Ok(())
 }
 
-fn get_surface(s: &StepFile, surf: ap214::Surface) -> Result<Surface, Error> {
-    match &s[surf] {
+fn get_surface<'a, S: Entities<'a>>(s: &S, surf: ap214::Surface, u: &Units)
+    -> Result<Surface, Error>
+{
+    match s.by_id(surf) {
         Entity::CylindricalSurface(c) => {
             let (location, axis, ref_direction) = axis2_placement_3d(s, c.position);
             Ok(Surface::new_cylinder(axis, ref_direction, location, c.radius.0.0.0))
@@ -519,7 +543,8 @@ fn get_surface(s: &StepFile, surf: ap214::Surface) -> Result<Surface, Error> {
         // We treat cones like planes, since that's a valid mapping into 2D
         Entity::ConicalSurface(c) => {
             let (location, axis, ref_direction) = axis2_placement_3d(s, c.position);
-            Ok(Surface::new_cone(axis, ref_direction, location, c.semi_angle.0))
+            Ok(Surface::new_cone(axis, ref_direction, location,
+                                 c.semi_angle.0 * u.plane_angle))
         },
         Entity::SphericalSurface(c) => {
             // We'll ignore axis and ref_direction in favor of building an
@@ -616,23 +641,29 @@ fn get_surface(s: &StepFile, surf: ap214::Surface) -> Result<Surface, Error> {
     }
 }
 
-fn control_points_1d(s: &StepFile, row: &Vec<CartesianPoint>) -> Vec<DVec3> {
+fn control_points_1d<'a, S: Entities<'a>>(s: &S, row: &Vec<CartesianPoint>)
+    -> Vec<DVec3>
+{
     row.iter().map(|p| cartesian_point(s, *p)).collect()
 }
 
-fn control_points_2d(s: &StepFile, rows: &Vec<Vec<CartesianPoint>>) -> Vec<Vec<DVec3>> {
+fn control_points_2d<'a, S: Entities<'a>>(s: &S, rows: &Vec<Vec<CartesianPoint>>)
+    -> Vec<Vec<DVec3>>
+{
     rows.iter()
         .map(|row| control_points_1d(s, row))
         .collect()
 }
 
-fn face_bound(s: &StepFile, b: FaceBound) -> Result<Vec<DVec3>, Error> {
-    let (bound, orientation) = match &s[b] {
+fn face_bound<'a, S: Entities<'a>>(s: &S, b: FaceBound)
+    -> Result<Vec<DVec3>, Error>
+{
+    let (bound, orientation) = match s.by_id(b) {
         Entity::FaceBound(b) => (b.bound, b.orientation),
         Entity::FaceOuterBound(b) => (b.bound, b.orientation),
         e => panic!("Could not get bound from {:?} at {:?}", e, b),
     };
-    match &s[bound] {
+    match s.by_id(bound) {
         Entity::EdgeLoop(e) => {
             let mut d = edge_loop(s, &e.edge_list)?;
             if !orientation {
@@ -649,7 +680,7 @@ fn face_bound(s: &StepFile, b: FaceBound) -> Result<Vec<DVec3>, Error> {
     }
 }
 
-fn edge_loop(s: &StepFile, edge_list: &[OrientedEdge])
+fn edge_loop<'a, S: Entities<'a>>(s: &S, edge_list: &[OrientedEdge])
     -> Result<Vec<DVec3>, Error>
 {
     let mut out = Vec::new();
@@ -666,7 +697,9 @@ fn edge_loop(s: &StepFile, edge_list: &[OrientedEdge])
     Ok(out)
 }
 
-fn edge_curve(s: &StepFile, e: EdgeCurve, orientation: bool) -> Result<Vec<DVec3>, Error> {
+fn edge_curve<'a, S: Entities<'a>>(s: &S, e: EdgeCurve, orientation: bool)
+    -> Result<Vec<DVec3>, Error>
+{
     let edge_curve = s.entity(e).expect("Could not get EdgeCurve");
     let curve = curve(s, edge_curve, edge_curve.edge_geometry, orientation)?;
 
@@ -680,10 +713,11 @@ fn edge_curve(s: &StepFile, e: EdgeCurve, orientation: bool) -> Result<Vec<DVec3
     Ok(curve.build(u, v))
 }
 
-fn curve(s: &StepFile, edge_curve: &ap214::EdgeCurve_,
-         curve_id: ap214::Curve, orientation: bool) -> Result<Curve, Error>
+fn curve<'a, S: Entities<'a>>(s: &S, edge_curve: &ap214::EdgeCurve_,
+                              curve_id: ap214::Curve, orientation: bool)
+    -> Result<Curve, Error>
 {
-    Ok(match &s[curve_id] {
+    Ok(match s.by_id(curve_id) {
         Entity::Circle(c) => {
             let (location, axis, ref_direction) = axis2_placement_3d(s, c.position.cast());
             Curve::new_circle(location, axis, ref_direction, c.radius.0.0.0,
@@ -772,10 +806,32 @@ fn curve(s: &StepFile, edge_curve: &ap214::EdgeCurve_,
     })
 }
 
-fn vertex_point(s: &StepFile, v: Vertex) -> DVec3 {
+fn vertex_point<'a, S: Entities<'a>>(s: &S, v: Vertex) -> DVec3 {
     cartesian_point(s,
         s.entity(v.cast::<VertexPoint_>())
             .expect("Could not get VertexPoint")
             .vertex_geometry
             .cast())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use step::{lazy::LazyStepFile, step_file::StepFile};
+
+    #[test]
+    fn test_triangulate_lazy() {
+        let flat = StepFile::strip_flatten(include_bytes!("../../examples/cube_hole.step"));
+        let (eager, _) = StepFile::parse(&flat);
+        let lazy = LazyStepFile::parse(&flat);
+        let (a, _) = triangulate(&eager);
+        let (b, stats) = triangulate(&lazy);
+        assert_eq!(stats.num_errors, 0);
+        assert_eq!(a.triangles.len(), b.triangles.len());
+        assert!(!b.triangles.is_empty());
+
+        // Triangulation only follows references, so entities which aren't
+        // part of a solid (e.g. product data) are never parsed
+        assert!(lazy.parsed() < lazy.len());
+    }
+}
@@ -35,11 +35,11 @@ worker.onmessage = function(e) {
         targetAxis = null;
     }
 }
-const loadMeshFromString = function(s) {
+const loadMeshFromBytes = function(buf) {
     const d = new Date();
     startTime = d.getTime();
     setStatus("Parsing & triangulating...");
-    worker.postMessage(s);
+    worker.postMessage(new Uint8Array(buf));
 }
 
 const fileSelector = document.getElementById('file-selector');
@@ -57,10 +57,10 @@ fileSelector.addEventListener('change', (event) => {
     const file = event.target.files[0];
     const reader = new FileReader();
     reader.addEventListener('load', (event) => {
-        loadMeshFromString(event.target.result);
+        loadMeshFromBytes(event.target.result);
     });
     setStatus("Uploading...");
-    reader.readAsText(file);
+    reader.readAsArrayBuffer(file);
 });
 
 setStatus("");
@@ -115,8 +115,8 @@ exampleSelector.onchange = function(e) {
         }
 
         fetch(ex[1])
-            .then(response => response.text())
-            .then(text => loadMeshFromString(text))
+            .then(response => response.arrayBuffer())
+            .then(buf => loadMeshFromBytes(buf))
     }
 }
 fetch('examples.json')
@@ -1,4 +1,5 @@
-/// Takes a STEP file (as an array of bytes), and returns a triangle mesh.
+/// Takes a STEP file (as an array of bytes, which may be gzip-compressed or
+/// a single-file zip archive), and returns a triangle mesh.
 ///
 /// Vertices are packed into rows of 9 floats, representing
 /// - Position
@@ -8,7 +9,7 @@
 /// Vertices are rows of three indexes into the triangle array
 ///
 use wasm_bindgen::prelude::*;
-use log::{Level};
+use log::{Level, error};
 
 #[wasm_bindgen]
 pub fn init_log() {
@@ -16,12 +17,19 @@ pub fn init_log() {
 }
 
 #[wasm_bindgen]
-pub fn step_to_triangle_buf(data: String) -> Vec<f32> {
+pub fn step_to_triangle_buf(data: &[u8]) -> Vec<f32> {
     use step::step_file::StepFile;
     use triangulate::triangulate::triangulate; // lol
 
-    let flat = StepFile::strip_flatten(data.as_bytes());
-    let step = StepFile::parse(&flat);
+    let data = match StepFile::decompress(data) {
+        Ok(d) => d,
+        Err(e) => {
+            error!("{}", e);
+            return Vec::new();
+        },
+    };
+    let flat = StepFile::strip_flatten(&data);
+    let (step, _report) = StepFile::parse(&flat);
     let (mut mesh, _stats) = triangulate(&step);
 
     let (mut xmin, mut xmax) = (std::f64::INFINITY, -std::f64::INFINITY);